@@ -1,16 +1,664 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 
+use jsonwebtoken::Algorithm;
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::Figment;
 use serde::Deserialize;
 
+use crate::model::types::Role;
+
+/// File read when `ANZEN_CONFIG` does not name another
+pub const DEFAULT_CONFIG_FILE: &str = "anzen-web-api.toml";
+const ENV_PREFIX: &str = "ANZEN_";
+
+/// Settings needed to reach core, before it can send the rest
+#[derive(Deserialize)]
+pub struct CoreConnection
+{
+    #[serde(default = "default_plugin_name")]
+    pub plugin_name: String,
+    #[serde(default = "default_login_key")]
+    pub login_key: String,
+    /// gRPC endpoint of anzen core
+    #[serde(default = "default_core_endpoint")]
+    pub core_endpoint: String,
+    /// Seconds each call to core may take
+    #[serde(default = "default_core_timeout")]
+    pub core_timeout: u64,
+    /// Longest wait in seconds between registration attempts
+    #[serde(default = "default_core_retry_max")]
+    pub core_retry_max: u64,
+    /// Registration attempts at startup before starting without core, only
+    /// when the local settings are complete on their own
+    #[serde(default = "default_core_startup_attempts")]
+    pub core_startup_attempts: u32,
+    /// Failed calls in a row before core is left alone for a while
+    #[serde(default = "default_core_breaker_threshold")]
+    pub core_breaker_threshold: u32,
+    /// Seconds before a call is let through again
+    #[serde(default = "default_core_breaker_cooldown")]
+    pub core_breaker_cooldown: u64,
+}
+
 #[derive(Deserialize)]
 pub struct Config
 {
+    /// Emails allowed to register without an invitation
+    #[serde(default)]
     pub auth_users: HashSet<String>,
-    pub key: String,
+    /// Emails which are given the admin role when they register
+    #[serde(default)]
+    pub admin_users: HashSet<String>,
+    /// Shared HS256 secret, used for signing when no key pair is active
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub jwt: JwtConfig,
     pub db_uri: String,
+    #[serde(default = "default_db_name")]
+    pub db_name: String,
+    /// Address the HTTP server listens on
+    #[serde(default = "default_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Serve HTTPS with these PEM files
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Origins browsers may call the API from, `*` allows any
+    #[serde(default = "default_cors_origins")]
+    pub cors_origins: Vec<String>,
+    /// Lifetime of access tokens in seconds
+    #[serde(default = "default_access_ttl")]
+    pub access_token_ttl: u64,
+    /// Lifetime of refresh sessions in seconds
+    #[serde(default = "default_refresh_ttl")]
+    pub refresh_token_ttl: u64,
+    /// Default lifetime of invitations in seconds
+    #[serde(default = "default_invite_ttl")]
+    pub invite_ttl: u64,
+    /// Lifetime of password reset tokens in seconds
+    #[serde(default = "default_reset_ttl")]
+    pub reset_token_ttl: u64,
+    /// Path to the PEM public key of the mail plugin, reset tokens are
+    /// encrypted to it. Password resets are off without one.
+    #[serde(default)]
+    pub reset_mailer_key: Option<String>,
+    /// Reset requests allowed for one email before it has to wait
+    #[serde(default = "default_reset_max_requests")]
+    pub reset_max_requests: u32,
+    /// Reset requests allowed from one address before it has to wait
+    #[serde(default = "default_reset_max_requests_ip")]
+    pub reset_max_requests_ip: u32,
+    /// Failed logins allowed for one email before it is locked out
+    #[serde(default = "default_max_attempts")]
+    pub login_max_attempts: u32,
+    /// Failed logins allowed from one address before it is locked out
+    #[serde(default = "default_max_attempts_ip")]
+    pub login_max_attempts_ip: u32,
+    /// First lockout in seconds, doubled for every further failure
+    #[serde(default = "default_lockout")]
+    pub login_lockout_secs: u64,
+    #[serde(default = "default_lockout_max")]
+    pub login_lockout_max_secs: u64,
+    #[serde(default)]
+    pub password: PasswordRules,
+    /// Login through an external OpenID Connect provider
+    pub oidc: Option<OidcConfig>,
+    /// PIN that must be given to arm or disarm, if set
+    #[serde(default)]
+    pub arm_pin: Option<String>,
+    /// Longest grace period in seconds that arming can be delayed by
+    #[serde(default = "default_arm_max_delay")]
+    pub arm_max_delay: u64,
+    #[serde(default)]
+    pub plugin_health: PluginHealthConfig,
+}
+
+#[derive(Deserialize)]
+pub struct TlsConfig
+{
+    /// Certificate chain
+    pub certs: String,
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+pub struct OidcConfig
+{
+    /// Issuer URL, discovery is fetched from below it
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Page of the dashboard the provider sends the user back to
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// ID token claim holding the user's groups
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// Provider group to local role
+    #[serde(default)]
+    pub role_mapping: HashMap<String, Role>,
+    /// Role for users in no mapped group, without one they are refused
+    pub default_role: Option<Role>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct JwtConfig
+{
+    /// Kid of the key new tokens are signed with
+    pub active_kid: Option<String>,
+    /// Every key tokens may still be verified against
+    #[serde(default)]
+    pub keys: Vec<JwtKey>,
+}
+
+#[derive(Deserialize)]
+pub struct JwtKey
+{
+    pub kid: String,
+    pub algorithm: Algorithm,
+    /// Path to the PEM private key, only needed for the active key
+    pub private_key: Option<String>,
+    /// Path to the PEM encoded SubjectPublicKeyInfo
+    pub public_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordRules
+{
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+    #[serde(default = "default_true")]
+    pub require_lowercase: bool,
+    #[serde(default = "default_true")]
+    pub require_uppercase: bool,
+    #[serde(default = "default_true")]
+    pub require_digit: bool,
+    #[serde(default = "default_true")]
+    pub require_special: bool,
+    /// File of common or breached passwords, one per line
+    pub blocklist: Option<String>,
+}
+
+impl Default for PasswordRules
+{
+    fn default() -> Self
+    {
+        PasswordRules {
+            min_length: default_min_length(),
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            blocklist: None,
+        }
+    }
+}
+
+/// How long a plugin can go without events or commands before it is flagged
+#[derive(Deserialize)]
+pub struct PluginHealthConfig
+{
+    /// Seconds of quiet before a plugin is reported as stale
+    #[serde(default = "default_stale_after")]
+    pub stale_after: u64,
+    /// Seconds of quiet before a plugin is reported as silent
+    #[serde(default = "default_silent_after")]
+    pub silent_after: u64,
+}
+
+impl Default for PluginHealthConfig
+{
+    fn default() -> Self
+    {
+        PluginHealthConfig {
+            stale_after: default_stale_after(),
+            silent_after: default_silent_after(),
+        }
+    }
+}
+
+fn default_stale_after() -> u64
+{
+    60 * 15
+}
+
+fn default_silent_after() -> u64
+{
+    60 * 60
+}
+
+fn default_arm_max_delay() -> u64
+{
+    60 * 10
+}
+
+fn default_access_ttl() -> u64
+{
+    60 * 15
+}
+
+fn default_refresh_ttl() -> u64
+{
+    60 * 60 * 24 * 30
+}
+
+fn default_plugin_name() -> String
+{
+    "web-api".into()
+}
+
+fn default_login_key() -> String
+{
+    "12345".into()
+}
+
+fn default_core_endpoint() -> String
+{
+    "grpc://[::1]:50000".into()
+}
+
+fn default_core_timeout() -> u64
+{
+    5
+}
+
+fn default_core_retry_max() -> u64
+{
+    30
+}
+
+fn default_core_startup_attempts() -> u32
+{
+    5
+}
+
+fn default_core_breaker_threshold() -> u32
+{
+    5
+}
+
+fn default_core_breaker_cooldown() -> u64
+{
+    30
+}
+
+fn default_db_name() -> String
+{
+    "anzen".into()
+}
+
+fn default_address() -> IpAddr
+{
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_port() -> u16
+{
+    8000
+}
+
+fn default_cors_origins() -> Vec<String>
+{
+    vec!["*".into()]
+}
+
+fn default_reset_ttl() -> u64
+{
+    60 * 60
+}
+
+fn default_invite_ttl() -> u64
+{
+    60 * 60 * 24 * 7
+}
+
+fn default_reset_max_requests() -> u32
+{
+    3
 }
 
-pub fn get_config(input: &str) -> Result<Config, toml::de::Error>
+fn default_reset_max_requests_ip() -> u32
 {
-    toml::from_str(input)
+    10
+}
+
+fn default_max_attempts() -> u32
+{
+    5
+}
+
+fn default_max_attempts_ip() -> u32
+{
+    20
+}
+
+fn default_lockout() -> u64
+{
+    30
+}
+
+fn default_lockout_max() -> u64
+{
+    60 * 60
+}
+
+fn default_oidc_scopes() -> Vec<String>
+{
+    vec!["openid".into(), "email".into(), "profile".into()]
+}
+
+fn default_groups_claim() -> String
+{
+    "groups".into()
+}
+
+fn default_min_length() -> usize
+{
+    16
+}
+
+fn default_true() -> bool
+{
+    true
+}
+
+/// Every problem found with the settings, so they can all be fixed at once
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        writeln!(f, "invalid configuration:")?;
+
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+// `main` returning an error prints it with Debug
+impl fmt::Debug for ConfigError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<rocket::figment::Error> for ConfigError
+{
+    fn from(error: rocket::figment::Error) -> Self
+    {
+        ConfigError(error.into_iter().map(|error| error.to_string()).collect())
+    }
+}
+
+/// Settings known before core is reached. Defaults come from the serde
+/// attributes, overridden by the TOML file, then by `ANZEN_*` variables.
+/// Nested keys are separated by a double underscore, `ANZEN_JWT__ACTIVE_KID`.
+pub fn local(file: &str) -> Figment
+{
+    Figment::new()
+        .merge(Toml::file(file))
+        .merge(Env::prefixed(ENV_PREFIX).ignore(&["CONFIG"]).split("__"))
+}
+
+pub fn core_connection(local: &Figment) -> Result<CoreConnection, ConfigError>
+{
+    let connection: CoreConnection = local.extract()?;
+
+    let mut problems = Vec::new();
+
+    if connection.plugin_name.trim().is_empty() {
+        problems.push("plugin_name must not be empty".to_string());
+    }
+
+    if !has_scheme(&connection.core_endpoint, &["grpc", "http", "https"]) {
+        problems.push(format!(
+            "core_endpoint must be a grpc:// or http(s):// URL, got {:?}",
+            connection.core_endpoint
+        ));
+    }
+
+    if connection.core_timeout == 0 {
+        problems.push("core_timeout must be more than 0 seconds".to_string());
+    }
+
+    if connection.core_retry_max == 0 {
+        problems.push("core_retry_max must be more than 0 seconds".to_string());
+    }
+
+    if connection.core_breaker_threshold == 0 {
+        problems.push("core_breaker_threshold must be more than 0".to_string());
+    }
+
+    match problems.is_empty() {
+        true => Ok(connection),
+        false => Err(ConfigError(problems)),
+    }
+}
+
+/// Options pushed by core go on top of the local settings
+pub fn load(local: Figment, plugin_opts: &str) -> Result<Config, ConfigError>
+{
+    let config: Config = local.merge(Toml::string(plugin_opts)).extract()?;
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+fn has_scheme(url: &str, schemes: &[&str]) -> bool
+{
+    schemes.iter().any(|scheme| {
+        url.strip_prefix(scheme)
+            .and_then(|rest| rest.strip_prefix("://"))
+            .map(|rest| !rest.is_empty())
+            .unwrap_or(false)
+    })
+}
+
+impl Config
+{
+    pub fn validate(&self) -> Result<(), ConfigError>
+    {
+        let mut problems = Vec::new();
+
+        if !has_scheme(&self.db_uri, &["mongodb", "mongodb+srv"]) {
+            problems.push("db_uri must be a mongodb:// or mongodb+srv:// URI".to_string());
+        }
+
+        // Characters Mongo does not allow in database names
+        let forbidden = ['/', '\\', '.', ' ', '"', '$'];
+
+        if self.db_name.is_empty() || self.db_name.len() > 63 || self.db_name.contains(&forbidden[..]) {
+            problems.push(format!("db_name {:?} is not a valid database name", self.db_name));
+        }
+
+        if self.port == 0 {
+            problems.push("port must not be 0".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            for (name, file) in [("tls.certs", &tls.certs), ("tls.key", &tls.key)] {
+                if !Path::new(file).is_file() {
+                    problems.push(format!("{} file {:?} does not exist", name, file));
+                }
+            }
+        }
+
+        if let Some(file) = &self.reset_mailer_key {
+            if !Path::new(file).is_file() {
+                problems.push(format!("reset_mailer_key file {:?} does not exist", file));
+            }
+        }
+
+        if self.cors_origins.is_empty() {
+            problems.push("cors_origins must list at least one origin, or \"*\"".to_string());
+        }
+
+        if self.cors_origins.len() > 1 && self.cors_origins.iter().any(|origin| origin == "*") {
+            problems.push("cors_origins cannot mix \"*\" with other origins".to_string());
+        }
+
+        for origin in self.cors_origins.iter().filter(|origin| *origin != "*") {
+            if !has_scheme(origin, &["http", "https"]) || origin.ends_with('/') {
+                problems.push(format!("cors origin {:?} must look like https://host[:port]", origin));
+            }
+        }
+
+        for (name, ttl) in [
+            ("access_token_ttl", self.access_token_ttl),
+            ("refresh_token_ttl", self.refresh_token_ttl),
+            ("reset_token_ttl", self.reset_token_ttl),
+            ("invite_ttl", self.invite_ttl),
+        ] {
+            if ttl == 0 {
+                problems.push(format!("{} must be more than 0 seconds", name));
+            }
+        }
+
+        if self.refresh_token_ttl < self.access_token_ttl {
+            problems.push("refresh_token_ttl must not be shorter than access_token_ttl".to_string());
+        }
+
+        if self.login_max_attempts == 0 || self.login_max_attempts_ip == 0 {
+            problems.push("login_max_attempts and login_max_attempts_ip must be more than 0".to_string());
+        }
+
+        if self.reset_max_requests == 0 || self.reset_max_requests_ip == 0 {
+            problems.push("reset_max_requests and reset_max_requests_ip must be more than 0".to_string());
+        }
+
+        if self.login_lockout_secs > self.login_lockout_max_secs {
+            problems.push("login_lockout_secs must not be longer than login_lockout_max_secs".to_string());
+        }
+
+        if self.password.min_length == 0 {
+            problems.push("password.min_length must be more than 0".to_string());
+        }
+
+        if self.plugin_health.stale_after >= self.plugin_health.silent_after {
+            problems.push("plugin_health.stale_after must be shorter than silent_after".to_string());
+        }
+
+        if let Some(oidc) = &self.oidc {
+            if !has_scheme(&oidc.issuer, &["https", "http"]) {
+                problems.push("oidc.issuer must be an http(s):// URL".to_string());
+            }
+
+            if !has_scheme(&oidc.redirect_uri, &["https", "http"]) {
+                problems.push("oidc.redirect_uri must be an http(s):// URL".to_string());
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError(problems)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const MINIMAL: &str = "db_uri = \"mongodb://127.0.0.1:27017\"\n";
+
+    /// Every problem loading `plugin_opts` on top of nothing else reports
+    fn problems(plugin_opts: &str) -> Vec<String>
+    {
+        match load(Figment::new(), &format!("{}{}", MINIMAL, plugin_opts)) {
+            Ok(_) => Vec::new(),
+            Err(ConfigError(problems)) => problems,
+        }
+    }
+
+    #[test]
+    fn later_layers_win()
+    {
+        let file = std::env::temp_dir().join(format!("anzen-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &file,
+            "db_uri = \"mongodb://file:27017\"\ndb_name = \"file\"\nport = 9000\n[jwt]\nactive_kid = \"file\"\n",
+        )
+        .unwrap();
+
+        // The only test reading ANZEN_*, the others start from an empty figment
+        std::env::set_var("ANZEN_DB_NAME", "env");
+        std::env::set_var("ANZEN_JWT__ACTIVE_KID", "env");
+
+        let config = load(local(file.to_str().unwrap()), "db_name = \"core\"\n");
+
+        for name in ["ANZEN_DB_NAME", "ANZEN_JWT__ACTIVE_KID"] {
+            std::env::remove_var(name);
+        }
+        std::fs::remove_file(&file).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.db_uri, "mongodb://file:27017");
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.jwt.active_kid.as_deref(), Some("env"));
+        assert_eq!(config.db_name, "core");
+    }
+
+    #[test]
+    fn defaults_are_valid()
+    {
+        assert_eq!(problems(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_a_db_uri_which_is_not_mongo()
+    {
+        let problems = match load(Figment::new(), "db_uri = \"postgres://127.0.0.1\"\n") {
+            Err(ConfigError(problems)) => problems,
+            Ok(_) => panic!("expected the config to be rejected"),
+        };
+
+        assert_eq!(problems, vec!["db_uri must be a mongodb:// or mongodb+srv:// URI"]);
+    }
+
+    #[test]
+    fn rejects_a_wildcard_among_origins()
+    {
+        assert_eq!(
+            problems("cors_origins = [\"*\", \"https://anzen.example\"]\n"),
+            vec!["cors_origins cannot mix \"*\" with other origins"]
+        );
+    }
+
+    #[test]
+    fn stale_must_come_before_silent()
+    {
+        for (stale, silent) in [(600, 600), (900, 600)] {
+            assert_eq!(
+                problems(&format!("[plugin_health]\nstale_after = {}\nsilent_after = {}\n", stale, silent)),
+                vec!["plugin_health.stale_after must be shorter than silent_after"]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_every_problem_at_once()
+    {
+        let error = load(Figment::new(), "db_uri = \"redis://x\"\nport = 0\ncors_origins = []\n").err().unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "invalid configuration:\n  - db_uri must be a mongodb:// or mongodb+srv:// URI\n  - port must not be 0\n  - cors_origins must list at least one origin, or \"*\"\n"
+        );
+    }
 }
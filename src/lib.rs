@@ -0,0 +1,10 @@
+#[macro_use]
+extern crate rocket;
+extern crate argon2;
+pub mod config;
+pub mod model;
+pub mod routes;
+
+/// Errors may be held across an await in handlers and spawned tasks, so
+/// they have to be Send
+pub type ResultT<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
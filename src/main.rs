@@ -1,13 +1,4 @@
-#[macro_use]
-extern crate rocket;
-extern crate argon2;
-use anzen_lib::client::PluginData;
-use anzen_lib::{self, anzen};
-mod config;
-mod model;
-mod routes;
-
-pub type ResultT<T> = Result<T, Box<dyn std::error::Error>>;
+use anzen_web_api::{config, routes, ResultT};
 
 #[tokio::main]
 async fn main() -> ResultT<()>
@@ -17,20 +8,32 @@ async fn main() -> ResultT<()>
     // - Recieves JWT key
     // Implement bcrypt
 
-    let register_data = PluginData {
-        name: "web-api".into(),
-        login_key: "12345".into(),
-        plugin_type: anzen::PluginType::Output,
-        server_socket: "grpc://[::1]:50000".into(),
+    let file = std::env::var("ANZEN_CONFIG").unwrap_or_else(|_| config::DEFAULT_CONFIG_FILE.into());
+    let local = config::local(&file);
+    let connection = config::core_connection(&local)?;
+
+    // Core's options can only be done without when the local settings are complete
+    let attempts = match config::load(local.clone(), "") {
+        Ok(_) => Some(connection.core_startup_attempts),
+        Err(_) => None,
     };
 
-    let (client, resp) = anzen_lib::client::register(&register_data).await.unwrap();
+    let core_api = routes::CoreAPI::init(connection);
+
+    let (config, unregistered) = match core_api.register_retrying(attempts).await {
+        Ok(plugin_opts) => (config::load(local, &plugin_opts)?, None),
+        Err(e) => (config::load(local, "")?, Some(e)),
+    };
 
-    let config = config::get_config(&resp.plugin_opts)?;
+    let rocket = routes::build_rocket(config, core_api.clone()).await?;
 
-    let token = anzen_lib::client::get_login_key(&resp.token);
+    // Rocket's logger is only set up once it has been built
+    if let Some(e) = unregistered {
+        rocket::warn!("{}, starting without core", e);
+        core_api.reconnect_in_background();
+    }
 
-    routes::launch(config, token, register_data.name, client).await?;
+    routes::launch(rocket).await?;
 
     Ok(())
 }
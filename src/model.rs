@@ -1,50 +1,144 @@
-use crate::{routes::returns::EventCommandN, model::pipeline::Match};
+use crate::{model::pipeline::Match, routes::returns::{
+    readings, BucketCount, CommandView, PRIVATE_REQUESTS, CursorPage, DeviceSummary, EventCommandN, EventView,
+    PluginHealth, PluginSummary, ReadingStats, SearchPages,
+}};
+pub use pipeline::{local_midnight, Bucket, Cursor, SortOrder, TimeRange};
 
 use crate::ResultT;
-use anzen_lib::db_types::{self, User};
+use std::collections::HashMap;
+use chrono_tz::Tz;
+use anzen_lib::db_types;
+use types::{Account, Permission, Role};
 use argon2::{self, Config};
 use mongodb::{
-    bson::{doc, oid::ObjectId, Document},
+    bson::{doc, oid::ObjectId, Bson, Document},
     Client, Collection,
 };
 use mongodb::bson::DateTime;
+use mongodb::change_stream::{event::ChangeStreamEvent, ChangeStream};
+use mongodb::options::{FindOneOptions, FindOptions, UpdateOptions};
+use mongodb::IndexModel;
 use rocket::futures::TryStreamExt;
 
 mod helpers;
 mod pipeline;
+pub mod types;
 
+const RECOVERY_CODES: usize = 8;
+/// Rotated refresh secrets remembered per session to spot them being reused
+const USED_REFRESH_HASHES: i32 = 100;
+const SECURITY_SETTINGS: &str = "security";
+const API_KEY_PREFIX: &str = "anz_";
+/// Days of events counted towards each device in the device list
+const DEVICE_WINDOW_DAYS: i64 = 30;
+
+/// Where each list in a search starts and how much of it to return
+pub struct SearchPage
+{
+    pub events_cursor: Option<Cursor>,
+    pub commands_cursor: Option<Cursor>,
+    pub order: SortOrder,
+    pub limit: i64,
+}
+
+#[derive(Clone)]
 pub struct AnzenDB
 {
     users: Collection<db_types::User>,
+    accounts: Collection<types::Account>,
     plugins: Collection<db_types::Plugin>,
     commands: Collection<db_types::Command>,
     events: Collection<db_types::Event>,
+    sessions: Collection<types::Session>,
+    audit: Collection<types::AuditEntry>,
+    settings: Collection<types::SecuritySettings>,
+    password_resets: Collection<types::PasswordReset>,
+    invitations: Collection<types::Invitation>,
+    api_keys: Collection<types::ApiKey>,
+    devices: Collection<Document>,
+    device_meta: Collection<types::DeviceMeta>,
 }
 
 impl AnzenDB
 {
-    pub async fn init(uri: String) -> ResultT<AnzenDB>
+    pub async fn init(uri: String, name: &str) -> ResultT<AnzenDB>
     {
         let client = Client::with_uri_str(uri).await?;
-        let db = client.database("anzen");
+        let db = client.database(name);
         Ok(AnzenDB {
             users: db.collection("users"),
+            accounts: db.collection("users"),
             plugins: db.collection("plugins"),
             commands: db.collection("commands"),
             events: db.collection("events"),
+            sessions: db.collection("sessions"),
+            audit: db.collection("audit"),
+            settings: db.collection("settings"),
+            password_resets: db.collection("password_resets"),
+            invitations: db.collection("invitations"),
+            api_keys: db.collection("api_keys"),
+            devices: db.collection("devices"),
+            device_meta: db.collection("device_meta"),
         })
     }
 
+    /// Indexes the activity summaries rely on. Core writes events and
+    /// commands, so nothing else creates them.
+    pub async fn ensure_indexes(&self) -> ResultT<()>
+    {
+        for keys in [doc! { "timestamp": -1 }, doc! { "metadata.plugin_id": 1, "timestamp": -1 }] {
+            self.events.create_index(IndexModel::builder().keys(keys.clone()).build(), None).await?;
+            self.commands.create_index(IndexModel::builder().keys(keys).build(), None).await?;
+        }
+
+        let keys = doc! { "metadata.device_id": 1, "timestamp": -1 };
+        self.events.create_index(IndexModel::builder().keys(keys).build(), None).await?;
+
+        Ok(())
+    }
+
+    /// Accounts created by an identity provider never log in with a password
     pub async fn valid_user(&self, email: &String, password: &String) -> ResultT<bool>
     {
-        let user = self.users.find_one(doc! {"email": email}, None).await?;
+        let user = self.users.find_one(doc! {
+            "email": email,
+            "external": doc! { "$exists": false }
+        }, None).await?;
         match user {
             Some(v) => Ok(argon2::verify_encoded(&v.hash, password.as_bytes())?),
             None => Ok(false),
         }
     }
 
-    pub async fn new_user(&self, email: &String, username: &String, level: u8, password: &[u8]) -> ResultT<bool>
+    pub async fn new_user(&self, email: &String, username: &String, role: Role, password: &[u8]) -> ResultT<bool>
+    {
+        self.insert_user(email, username, role, password, None).await
+    }
+
+    /// Creates a user who logs in through an external provider, linked to
+    /// their provider account. They get a random password so only the
+    /// provider can log them in. Returns false if the email is taken.
+    pub async fn new_external_user(
+        &self,
+        external: &types::ExternalIdentity,
+        email: &String,
+        username: &String,
+        role: Role,
+    ) -> ResultT<bool>
+    {
+        let password = helpers::gen_token();
+
+        self.insert_user(email, username, role, password.as_bytes(), Some(external)).await
+    }
+
+    async fn insert_user(
+        &self,
+        email: &String,
+        username: &String,
+        role: Role,
+        password: &[u8],
+        external: Option<&types::ExternalIdentity>,
+    ) -> ResultT<bool>
     {
         let user = self.users.find_one(doc! { "email": email }, None).await?;
         if user.is_some() {
@@ -60,17 +154,318 @@ impl AnzenDB
             email: email.to_string(),
             username: username.to_string(),
             created: DateTime::now(),
-            level,
+            level: role.level(),
             salt,
             hash,
         };
 
-        self.users.insert_one(new_user, None).await?;
+        let mut document = mongodb::bson::to_document(&new_user)?;
+        document.insert("role", role.as_str());
+
+        if let Some(external) = external {
+            document.insert("external", mongodb::bson::to_document(external)?);
+        }
+
+        self.users.clone_with_type::<Document>().insert_one(document, None).await?;
         Ok(true)
     }
 
-    pub async fn get_all_users(&self) -> ResultT<Vec<Document>> {
-        todo!()
+    /// The account created for a provider account, if it has logged in before
+    pub async fn find_external_account(&self, issuer: &str, subject: &str) -> ResultT<Option<Account>>
+    {
+        Ok(self.accounts.find_one(doc! {
+            "external.issuer": issuer,
+            "external.subject": subject
+        }, None).await?)
+    }
+
+    /// Like `get_account`, but tells a missing account apart from a failed lookup
+    pub async fn find_account(&self, email: &String) -> ResultT<Option<Account>>
+    {
+        Ok(self.accounts.find_one(doc! { "email": email }, None).await?)
+    }
+
+    pub async fn get_account(&self, email: &String) -> ResultT<Account>
+    {
+        let data = self.accounts.find_one(doc! { "email": email }, None).await?;
+
+        match data {
+            Some(data) => Ok(data),
+            _ => Err("no user".into()),
+        }
+    }
+
+    /// Lists users newest first, returning the page and the total number of
+    /// matching users
+    pub async fn get_all_users(
+        &self,
+        email: Option<String>,
+        role: Option<Role>,
+        disabled: Option<bool>,
+        page: u64,
+        per_page: i64,
+    ) -> ResultT<(Vec<Account>, u64)>
+    {
+        let mut filter = doc! {};
+
+        if let Some(email) = email {
+            filter.insert("email", doc! {
+                "$regex": regex::escape(&email),
+                "$options": "i"
+            });
+        }
+
+        if let Some(role) = role {
+            // Records created before roles were stored only carry a level
+            filter.insert("$or", vec![
+                doc! { "role": role.as_str() },
+                doc! { "role": doc! { "$exists": false }, "level": role.level() as i32 },
+            ]);
+        }
+
+        if let Some(disabled) = disabled {
+            filter.insert("disabled", match disabled {
+                true => doc! { "$eq": true },
+                false => doc! { "$ne": true },
+            });
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! { "created": -1 })
+            .skip(page.saturating_mul(per_page as u64))
+            .limit(per_page)
+            .build();
+
+        let total = self.accounts.count_documents(filter.clone(), None).await?;
+        let data = self.accounts.find(filter, options).await?;
+
+        let users: Vec<_> = data.try_collect().await?;
+
+        Ok((users, total))
+    }
+
+    /// Admins who can still log in, including records that only carry a level
+    pub async fn count_active_admins(&self) -> ResultT<u64>
+    {
+        let filter = doc! {
+            "$or": [
+                doc! { "role": Role::Admin.as_str() },
+                doc! { "role": doc! { "$exists": false }, "level": Role::Admin.level() as i32 },
+            ],
+            "disabled": doc! { "$ne": true }
+        };
+
+        Ok(self.accounts.count_documents(filter, None).await?)
+    }
+
+    pub async fn get_account_by_id(&self, id: &str) -> ResultT<Option<Account>>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        Ok(self.accounts.find_one(doc! { "_id": id }, None).await?)
+    }
+
+    pub async fn set_role(&self, id: &ObjectId, role: Role) -> ResultT<bool>
+    {
+        let result = self.accounts.update_one(doc! {
+            "_id": id
+        }, doc! {
+            "$set": doc! {
+                "role": role.as_str(),
+                "level": role.level() as i32
+            }
+        }, None).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn set_disabled(&self, id: &ObjectId, disabled: bool) -> ResultT<bool>
+    {
+        let result = self.accounts.update_one(doc! {
+            "_id": id
+        }, doc! {
+            "$set": doc! { "disabled": disabled }
+        }, None).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn require_password_reset(&self, id: &ObjectId) -> ResultT<bool>
+    {
+        let result = self.accounts.update_one(doc! {
+            "_id": id
+        }, doc! {
+            "$set": doc! { "password_reset": true }
+        }, None).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn delete_user(&self, id: &ObjectId) -> ResultT<bool>
+    {
+        let result = self.accounts.delete_one(doc! { "_id": id }, None).await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Stores a new unconfirmed TOTP secret, replacing any earlier attempt.
+    /// Fails once TOTP has been enabled so a stolen token cannot re-enrol.
+    pub async fn begin_totp(&self, email: &String, secret: &String) -> ResultT<bool>
+    {
+        let result = self.accounts.update_one(doc! {
+            "email": email,
+            "totp.enabled": doc! { "$ne": true }
+        }, doc! {
+            "$set": doc! {
+                "totp": doc! {
+                    "secret": secret,
+                    "enabled": false,
+                    "recovery_codes": []
+                }
+            }
+        }, None).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    /// Confirms the pending TOTP secret and returns freshly generated
+    /// recovery codes, which are only ever shown this once
+    pub async fn enable_totp(&self, email: &String) -> ResultT<Vec<String>>
+    {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| helpers::gen_token()[..10].to_lowercase())
+            .collect();
+
+        let hashes: Vec<String> = codes.iter().map(|code| helpers::hash_token(code)).collect();
+
+        self.accounts.update_one(doc! {
+            "email": email,
+            "totp": doc! { "$exists": true }
+        }, doc! {
+            "$set": doc! {
+                "totp.enabled": true,
+                "totp.recovery_codes": hashes
+            }
+        }, None).await?;
+
+        Ok(codes)
+    }
+
+    pub async fn disable_totp(&self, email: &String) -> ResultT<bool>
+    {
+        let result = self.accounts.update_one(doc! {
+            "email": email
+        }, doc! {
+            "$unset": doc! { "totp": "" }
+        }, None).await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Consumes a recovery code, each one only works once
+    pub async fn use_recovery_code(&self, email: &String, code: &str) -> ResultT<bool>
+    {
+        let hash = helpers::hash_token(&code.to_lowercase());
+
+        let result = self.accounts.update_one(doc! {
+            "email": email,
+            "totp.recovery_codes": &hash
+        }, doc! {
+            "$pull": doc! { "totp.recovery_codes": &hash }
+        }, None).await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Records the time step a TOTP code was accepted for. Fails when that
+    /// step or a later one was already used, so codes cannot be replayed.
+    pub async fn use_totp_step(&self, email: &String, step: i64) -> ResultT<bool>
+    {
+        let result = self.accounts.update_one(doc! {
+            "email": email,
+            "totp": doc! { "$exists": true },
+            "$or": [
+                doc! { "totp.last_step": doc! { "$exists": false } },
+                doc! { "totp.last_step": null },
+                doc! { "totp.last_step": doc! { "$lt": step } },
+            ]
+        }, doc! {
+            "$set": doc! { "totp.last_step": step }
+        }, None).await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    pub async fn security_settings(&self) -> ResultT<types::SecuritySettings>
+    {
+        let settings = self.settings.find_one(doc! { "_id": SECURITY_SETTINGS }, None).await?;
+
+        Ok(settings.unwrap_or_default())
+    }
+
+    pub async fn set_require_2fa(&self, required: bool) -> ResultT<()>
+    {
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.settings.update_one(doc! {
+            "_id": SECURITY_SETTINGS
+        }, doc! {
+            "$set": doc! { "require_2fa_arm": required }
+        }, options).await?;
+
+        Ok(())
+    }
+
+    /// The audit log is append-only, nothing updates or removes entries
+    pub async fn audit(&self, entry: types::AuditEntry) -> ResultT<()>
+    {
+        self.audit.insert_one(entry, None).await?;
+        Ok(())
+    }
+
+    pub async fn audit_log(
+        &self,
+        start: Option<DateTime>,
+        end: Option<DateTime>,
+        actor: Option<String>,
+        action: Option<String>,
+        limit: i64,
+    ) -> ResultT<Vec<types::AuditEntry>>
+    {
+        let mut filter = doc! {};
+        let mut range = doc! {};
+
+        if let Some(start) = start {
+            range.insert("$gte", start);
+        }
+
+        if let Some(end) = end {
+            range.insert("$lt", end);
+        }
+
+        if !range.is_empty() {
+            filter.insert("timestamp", range);
+        }
+
+        if let Some(actor) = actor {
+            filter.insert("actor", actor);
+        }
+
+        // Actions are dotted, so "user" matches every "user.*" entry
+        if let Some(action) = action {
+            filter.insert("action", doc! {
+                "$regex": format!("^{}", regex::escape(&action))
+            });
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! { "timestamp": -1 })
+            .limit(limit)
+            .build();
+
+        let data = self.audit.find(filter, options).await?;
+
+        Ok(data.try_collect().await?)
     }
 
     pub async fn change_password(&self, email: &String, password: &[u8]) -> ResultT<bool> {
@@ -84,167 +479,858 @@ impl AnzenDB
         }, doc! {
             "$set": doc! {
                 "salt": salt,
-                "hash": hash
+                "hash": hash,
+                "password_reset": false
             }
         }, None).await?;
 
         Ok(true)
     }
 
-    pub async fn event_statistics(&self) -> ResultT<Vec<Document>>
+    /// Creates a refresh session for the user, returning the session id and
+    /// the refresh secret
+    pub async fn create_session(&self, email: &String, ttl: u64) -> ResultT<(String, String)>
+    {
+        let secret = helpers::gen_token();
+        let now = DateTime::now();
+
+        let session = types::Session {
+            _id: ObjectId::new(),
+            email: email.to_string(),
+            token_hash: helpers::hash_token(&secret),
+            used_hashes: Vec::new(),
+            created: now,
+            expires: DateTime::from_millis(now.timestamp_millis() + (ttl as i64) * 1000),
+            revoked: false,
+        };
+
+        let id = session._id.to_hex();
+
+        self.sessions.insert_one(session, None).await?;
+
+        Ok((id, secret))
+    }
+
+    /// Swaps the refresh secret of a session for a new one. A secret is only
+    /// ever handed out once, so one presented again has most likely been
+    /// stolen and every session of the user is revoked.
+    pub async fn rotate_session(&self, id: &str, secret: &str, ttl: u64) -> ResultT<Option<(String, String)>>
     {
-        let pipeline = [
-            doc! {
-                "$match": doc! {
-                    "metadata.armed": false
+        let id = ObjectId::parse_str(id)?;
+        let now = DateTime::now();
+        let hash = helpers::hash_token(secret);
+
+        let session = match self.sessions.find_one(doc! { "_id": id }, None).await? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        // Checked before revocation and expiry, a replay after logout still counts
+        if session.used_hashes.contains(&hash) {
+            self.revoke_all_sessions(&session.email).await?;
+            return Ok(None);
+        }
+
+        if session.revoked || session.expires <= now {
+            return Ok(None);
+        }
+
+        if session.token_hash != hash {
+            self.revoke_session(&id.to_hex()).await?;
+            return Ok(None);
+        }
+
+        let new_secret = helpers::gen_token();
+
+        let result = self.sessions.update_one(doc! {
+            "_id": id,
+            "token_hash": &session.token_hash
+        }, doc! {
+            "$set": doc! {
+                "token_hash": helpers::hash_token(&new_secret),
+                "expires": DateTime::from_millis(now.timestamp_millis() + (ttl as i64) * 1000)
+            },
+            "$push": doc! {
+                "used_hashes": doc! {
+                    "$each": [&session.token_hash],
+                    "$slice": -USED_REFRESH_HASHES
                 }
+            }
+        }, None).await?;
+
+        // Another request rotated the same secret first, it was used twice
+        if result.modified_count == 0 {
+            self.revoke_all_sessions(&session.email).await?;
+            return Ok(None);
+        }
+
+        Ok(Some((session.email, new_secret)))
+    }
+
+    pub async fn session_active(&self, id: &str) -> ResultT<bool>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        let session = self.sessions.find_one(doc! {
+            "_id": id,
+            "revoked": false,
+            "expires": doc! { "$gt": DateTime::now() }
+        }, None).await?;
+
+        Ok(session.is_some())
+    }
+
+    pub async fn revoke_session(&self, id: &str) -> ResultT<bool>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        let result = self.sessions.update_one(doc! {
+            "_id": id
+        }, doc! {
+            "$set": doc! { "revoked": true }
+        }, None).await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    pub async fn revoke_all_sessions(&self, email: &String) -> ResultT<u64>
+    {
+        let result = self.sessions.update_many(doc! {
+            "email": email,
+            "revoked": false
+        }, doc! {
+            "$set": doc! { "revoked": true }
+        }, None).await?;
+
+        Ok(result.modified_count)
+    }
+
+    pub async fn revoke_other_sessions(&self, email: &String, keep: &str) -> ResultT<u64>
+    {
+        let keep = ObjectId::parse_str(keep)?;
+
+        let result = self.sessions.update_many(doc! {
+            "email": email,
+            "_id": doc! { "$ne": keep },
+            "revoked": false
+        }, doc! {
+            "$set": doc! { "revoked": true }
+        }, None).await?;
+
+        Ok(result.modified_count)
+    }
+
+    /// Creates a reset token for an existing user, replacing any earlier
+    /// unused one. Returns the reset id and token, or `None` when there is
+    /// no such user or the user logs in through an identity provider.
+    pub async fn create_password_reset(&self, email: &String, ttl: u64) -> ResultT<Option<(ObjectId, String)>>
+    {
+        let local = doc! { "email": email, "external": doc! { "$exists": false } };
+
+        if self.accounts.find_one(local, None).await?.is_none() {
+            return Ok(None);
+        }
+
+        self.password_resets.update_many(doc! {
+            "email": email,
+            "used": false
+        }, doc! {
+            "$set": doc! { "used": true }
+        }, None).await?;
+
+        let token = helpers::gen_token();
+        let now = DateTime::now();
+
+        let reset = types::PasswordReset {
+            _id: ObjectId::new(),
+            email: email.to_string(),
+            token_hash: helpers::hash_token(&token),
+            created: now,
+            expires: DateTime::from_millis(now.timestamp_millis() + (ttl as i64) * 1000),
+            used: false,
+        };
+
+        self.password_resets.insert_one(&reset, None).await?;
+
+        Ok(Some((reset._id, token)))
+    }
+
+    /// Marks a reset token as used, returning the email it was issued for
+    /// if it was still valid
+    pub async fn consume_password_reset(&self, token: &str) -> ResultT<Option<String>>
+    {
+        let reset = self.password_resets.find_one_and_update(doc! {
+            "token_hash": helpers::hash_token(token),
+            "used": false,
+            "expires": doc! { "$gt": DateTime::now() }
+        }, doc! {
+            "$set": doc! { "used": true }
+        }, None).await?;
+
+        Ok(reset.map(|reset| reset.email))
+    }
+
+    pub async fn create_invitation(
+        &self,
+        email: &String,
+        role: Role,
+        created_by: &String,
+        ttl: u64,
+    ) -> ResultT<types::Invitation>
+    {
+        let now = DateTime::now();
+
+        let invitation = types::Invitation {
+            _id: ObjectId::new(),
+            email: email.to_string(),
+            role,
+            created_by: created_by.to_string(),
+            created: now,
+            expires: expires_after(now, ttl)?,
+            used: false,
+            revoked: false,
+        };
+
+        self.invitations.insert_one(&invitation, None).await?;
+
+        Ok(invitation)
+    }
+
+    /// Lists invitations newest first, by default only those still usable
+    pub async fn list_invitations(&self, all: bool) -> ResultT<Vec<types::Invitation>>
+    {
+        let filter = match all {
+            true => doc! {},
+            false => doc! {
+                "used": false,
+                "revoked": false,
+                "expires": doc! { "$gt": DateTime::now() }
             },
-            doc! {
+        };
+
+        let options = FindOptions::builder().sort(doc! { "created": -1 }).build();
+        let data = self.invitations.find(filter, options).await?;
+
+        Ok(data.try_collect().await?)
+    }
+
+    pub async fn get_invitation(&self, id: &str) -> ResultT<Option<types::Invitation>>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        Ok(self.invitations.find_one(doc! { "_id": id }, None).await?)
+    }
+
+    pub async fn revoke_invitation(&self, id: &ObjectId) -> ResultT<bool>
+    {
+        let result = self.invitations.update_one(doc! {
+            "_id": id,
+            "used": false
+        }, doc! {
+            "$set": doc! { "revoked": true }
+        }, None).await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Makes an invitation usable again after registering with it failed
+    pub async fn release_invitation(&self, id: &str) -> ResultT<()>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        self.invitations.update_one(doc! {
+            "_id": id,
+            "used": true,
+            "revoked": false
+        }, doc! {
+            "$set": doc! { "used": false }
+        }, None).await?;
+
+        Ok(())
+    }
+
+    /// Uses up an invitation, returning the role it grants if it was valid
+    /// for the email. Callers release it again if the account is not created.
+    pub async fn accept_invitation(&self, id: &str, email: &String) -> ResultT<Option<Role>>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        let invitation = self.invitations.find_one_and_update(doc! {
+            "_id": id,
+            "email": email,
+            "used": false,
+            "revoked": false,
+            "expires": doc! { "$gt": DateTime::now() }
+        }, doc! {
+            "$set": doc! { "used": true }
+        }, None).await?;
+
+        Ok(invitation.map(|invitation| invitation.role))
+    }
+
+    /// Stores a new API key, returning the record and the key itself which
+    /// cannot be recovered later
+    pub async fn create_api_key(
+        &self,
+        name: &String,
+        scopes: Vec<Permission>,
+        allowed_ips: Vec<String>,
+        ttl: Option<u64>,
+        created_by: &String,
+    ) -> ResultT<(types::ApiKey, String)>
+    {
+        let key = format!("{}{}", API_KEY_PREFIX, helpers::gen_token());
+        let now = DateTime::now();
+
+        let api_key = types::ApiKey {
+            _id: ObjectId::new(),
+            name: name.to_string(),
+            prefix: key[..API_KEY_PREFIX.len() + 6].to_string(),
+            key_hash: helpers::hash_token(&key),
+            scopes,
+            allowed_ips,
+            expires: ttl.map(|ttl| expires_after(now, ttl)).transpose()?,
+            created_by: created_by.to_string(),
+            created: now,
+            last_used: None,
+            revoked: false,
+        };
+
+        self.api_keys.insert_one(&api_key, None).await?;
+
+        Ok((api_key, key))
+    }
+
+    /// Looks up an unrevoked, unexpired key and marks it as used
+    pub async fn find_api_key(&self, key: &str) -> ResultT<Option<types::ApiKey>>
+    {
+        let now = DateTime::now();
+
+        let api_key = self.api_keys.find_one_and_update(doc! {
+            "key_hash": helpers::hash_token(key),
+            "revoked": false,
+            "$or": [
+                doc! { "expires": null },
+                doc! { "expires": doc! { "$gt": now } }
+            ]
+        }, doc! {
+            "$set": doc! { "last_used": now }
+        }, None).await?;
+
+        Ok(api_key)
+    }
+
+    pub async fn list_api_keys(&self) -> ResultT<Vec<types::ApiKey>>
+    {
+        let options = FindOptions::builder().sort(doc! { "created": -1 }).build();
+        let data = self.api_keys.find(doc! {}, options).await?;
+
+        Ok(data.try_collect().await?)
+    }
+
+    pub async fn get_api_key(&self, id: &str) -> ResultT<Option<types::ApiKey>>
+    {
+        let id = ObjectId::parse_str(id)?;
+
+        Ok(self.api_keys.find_one(doc! { "_id": id }, None).await?)
+    }
+
+    /// Replaces the secret of a live key, the old key stops working at once
+    pub async fn rotate_api_key(&self, id: &ObjectId) -> ResultT<Option<String>>
+    {
+        let key = format!("{}{}", API_KEY_PREFIX, helpers::gen_token());
+
+        let result = self.api_keys.update_one(doc! {
+            "_id": id,
+            "revoked": false
+        }, doc! {
+            "$set": doc! {
+                "key_hash": helpers::hash_token(&key),
+                "prefix": &key[..API_KEY_PREFIX.len() + 6]
+            }
+        }, None).await?;
+
+        match result.modified_count > 0 {
+            true => Ok(Some(key)),
+            false => Ok(None),
+        }
+    }
+
+    pub async fn revoke_api_key(&self, id: &ObjectId) -> ResultT<bool>
+    {
+        let result = self.api_keys.update_one(doc! {
+            "_id": id,
+            "revoked": false
+        }, doc! {
+            "$set": doc! { "revoked": true }
+        }, None).await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    pub async fn event_statistics(&self, range: &TimeRange, bucket: Bucket, tz: Tz) -> ResultT<Vec<ReadingStats>>
+    {
+        let pipeline = pipeline::PipelineBuilder::new()
+            .find(Some(&[Match::new("metadata.armed", Some(false))]), Some(range))?
+            .bucket("bucket", bucket, tz)?
+            .custom(doc! {
                 "$project": doc! {
-                    "date": doc! {
-                        "$dateToParts": doc! {
-                            "date": "$timestamp"
-                        }
-                    },
+                    "bucket": 1,
                     "data": doc! {
                         "$objectToArray": "$data"
                     }
                 }
-            },
-            doc! {
+            })?
+            .custom(doc! {
                 "$unwind": doc! {
                     "path": "$data"
                 }
-            },
-            doc! {
-                "$group": doc! {
-                    "_id": doc! {
-                        "date": doc! {
-                            "year": "$date.year",
-                            "month": "$date.month",
-                            "day": "$date.day",
-                            "hour": "$date.hour"
-                        },
-                        "data": "$data.k"
-                    },
-                    "total_occurences": doc! {
-                        "$count": doc! {}
-                    },
-                    "float_avg": doc! {
-                        "$avg": "$data.v.float_value"
-                    },
-                    "int_avg": doc! {
-                        "$avg": "$data.v.int_value"
-                    },
-                    "binary_avg": doc! {
-                        "$avg": "$data.v.binary_value"
-                    }
+            })?
+            .group(doc! {
+                "date": "$bucket",
+                "data": "$data.k"
+            }, doc! {
+                "total_occurences": doc! {
+                    "$count": doc! {}
+                },
+                "float_avg": doc! {
+                    "$avg": "$data.v.float_value"
+                },
+                "int_avg": doc! {
+                    "$avg": "$data.v.int_value"
+                },
+                "binary_avg": doc! {
+                    "$avg": "$data.v.binary_value"
                 }
-            },
-        ];
+            })?
+            .build();
 
         let data = self.events.aggregate(pipeline, None).await?;
 
-        let vec_docs: Vec<_> = data.try_collect().await?;
+        let vec_docs: Vec<Document> = data.try_collect().await?;
 
-        Ok(vec_docs)
+        let key = |doc: &Document| -> Option<(i64, String)> {
+            let id = doc.get_document("_id").ok()?;
+            Some((id.get_datetime("date").ok()?.timestamp_millis(), id.get_str("data").ok()?.to_string()))
+        };
+
+        let mut series: Vec<String> = vec_docs.iter().filter_map(|doc| key(doc).map(|(_, k)| k)).collect();
+        series.sort();
+        series.dedup();
+
+        let dates: Vec<DateTime> = vec_docs
+            .iter()
+            .filter_map(|doc| doc.get_document("_id").ok()?.get_datetime("date").ok().copied())
+            .collect();
+
+        let mut found: HashMap<(i64, String), Document> = vec_docs
+            .into_iter()
+            .filter_map(|doc| Some((key(&doc)?, doc)))
+            .collect();
+
+        let mut filled = Vec::new();
+
+        for date in bucket_starts(range, bucket, tz, &dates) {
+            for name in &series {
+                let doc = found.remove(&(date.timestamp_millis(), name.clone())).unwrap_or_else(|| doc! {
+                    "_id": doc! { "date": date, "data": name },
+                    "total_occurences": 0,
+                    "float_avg": Bson::Null,
+                    "int_avg": Bson::Null,
+                    "binary_avg": Bson::Null
+                });
+                filled.push(doc);
+            }
+        }
+
+        // Buckets outside the reported range still hold real data
+        filled.extend(found.into_values());
+        filled.sort_by_key(key);
+
+        Ok(filled.iter().filter_map(ReadingStats::from_document).collect())
     }
 
     pub async fn count_status_time(
         &self,
-        start: Option<String>,
-        end: Option<String>,
+        range: &TimeRange,
         armed: Option<bool>,
         device: Option<String>,
         plugin: Option<String>,
-    ) -> ResultT<Vec<Document>>
+        bucket: Bucket,
+        tz: Tz,
+    ) -> ResultT<Vec<BucketCount>>
     {
         let pipeline = pipeline::PipelineBuilder::new()
-            .find(None, start, end)?
-            .custom(doc! {
-                "$sort": doc! {
-                    "timestamp": -1
-                }
-            })?
-            .lookup("devices", "metadata.device_id", "_id", "device")?
-            .lookup("plugins", "metadata.plugin_id", "_id", "plugin")?
-            .replace_field(&["device", "plugin"])?
+            .find(None, Some(range))?
+            .sources()?
             .find(Some(&[
                 Match::new("metadata.armed", armed),
                 Match::new("device.id", device),
                 Match::new("plugin.name", plugin),
-            ]), None, None)?
-            .custom(
-                doc! {
-                    "$project": doc! {
-                        "date": doc! {
-                            "$dateToParts": doc! {
-                                "date": "$timestamp"
-                            }
-                        },
-                        "armed": "$metadata.armed"
-                    }
+            ]), None)?
+            .bucket("bucket", bucket, tz)?
+            .group(doc! {
+                "date": "$bucket",
+                "armed": "$metadata.armed"
+            }, doc! {
+                "count": doc! {
+                    "$count": doc! {}
                 }
-            )?
-            .custom(
-                    doc! {
-                        "$group": doc! {
-                            "_id": doc! {
-                                "date": doc! {
-                                    "year": "$date.year",
-                                    "month": "$date.month",
-                                    "day": "$date.day",
-                                    "hour": "$date.hour"
-                                },
-                                "armed": "$armed"
-                            },
-                            "count": doc! {
-                                "$count": doc! {}
-                            }
-                        }
-                    }
-            )?
-            .custom(
-                doc! {
-                    "$project": doc! {
-                        "date": doc! {
-                            "$dateFromParts": doc! {
-                                "year": "$_id.date.year",
-                                "month": "$_id.date.month",
-                                "day": "$_id.date.day",
-                                "hour": "$_id.date.hour"
-                            }
-                        },
-                        "armed": "$armed",
-                        "count": "$count"
-                    }
+            })?
+            .custom(doc! {
+                "$project": doc! {
+                    "_id": 0,
+                    "date": "$_id.date",
+                    "armed": "$_id.armed",
+                    "count": "$count"
                 }
-            )?
+            })?
             .build();
 
         let data = self.events.aggregate(pipeline, None).await?;
 
-        let vec_docs: Vec<_> = data.try_collect().await?;
+        let vec_docs: Vec<Document> = data.try_collect().await?;
+
+        let dates: Vec<DateTime> = vec_docs
+            .iter()
+            .filter_map(|doc| doc.get_datetime("date").ok().copied())
+            .collect();
+
+        let key = |doc: &Document| -> Option<(i64, bool)> {
+            Some((doc.get_datetime("date").ok()?.timestamp_millis(), doc.get_bool("armed").ok()?))
+        };
+
+        let mut found: HashMap<(i64, bool), Document> = vec_docs
+            .into_iter()
+            .filter_map(|doc| Some((key(&doc)?, doc)))
+            .collect();
+
+        let series = match armed {
+            Some(armed) => vec![armed],
+            None => vec![false, true],
+        };
+
+        let mut filled = Vec::new();
 
-        Ok(vec_docs)
+        for date in bucket_starts(range, bucket, tz, &dates) {
+            for armed in &series {
+                let doc = found.remove(&(date.timestamp_millis(), *armed)).unwrap_or_else(|| doc! {
+                    "date": date,
+                    "armed": armed,
+                    "count": 0
+                });
+                filled.push(doc);
+            }
+        }
+
+        // Buckets outside the reported range still hold real data
+        filled.extend(found.into_values());
+        filled.sort_by_key(key);
+
+        Ok(filled.iter().filter_map(BucketCount::from_document).collect())
     }
 
-    pub async fn get_user(&self, email: &String) -> ResultT<User>
+    /// Devices with their admin metadata and what their events say about them,
+    /// a page at a time in id order. Passing an id limits the result to that
+    /// one device. Events are only counted over the last `DEVICE_WINDOW_DAYS`.
+    /// Returns the page and the total number of matching devices.
+    pub async fn list_devices(
+        &self,
+        id: Option<ObjectId>,
+        include_ignored: bool,
+        page: u64,
+        per_page: i64,
+    ) -> ResultT<(Vec<DeviceSummary>, u64)>
     {
-        let data = self
-            .users
-            .find_one(
-                doc! {
-                    "email": email
-                },
-                None,
-            )
+        let mut filter = match id {
+            Some(id) => doc! { "_id": id },
+            None => doc! {},
+        };
+
+        if !include_ignored {
+            let ignored: Vec<types::DeviceMeta> = self.device_meta
+                .find(doc! { "ignored": true }, None)
+                .await?
+                .try_collect()
+                .await?;
+            let ignored: Vec<ObjectId> = ignored.into_iter().map(|meta| meta._id).collect();
+
+            filter.insert("_id", match id {
+                Some(id) => doc! { "$eq": id, "$nin": ignored },
+                None => doc! { "$nin": ignored },
+            });
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .skip(page.saturating_mul(per_page as u64))
+            .limit(per_page)
+            .build();
+
+        let total = self.devices.count_documents(filter.clone(), None).await?;
+        let devices: Vec<Document> = self.devices.find(filter, options).await?.try_collect().await?;
+
+        let ids: Vec<ObjectId> = devices.iter().filter_map(|device| device.get_object_id("_id").ok()).collect();
+
+        let now = DateTime::now().timestamp_millis();
+        let window = TimeRange {
+            start: Some(DateTime::from_millis(now - DEVICE_WINDOW_DAYS * 24 * 60 * 60 * 1000)),
+            end: None,
+        };
+
+        let activity_pipeline = pipeline::PipelineBuilder::new()
+            .find(Some(&[Match::new("metadata.device_id", Some(doc! { "$in": ids.clone() }))]), Some(&window))?
+            .sort(SortOrder::Descending)?
+            .group("$metadata.device_id", doc! {
+                "last_seen": doc! { "$first": "$timestamp" },
+                "latest": doc! { "$first": "$data" },
+                "plugin_id": doc! { "$first": "$metadata.plugin_id" },
+                "event_count": doc! { "$sum": 1 }
+            })?
+            .lookup("plugins", "plugin_id", "_id", "plugin")?
+            .replace_field(&["plugin"])?
+            .build();
+
+        let meta: Vec<types::DeviceMeta> = self.device_meta
+            .find(doc! { "_id": doc! { "$in": ids.clone() } }, None)
+            .await?
+            .try_collect()
             .await?;
+        let activity: Vec<Document> = self.events.aggregate(activity_pipeline, None).await?.try_collect().await?;
 
-        match data {
-            Some(data) => Ok(data),
-            _ => Err("no user".into()),
+        let mut meta: HashMap<ObjectId, types::DeviceMeta> = meta.into_iter().map(|meta| (meta._id, meta)).collect();
+        let mut activity: HashMap<ObjectId, Document> = activity
+            .into_iter()
+            .filter_map(|doc| Some((doc.get_object_id("_id").ok()?, doc)))
+            .collect();
+
+        // Devices quiet for the whole window only need their latest event
+        for id in &ids {
+            if activity.contains_key(id) {
+                continue;
+            }
+
+            let latest_pipeline = pipeline::PipelineBuilder::new()
+                .find(Some(&[Match::new("metadata.device_id", Some(*id))]), None)?
+                .custom(doc! { "$sort": doc! { "timestamp": -1 } })?
+                .limit(1)?
+                .custom(doc! {
+                    "$project": doc! {
+                        "_id": "$metadata.device_id",
+                        "last_seen": "$timestamp",
+                        "latest": "$data",
+                        "plugin_id": "$metadata.plugin_id",
+                        "event_count": doc! { "$literal": 0 }
+                    }
+                })?
+                .lookup("plugins", "plugin_id", "_id", "plugin")?
+                .replace_field(&["plugin"])?
+                .build();
+
+            let latest: Vec<Document> = self.events.aggregate(latest_pipeline, None).await?.try_collect().await?;
+
+            if let Some(latest) = latest.into_iter().next() {
+                activity.insert(*id, latest);
+            }
+        }
+
+        let summaries = devices
+            .iter()
+            .filter_map(|device| {
+                let id = device.get_object_id("_id").ok()?;
+                let meta = meta.remove(&id);
+                let activity = activity.remove(&id);
+
+                let ignored = meta.as_ref().map(|meta| meta.ignored).unwrap_or(false);
+                let activity = activity.as_ref();
+
+                Some(DeviceSummary {
+                    id: id.to_hex(),
+                    device_id: device.get_str("id").ok().map(|id| id.to_string()),
+                    name: meta.as_ref().and_then(|meta| meta.name.clone()),
+                    zone: meta.as_ref().and_then(|meta| meta.zone.clone()),
+                    ignored,
+                    plugin_id: activity
+                        .and_then(|doc| doc.get_object_id("plugin_id").ok())
+                        .map(|id| id.to_hex()),
+                    plugin: activity
+                        .and_then(|doc| doc.get_document("plugin").ok())
+                        .and_then(|plugin| plugin.get_str("name").ok())
+                        .map(|name| name.to_string()),
+                    last_seen: activity
+                        .and_then(|doc| doc.get_datetime("last_seen").ok())
+                        .and_then(|time| time.try_to_rfc3339_string().ok()),
+                    latest: activity.and_then(|doc| doc.get_document("latest").ok()).map(readings),
+                    event_count: activity
+                        .and_then(|doc| doc.get("event_count"))
+                        .and_then(|count| count.as_i32().map(i64::from).or_else(|| count.as_i64()))
+                        .unwrap_or(0),
+                })
+            })
+            .collect();
+
+        Ok((summaries, total))
+    }
+
+    /// Sets the admin metadata of a device, `None` leaves a field as it is and
+    /// an empty name or zone clears it
+    pub async fn update_device(
+        &self,
+        id: &ObjectId,
+        name: Option<String>,
+        zone: Option<String>,
+        ignored: Option<bool>,
+        updated_by: &str,
+    ) -> ResultT<bool>
+    {
+        if self.devices.count_documents(doc! { "_id": id }, None).await? == 0 {
+            return Ok(false);
+        }
+
+        let mut set = doc! {
+            "updated_by": updated_by,
+            "updated": DateTime::now(),
+        };
+        let mut unset = doc! {};
+
+        for (field, value) in [("name", name), ("zone", zone)] {
+            match value.as_deref().map(str::trim) {
+                Some("") => { unset.insert(field, ""); }
+                Some(value) => { set.insert(field, value); }
+                None => {}
+            }
+        }
+
+        if let Some(ignored) = ignored {
+            set.insert("ignored", ignored);
+        }
+
+        let mut update = doc! { "$set": set };
+
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
         }
+
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.device_meta.update_one(doc! { "_id": id }, update, options).await?;
+
+        Ok(true)
+    }
+
+    /// Every registered plugin with how recently and how much it has been
+    /// sending. Plugins quiet for `stale_after` seconds are stale and for
+    /// `silent_after` seconds are silent.
+    pub async fn list_plugins(&self, stale_after: u64, silent_after: u64) -> ResultT<Vec<PluginSummary>>
+    {
+        let now = DateTime::now().timestamp_millis();
+        let hour_ago = DateTime::from_millis(now - 60 * 60 * 1000);
+        let day_ago = DateTime::from_millis(now - 60 * 60 * 24 * 1000);
+
+        // Counting only needs the last day, health needs to see back to silent
+        let window = i64::try_from(silent_after).unwrap_or(i64::MAX).saturating_mul(1000);
+        let since = DateTime::from_millis(now.saturating_sub(window)).min(day_ago);
+
+        let plugins: Vec<Document> = self.plugins
+            .clone_with_type::<Document>()
+            .find(None, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        let events = self.events.clone_with_type::<Document>();
+        let commands = self.commands.clone_with_type::<Document>();
+
+        let recent_events = activity(&events, since, hour_ago, day_ago).await?;
+        let recent_commands = activity(&commands, since, hour_ago, day_ago).await?;
+
+        // Plugins quiet for the whole window are silent, they only need a
+        // lookup for when they were last heard from
+        let mut last_heard = HashMap::new();
+
+        for plugin in &plugins {
+            let id = match plugin.get_object_id("_id") {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            if recent_events.contains_key(&id) || recent_commands.contains_key(&id) {
+                continue;
+            }
+
+            let last = match (last_seen(&events, id).await?, last_seen(&commands, id).await?) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+
+            last_heard.insert(id, last);
+        }
+
+        let summaries = plugins
+            .iter()
+            .filter_map(|plugin| {
+                let id = plugin.get_object_id("_id").ok()?;
+                let events = recent_events.get(&id).copied().unwrap_or_default();
+                let commands = recent_commands.get(&id).copied().unwrap_or_default();
+
+                let last_activity = match (events.last, commands.last) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    (a, b) => a.or(b),
+                }
+                .or_else(|| last_heard.get(&id).copied().flatten());
+
+                let quiet_for = last_activity.map(|last| (now - last.timestamp_millis()) / 1000);
+
+                let health = match quiet_for {
+                    Some(secs) if secs < stale_after as i64 => PluginHealth::Healthy,
+                    Some(secs) if secs < silent_after as i64 => PluginHealth::Stale,
+                    _ => PluginHealth::Silent,
+                };
+
+                Some(PluginSummary {
+                    id: id.to_hex(),
+                    name: plugin.get_str("name").ok().map(|name| name.to_string()),
+                    plugin_type: ["plugin_type", "type"]
+                        .iter()
+                        .find_map(|field| plugin.get(*field))
+                        .map(|kind| match kind {
+                            Bson::String(kind) => kind.clone(),
+                            kind => kind.to_string(),
+                        }),
+                    last_activity: last_activity.and_then(|last| last.try_to_rfc3339_string().ok()),
+                    events_last_hour: events.last_hour,
+                    events_last_day: events.last_day,
+                    commands_last_hour: commands.last_hour,
+                    commands_last_day: commands.last_day,
+                    health,
+                })
+            })
+            .collect();
+
+        Ok(summaries)
+    }
+
+    /// Follows new events as they are inserted
+    pub async fn watch_events(
+        &self,
+        device: Option<ObjectId>,
+        plugin: Option<ObjectId>,
+    ) -> ResultT<ChangeStream<ChangeStreamEvent<Document>>>
+    {
+        let pipeline = [inserted(device, plugin)];
+
+        Ok(self.events.clone_with_type::<Document>().watch(pipeline, None).await?)
+    }
+
+    /// Follows new commands as they are inserted, commands are never tied to a device
+    pub async fn watch_commands(
+        &self,
+        plugin: Option<ObjectId>,
+    ) -> ResultT<ChangeStream<ChangeStreamEvent<Document>>>
+    {
+        let pipeline = [inserted(None, plugin), public_commands("fullDocument.")];
+
+        Ok(self.commands.clone_with_type::<Document>().watch(pipeline, None).await?)
     }
 
     pub async fn last_n(&self, n: i64) -> ResultT<EventCommandN>
@@ -260,12 +1346,11 @@ impl AnzenDB
                 }
             })?
             .limit(n)?
-            .lookup("plugins", "metadata.plugin_id", "_id", "plugin")?
-            .lookup("devices", "metadata.device_id", "_id", "device")?
-            .replace_field(&["device", "plugin"])?
+            .sources()?
             .build();
 
         let command_pipeline = pipeline::PipelineBuilder::new()
+            .custom(public_commands(""))?
             .custom(doc! {
                 "$sort": doc! {
                     "timestamp": -1
@@ -279,54 +1364,214 @@ impl AnzenDB
         let event_data = self.events.aggregate(event_pipeline, None).await?;
         let command_data = self.commands.aggregate(command_pipeline, None).await?;
 
-        let vec_events: Vec<_> = event_data.try_collect().await?;
-        let vec_commnads: Vec<_> = command_data.try_collect().await?;
+        let vec_events: Vec<Document> = event_data.try_collect().await?;
+        let vec_commnads: Vec<Document> = command_data.try_collect().await?;
 
         Ok(EventCommandN {
-            events: vec_events,
-            commands: vec_commnads,
+            events: vec_events.iter().filter_map(EventView::from_document).collect(),
+            commands: vec_commnads.iter().filter_map(CommandView::from_document).collect(),
         })
     }
 
     pub async fn search(
         &self,
-        start: Option<String>,
-        end: Option<String>,
+        range: &TimeRange,
         armed: Option<bool>,
         device: Option<String>,
         plugin: Option<String>,
-    ) ->  ResultT<EventCommandN>
+        page: &SearchPage,
+    ) ->  ResultT<(EventCommandN, SearchPages)>
     {
+        // One extra document tells us whether there is another page
         let event_pipeline = pipeline::PipelineBuilder::new()
-            .limit(50)?
-            .find(None, start.clone(), end.clone())?
-            .lookup("devices", "metadata.device_id", "_id", "device")?
-            .lookup("plugins", "metadata.plugin_id", "_id", "plugin")?
-            .replace_field(&["device", "plugin"])?
+            .find(None, Some(range))?
+            .after(page.events_cursor.as_ref(), page.order)?
+            .sort(page.order)?
+            .sources()?
             .find(Some(&[
                 Match::new("metadata.armed", armed),
                 Match::new("device.id", device),
                 Match::new("plugin.name", plugin),
-            ]), None, None)?
+            ]), None)?
+            .limit(page.limit + 1)?
             .build();
 
         let command_pipeline = pipeline::PipelineBuilder::new()
-            .limit(50)?
-            .find(None, start, end)?
+            .find(None, Some(range))?
+            .custom(public_commands(""))?
+            .after(page.commands_cursor.as_ref(), page.order)?
+            .sort(page.order)?
+            .limit(page.limit + 1)?
             .lookup("plugins", "metadata.plugin_id", "_id", "plugin")?
             .replace_field(&["plugin"])?
             .build();
-        
 
         let event_data = self.events.aggregate(event_pipeline, None).await?;
         let command_data = self.commands.aggregate(command_pipeline, None).await?;
 
-        let vec_events: Vec<_> = event_data.try_collect().await?;
-        let vec_commnads: Vec<_> = command_data.try_collect().await?;
+        let mut vec_events: Vec<Document> = event_data.try_collect().await?;
+        let mut vec_commnads: Vec<Document> = command_data.try_collect().await?;
 
-        Ok(EventCommandN {
-            events: vec_events,
-            commands: vec_commnads,
+        let pages = SearchPages {
+            events: next_page(&mut vec_events, page.limit),
+            commands: next_page(&mut vec_commnads, page.limit),
+        };
+
+        Ok((EventCommandN {
+            events: vec_events.iter().filter_map(EventView::from_document).collect(),
+            commands: vec_commnads.iter().filter_map(CommandView::from_document).collect(),
+        }, pages))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Activity
+{
+    last: Option<DateTime>,
+    last_hour: i64,
+    last_day: i64,
+}
+
+/// When something created at `now` and lasting `ttl` seconds expires
+fn expires_after(now: DateTime, ttl: u64) -> ResultT<DateTime>
+{
+    i64::try_from(ttl)
+        .ok()
+        .and_then(|ttl| ttl.checked_mul(1000))
+        .and_then(|ttl| now.timestamp_millis().checked_add(ttl))
+        .map(DateTime::from_millis)
+        .ok_or_else(|| "Expiry is out of range".into())
+}
+
+/// Latest timestamp and recent counts per plugin in an events or commands
+/// collection, looking only at what was recorded `since`
+async fn activity(
+    collection: &Collection<Document>,
+    since: DateTime,
+    hour_ago: DateTime,
+    day_ago: DateTime,
+) -> ResultT<HashMap<ObjectId, Activity>>
+{
+    let window = TimeRange {
+        start: Some(since),
+        end: None,
+    };
+
+    let pipeline = pipeline::PipelineBuilder::new()
+        .find(None, Some(&window))?
+        .group("$metadata.plugin_id", doc! {
+            "last": doc! { "$max": "$timestamp" },
+            "last_hour": doc! {
+                "$sum": doc! { "$cond": [doc! { "$gte": ["$timestamp", hour_ago] }, 1, 0] }
+            },
+            "last_day": doc! {
+                "$sum": doc! { "$cond": [doc! { "$gte": ["$timestamp", day_ago] }, 1, 0] }
+            }
+        })?
+        .build();
+
+    let docs: Vec<Document> = collection.aggregate(pipeline, None).await?.try_collect().await?;
+
+    let count = |doc: &Document, field: &str| -> i64 {
+        match doc.get(field) {
+            Some(Bson::Int32(count)) => *count as i64,
+            Some(Bson::Int64(count)) => *count,
+            _ => 0,
+        }
+    };
+
+    Ok(docs
+        .iter()
+        .filter_map(|doc| {
+            let id = doc.get_object_id("_id").ok()?;
+
+            Some((id, Activity {
+                last: doc.get_datetime("last").ok().copied(),
+                last_hour: count(doc, "last_hour"),
+                last_day: count(doc, "last_day"),
+            }))
         })
+        .collect())
+}
+
+/// When a plugin last wrote to an events or commands collection
+async fn last_seen(collection: &Collection<Document>, plugin: ObjectId) -> ResultT<Option<DateTime>>
+{
+    let options = FindOneOptions::builder()
+        .sort(doc! { "timestamp": -1 })
+        .projection(doc! { "timestamp": 1 })
+        .build();
+
+    let last = collection.find_one(doc! { "metadata.plugin_id": plugin }, options).await?;
+
+    Ok(last.and_then(|doc| doc.get_datetime("timestamp").ok().copied()))
+}
+
+/// Buckets to report, covering the range or, where it is open, the data that was found.
+/// A range without an end runs up to now.
+fn bucket_starts(range: &TimeRange, bucket: Bucket, tz: Tz, dates: &[DateTime]) -> Vec<DateTime>
+{
+    let start = match range.start.or_else(|| dates.iter().min().copied()) {
+        Some(start) => start,
+        None => return Vec::new(),
+    };
+
+    let end = match (range.end, range.start) {
+        (Some(end), _) => end,
+        (None, Some(_)) => DateTime::now(),
+        (None, None) => match dates.iter().max() {
+            Some(last) => DateTime::from_millis(last.timestamp_millis() + 1),
+            None => return Vec::new(),
+        },
+    };
+
+    bucket.starts(tz, start, end)
+}
+
+/// Drops the extra document fetched past the page and points the cursor at the last kept one
+fn next_page(docs: &mut Vec<Document>, limit: i64) -> CursorPage
+{
+    let has_more = docs.len() as i64 > limit;
+    docs.truncate(limit as usize);
+
+    let next_cursor = match has_more {
+        true => docs.last().and_then(Cursor::from_document).map(|cursor| cursor.encode()),
+        false => None,
+    };
+
+    CursorPage { next_cursor, has_more }
+}
+
+/// Leaves out commands whose data is one of the private requests, `prefix`
+/// is where the command sits in the documents being matched
+fn public_commands(prefix: &str) -> Document
+{
+    let pattern = format!(r#""request"\s*:\s*"({})""#, PRIVATE_REQUESTS.join("|"));
+    let data = format!("{}data", prefix);
+    let metadata = format!("{}metadata.data", prefix);
+
+    doc! {
+        "$match": doc! {
+            "$nor": [
+                doc! { data: doc! { "$regex": pattern.as_str() } },
+                doc! { metadata: doc! { "$regex": pattern.as_str() } },
+            ]
+        }
     }
 }
+
+/// Change stream stage matching inserts from a device or plugin
+fn inserted(device: Option<ObjectId>, plugin: Option<ObjectId>) -> Document
+{
+    let mut filter = doc! { "operationType": "insert" };
+
+    if let Some(device) = device {
+        filter.insert("fullDocument.metadata.device_id", device);
+    }
+
+    if let Some(plugin) = plugin {
+        filter.insert("fullDocument.metadata.plugin_id", plugin);
+    }
+
+    doc! { "$match": filter }
+}
@@ -1,5 +1,6 @@
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
 
 // Adapted from https://rust-lang-nursery.github.io/rust-cookbook/algorithms/randomness.html
 
@@ -11,3 +12,18 @@ pub async fn gen_salt() -> String
         .map(char::from)
         .collect()
 }
+
+pub fn gen_token() -> String
+{
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+/// Tokens are looked up by their hash so they are never stored in plain text
+pub fn hash_token(token: &str) -> String
+{
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
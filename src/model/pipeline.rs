@@ -1,4 +1,6 @@
-use mongodb::bson::{DateTime, Document, doc, Bson};
+use chrono::{Datelike, Duration, NaiveDate, TimeZone, Timelike};
+use chrono_tz::Tz;
+use mongodb::bson::{DateTime, Document, doc, Bson, oid::ObjectId};
 
 use crate::ResultT;
 
@@ -23,6 +25,178 @@ impl Match {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+impl SortOrder {
+    fn direction(&self) -> i32 {
+        match self {
+            SortOrder::Ascending => 1,
+            SortOrder::Descending => -1,
+        }
+    }
+}
+
+/// Position of the last document on a page, so the next page starts after it
+pub struct Cursor {
+    timestamp: DateTime,
+    id: ObjectId,
+}
+
+impl Cursor {
+    /// Cursors are opaque to clients, only `decode` needs to understand them
+    pub fn encode(&self) -> String {
+        let raw = format!("{}_{}", self.timestamp.timestamp_millis(), self.id.to_hex());
+        base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str) -> ResultT<Cursor> {
+        let raw = String::from_utf8(base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)?)?;
+        let (millis, id) = raw.split_once('_').ok_or("Malformed cursor")?;
+
+        Ok(Cursor {
+            timestamp: DateTime::from_millis(millis.parse()?),
+            id: ObjectId::parse_str(id)?,
+        })
+    }
+
+    /// Cursor for the document a page ended on
+    pub fn from_document(doc: &Document) -> Option<Cursor> {
+        Some(Cursor {
+            timestamp: *doc.get_datetime("timestamp").ok()?,
+            id: doc.get_object_id("_id").ok()?,
+        })
+    }
+}
+
+/// Most buckets zero filling will add, so a minute bucket over years stays bounded
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl Bucket {
+    pub fn parse(bucket: &str) -> Option<Bucket> {
+        match bucket {
+            "minute" => Some(Bucket::Minute),
+            "hour" => Some(Bucket::Hour),
+            "day" => Some(Bucket::Day),
+            "week" => Some(Bucket::Week),
+            "month" => Some(Bucket::Month),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Bucket::Minute => "minute",
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+
+    /// Start of the bucket holding `time`, matching what `$dateTrunc` gives
+    fn floor(&self, time: chrono::DateTime<Tz>) -> Option<chrono::DateTime<Tz>> {
+        let date = time.date_naive();
+
+        match self {
+            Bucket::Minute => time.with_second(0)?.with_nanosecond(0),
+            Bucket::Hour => time.with_minute(0)?.with_second(0)?.with_nanosecond(0),
+            Bucket::Day => local_midnight(date, time.timezone()),
+            Bucket::Week => {
+                let monday = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+                local_midnight(monday, time.timezone())
+            }
+            Bucket::Month => local_midnight(date.with_day(1)?, time.timezone()),
+        }
+    }
+
+    /// Start of the bucket after the one starting at `start`
+    fn next(&self, start: chrono::DateTime<Tz>) -> Option<chrono::DateTime<Tz>> {
+        let date = start.date_naive();
+
+        match self {
+            Bucket::Minute => Some(start + Duration::minutes(1)),
+            Bucket::Hour => Some(start + Duration::hours(1)),
+            Bucket::Day => local_midnight(date.succ_opt()?, start.timezone()),
+            Bucket::Week => local_midnight(date + Duration::days(7), start.timezone()),
+            Bucket::Month => {
+                let next = match date.month() {
+                    12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)?,
+                    month => NaiveDate::from_ymd_opt(date.year(), month + 1, 1)?,
+                };
+                local_midnight(next, start.timezone())
+            }
+        }
+    }
+
+    /// Shortest a bucket can be, allowing for days shortened by DST
+    fn min_len(&self) -> Duration {
+        match self {
+            Bucket::Minute => Duration::minutes(1),
+            Bucket::Hour => Duration::hours(1),
+            Bucket::Day => Duration::hours(23),
+            Bucket::Week => Duration::days(7) - Duration::hours(1),
+            Bucket::Month => Duration::days(28) - Duration::hours(1),
+        }
+    }
+
+    /// Every bucket start from the one holding `start` up to `end`, which is
+    /// excluded. Past `MAX_BUCKETS` the oldest are left out, never the newest.
+    pub fn starts(&self, tz: Tz, start: DateTime, end: DateTime) -> Vec<DateTime> {
+        let earliest = end.timestamp_millis()
+            .saturating_sub((MAX_BUCKETS as i64).saturating_mul(self.min_len().num_milliseconds()));
+        let start = start.timestamp_millis().max(earliest);
+
+        let mut starts = Vec::new();
+
+        let mut current = tz.timestamp_millis_opt(start)
+            .single()
+            .and_then(|time| self.floor(time));
+
+        while let Some(time) = current {
+            if time.timestamp_millis() >= end.timestamp_millis() {
+                break;
+            }
+
+            starts.push(DateTime::from_millis(time.timestamp_millis()));
+            current = self.next(time);
+        }
+
+        if starts.len() > MAX_BUCKETS {
+            starts.drain(..starts.len() - MAX_BUCKETS);
+        }
+
+        starts
+    }
+}
+
+/// Start of a day in `tz`. Zones that skip midnight for DST start an hour later.
+pub fn local_midnight(date: NaiveDate, tz: Tz) -> Option<chrono::DateTime<Tz>> {
+    [0, 1]
+        .iter()
+        .filter_map(|hour| date.and_hms_opt(*hour, 0, 0))
+        .find_map(|time| tz.from_local_datetime(&time).earliest())
+}
+
+/// Half open range, `start` is included and `end` is not
+#[derive(Clone, Copy, Default)]
+pub struct TimeRange {
+    pub start: Option<DateTime>,
+    pub end: Option<DateTime>,
+}
+
 pub struct PipelineBuilder {
     pipeline: Vec<Document>
 }
@@ -39,18 +213,20 @@ impl PipelineBuilder {
         Ok(self)
     } 
 
-    pub fn find(&mut self, find: Option<&[Match]>, start: Option<String>, end: Option<String>) -> ResultT<&mut Self> {
+    pub fn find(&mut self, find: Option<&[Match]>, time: Option<&TimeRange>) -> ResultT<&mut Self> {
 
         let mut match_doc = doc! {};
 
         let mut range = doc! {};
 
-        if let Some(time) = start {
-            range.insert("$lt", DateTime::parse_rfc3339_str(time)?);
-        }
+        if let Some(time) = time {
+            if let Some(start) = time.start {
+                range.insert("$gte", start);
+            }
 
-        if let Some(time) = end {
-            range.insert("$lt", DateTime::parse_rfc3339_str(time)?);
+            if let Some(end) = time.end {
+                range.insert("$lt", end);
+            }
         }
 
         if !range.is_empty() {
@@ -72,6 +248,71 @@ impl PipelineBuilder {
         Ok(self)
     }
 
+    /// Orders by timestamp, with the id breaking ties so pages are stable
+    pub fn sort(&mut self, order: SortOrder) -> ResultT<&mut Self> {
+        self.pipeline.push(doc! {
+            "$sort": doc! {
+                "timestamp": order.direction(),
+                "_id": order.direction()
+            }
+        });
+        Ok(self)
+    }
+
+    /// Skips everything up to and including the cursor in the given order
+    pub fn after(&mut self, cursor: Option<&Cursor>, order: SortOrder) -> ResultT<&mut Self> {
+        if let Some(cursor) = cursor {
+            let op = match order {
+                SortOrder::Ascending => "$gt",
+                SortOrder::Descending => "$lt",
+            };
+
+            self.pipeline.push(doc! {
+                "$match": doc! {
+                    "$or": [
+                        doc! { "timestamp": doc! { op: cursor.timestamp } },
+                        doc! { "timestamp": cursor.timestamp, "_id": doc! { op: cursor.id } },
+                    ]
+                }
+            });
+        }
+        Ok(self)
+    }
+
+    /// Adds `field` holding the start of the bucket each document falls in
+    pub fn bucket(&mut self, field: &str, bucket: Bucket, tz: Tz) -> ResultT<&mut Self> {
+        self.pipeline.push(doc! {
+            "$addFields": doc! {
+                field: doc! {
+                    "$dateTrunc": doc! {
+                        "date": "$timestamp",
+                        "unit": bucket.as_str(),
+                        "timezone": tz.name(),
+                        "startOfWeek": "monday"
+                    }
+                }
+            }
+        });
+        Ok(self)
+    }
+
+    pub fn group<T: Into<Bson>>(&mut self, id: T, fields: Document) -> ResultT<&mut Self> {
+        let mut group = doc! { "_id": id };
+        group.extend(fields);
+
+        self.pipeline.push(doc! {
+            "$group": group
+        });
+        Ok(self)
+    }
+
+    /// Joins the device and plugin an event came from
+    pub fn sources(&mut self) -> ResultT<&mut Self> {
+        self.lookup("devices", "metadata.device_id", "_id", "device")?
+            .lookup("plugins", "metadata.plugin_id", "_id", "plugin")?
+            .replace_field(&["device", "plugin"])
+    }
+
     pub fn limit(&mut self, limit: i64) -> ResultT<&mut Self> {
         self.pipeline.push(doc! {
             "$limit": limit
@@ -112,3 +353,105 @@ impl PipelineBuilder {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            timestamp: DateTime::from_millis(1_672_531_200_123),
+            id: ObjectId::new(),
+        };
+
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+
+        assert_eq!(decoded.timestamp, cursor.timestamp);
+        assert_eq!(decoded.id, cursor.id);
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let not_base64 = "not base64!";
+        let no_separator = base64::encode_config("1672531200123", base64::URL_SAFE_NO_PAD);
+        let bad_millis = base64::encode_config("soon_63b0cd00a1b2c3d4e5f60718", base64::URL_SAFE_NO_PAD);
+        let bad_id = base64::encode_config("1672531200123_xyz", base64::URL_SAFE_NO_PAD);
+
+        for cursor in [not_base64, &no_separator, &bad_millis, &bad_id] {
+            assert!(Cursor::decode(cursor).is_err(), "{}", cursor);
+        }
+    }
+
+    fn at(tz: Tz, time: &str) -> chrono::DateTime<Tz> {
+        chrono::DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&tz)
+    }
+
+    fn utc(time: chrono::DateTime<Tz>) -> String {
+        time.with_timezone(&chrono::Utc).to_rfc3339()
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let thursday = at(Tz::UTC, "2023-03-16T15:30:00Z");
+
+        assert_eq!(utc(Bucket::Week.floor(thursday).unwrap()), "2023-03-13T00:00:00+00:00");
+
+        let monday = at(Tz::UTC, "2023-03-13T00:00:00Z");
+        assert_eq!(utc(Bucket::Week.floor(monday).unwrap()), "2023-03-13T00:00:00+00:00");
+        assert_eq!(utc(Bucket::Week.next(monday).unwrap()), "2023-03-20T00:00:00+00:00");
+    }
+
+    #[test]
+    fn months_roll_over_into_the_next_year() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let end_of_january = at(tz, "2023-01-31T23:59:00+01:00");
+
+        assert_eq!(utc(Bucket::Month.floor(end_of_january).unwrap()), "2022-12-31T23:00:00+00:00");
+
+        let december = at(tz, "2023-12-01T00:00:00+01:00");
+        assert_eq!(utc(Bucket::Month.next(december).unwrap()), "2023-12-31T23:00:00+00:00");
+    }
+
+    #[test]
+    fn hours_follow_half_hour_zones() {
+        let tz: Tz = "Asia/Kolkata".parse().unwrap();
+        let time = at(tz, "2023-01-01T10:45:00+05:30");
+
+        assert_eq!(utc(Bucket::Hour.floor(time).unwrap()), "2023-01-01T04:30:00+00:00");
+        assert_eq!(utc(Bucket::Day.floor(time).unwrap()), "2022-12-31T18:30:00+00:00");
+    }
+
+    #[test]
+    fn days_follow_dst_changes() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let starts = Bucket::Day.starts(
+            tz,
+            DateTime::parse_rfc3339_str("2023-03-25T12:00:00Z").unwrap(),
+            DateTime::parse_rfc3339_str("2023-03-28T00:00:00Z").unwrap(),
+        );
+
+        let starts: Vec<String> = starts.iter().map(|start| start.try_to_rfc3339_string().unwrap()).collect();
+        assert_eq!(starts, ["2023-03-24T23:00:00Z", "2023-03-25T23:00:00Z", "2023-03-26T22:00:00Z", "2023-03-27T22:00:00Z"]);
+
+        // São Paulo skipped midnight when DST began
+        let tz: Tz = "America/Sao_Paulo".parse().unwrap();
+        let time = at(tz, "2018-11-04T12:00:00-02:00");
+        assert_eq!(utc(Bucket::Day.floor(time).unwrap()), "2018-11-04T03:00:00+00:00");
+    }
+
+    #[test]
+    fn long_ranges_keep_the_newest_buckets() {
+        let end = DateTime::parse_rfc3339_str("2023-02-01T00:00:00Z").unwrap();
+        let start = DateTime::parse_rfc3339_str("2022-01-01T00:00:00Z").unwrap();
+
+        let starts = Bucket::Minute.starts(Tz::UTC, start, end);
+
+        assert_eq!(starts.len(), MAX_BUCKETS);
+        assert_eq!(starts.last().unwrap().timestamp_millis(), end.timestamp_millis() - 60_000);
+
+        // Open ranges start at the epoch, which must not overflow
+        let starts = Bucket::Month.starts(Tz::UTC, DateTime::from_millis(0), end);
+        assert_eq!(starts.last().unwrap().try_to_rfc3339_string().unwrap(), "2023-01-01T00:00:00Z");
+    }
+}
@@ -0,0 +1,249 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// A refresh session. Only a hash of the refresh secret is stored, the
+/// secret itself is handed to the client once and rotated on every refresh.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session
+{
+    pub _id: ObjectId,
+    pub email: String,
+    pub token_hash: String,
+    /// Hashes of secrets already rotated away, the most recent last
+    #[serde(default)]
+    pub used_hashes: Vec<String>,
+    pub created: DateTime,
+    pub expires: DateTime,
+    pub revoked: bool,
+}
+
+/// Single use password reset. Only a hash of the token is stored, the token
+/// itself goes to the mail plugin sealed to its key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordReset
+{
+    pub _id: ObjectId,
+    pub email: String,
+    pub token_hash: String,
+    pub created: DateTime,
+    pub expires: DateTime,
+    pub used: bool,
+}
+
+/// Admin issued permission for one email to register with the given role
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invitation
+{
+    pub _id: ObjectId,
+    pub email: String,
+    pub role: Role,
+    pub created_by: String,
+    pub created: DateTime,
+    pub expires: DateTime,
+    pub used: bool,
+    pub revoked: bool,
+}
+
+/// Key for machine clients. Only a hash of the key is stored, `prefix`
+/// keeps enough of it to tell keys apart in listings.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey
+{
+    pub _id: ObjectId,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<Permission>,
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    pub expires: Option<DateTime>,
+    pub created_by: String,
+    pub created: DateTime,
+    pub last_used: Option<DateTime>,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission
+{
+    ViewStats,
+    ArmDisarm,
+    Search,
+    ManageUsers,
+    AddEmail,
+    ViewAudit,
+    ManageDevices,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role
+{
+    Admin,
+    Operator,
+    Viewer,
+}
+
+impl Role
+{
+    pub fn permissions(&self) -> &'static [Permission]
+    {
+        match self {
+            Role::Admin => &[
+                Permission::ViewStats,
+                Permission::ArmDisarm,
+                Permission::Search,
+                Permission::ManageUsers,
+                Permission::AddEmail,
+                Permission::ViewAudit,
+                Permission::ManageDevices,
+            ],
+            Role::Operator => &[
+                Permission::ViewStats,
+                Permission::ArmDisarm,
+                Permission::AddEmail,
+            ],
+            Role::Viewer => &[Permission::ViewStats],
+        }
+    }
+
+    pub fn grants(&self, permission: Permission) -> bool
+    {
+        self.permissions().contains(&permission)
+    }
+
+    /// Numeric level kept on the user record for the rest of anzen
+    pub fn level(&self) -> u8
+    {
+        match self {
+            Role::Admin => 0,
+            Role::Operator => 2,
+            Role::Viewer => 3,
+        }
+    }
+
+    /// Role for records created before roles were stored
+    pub fn from_level(level: u8) -> Role
+    {
+        match level {
+            0 => Role::Admin,
+            1 | 2 => Role::Operator,
+            _ => Role::Viewer,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+/// View over the `users` collection without the password hash and salt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Account
+{
+    pub _id: ObjectId,
+    pub email: String,
+    pub username: String,
+    pub created: DateTime,
+    pub level: u8,
+    #[serde(default)]
+    pub role: Option<Role>,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Set by an admin to force a password change before anything else
+    #[serde(default)]
+    pub password_reset: bool,
+    #[serde(default)]
+    pub totp: Option<Totp>,
+    /// Set on accounts created by an identity provider login
+    #[serde(default)]
+    pub external: Option<ExternalIdentity>,
+}
+
+impl Account
+{
+    pub fn role(&self) -> Role
+    {
+        self.role.unwrap_or_else(|| Role::from_level(self.level))
+    }
+
+    pub fn totp_enabled(&self) -> bool
+    {
+        matches!(&self.totp, Some(totp) if totp.enabled)
+    }
+}
+
+/// Provider account an externally created user is linked to. Logins are
+/// matched on this, never on the email the provider reports.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExternalIdentity
+{
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// TOTP enrolment stored on the user record. The secret is kept base32
+/// encoded, recovery codes only as hashes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Totp
+{
+    pub secret: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code, a code is only good once
+    #[serde(default)]
+    pub last_step: Option<i64>,
+}
+
+/// Household wide security policy, stored as a single document
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SecuritySettings
+{
+    #[serde(default)]
+    pub require_2fa_arm: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome
+{
+    Success,
+    Failure,
+    Denied,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry
+{
+    pub _id: ObjectId,
+    pub timestamp: DateTime,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub outcome: AuditOutcome,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Names and grouping admins give a device, kept apart from the records
+/// plugins write so re-registering a device does not lose them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceMeta
+{
+    /// Same as the device `_id` that events reference in `metadata.device_id`
+    pub _id: ObjectId,
+    pub name: Option<String>,
+    pub zone: Option<String>,
+    #[serde(default)]
+    pub ignored: bool,
+    pub updated_by: String,
+    pub updated: DateTime,
+}
@@ -1,48 +1,188 @@
-use anzen_lib::client::ClientRef;
-
 use crate::{model, ResultT};
+use rocket::{Build, Rocket, Route};
 
+mod audit;
 mod auth;
 mod cors;
 mod data;
+mod devices;
 mod errors;
 pub mod returns;
 mod state;
 mod account;
 mod corefuncs;
 mod helpers;
+mod invites;
+mod keys;
+mod mfa;
+mod oidc;
+mod openapi;
+mod permissions;
+mod plugins;
+mod signing;
+mod stream;
+
+pub use state::CoreAPI;
 
-pub async fn launch(
-    config: crate::config::Config,
-    token: String,
-    name: String,
-    client: ClientRef,
-) -> ResultT<()>
+pub async fn launch(rocket: Rocket<Build>) -> ResultT<()>
 {
-    let validation = state::Validation::init(config.key, config.auth_users);
-    let core_api = state::CoreAPI::init(token, client, name);
-    let db_state = model::AnzenDB::init(config.db_uri).await?;
+    if let Some(db) = rocket.state::<model::AnzenDB>() {
+        if let Err(e) = db.ensure_indexes().await {
+            warn!("Could not create indexes: {}", e);
+        }
+    }
 
-    let _ = rocket::build()
-        .mount("/api/v1/auth", routes![auth::login, auth::register,])
-        .mount(
-            "/api/v1/data",
-            routes![data::stats, data::test, data::toggle, data::search],
-        )
-        .mount(
-            "/api/v1/users",
-            routes![account::user, account::users, account::updatepassword]
-        )
-        .mount(
-            "/api/v1/core",
-            routes![corefuncs::addmail]
-        )
-        .mount("/", routes![cors::resp_options])
+    let _ = rocket.launch().await?;
+    Ok(())
+}
+
+/// Sets up state and mounts every route without launching, so tests can
+/// drive the API through a local client
+pub async fn build_rocket(config: crate::config::Config, core_api: CoreAPI) -> ResultT<Rocket<Build>>
+{
+    let db_state = model::AnzenDB::init(config.db_uri, &config.db_name).await?;
+    let keyring = signing::Keyring::init(config.key, config.jwt)?;
+    let validation = state::Validation::init(
+        keyring,
+        config.auth_users,
+        config.admin_users,
+        config.access_token_ttl,
+        config.refresh_token_ttl,
+        config.reset_token_ttl,
+        config.invite_ttl,
+        db_state.clone(),
+    );
+    let throttle = state::LoginThrottle::init(
+        config.login_max_attempts,
+        config.login_max_attempts_ip,
+        config.login_lockout_secs,
+        config.login_lockout_max_secs,
+    );
+    let reset_throttle = state::ResetThrottle(state::LoginThrottle::init(
+        config.reset_max_requests,
+        config.reset_max_requests_ip,
+        config.login_lockout_secs,
+        config.login_lockout_max_secs,
+    ));
+    let reset_mailer = state::ResetMailer::init(config.reset_mailer_key.as_deref())?;
+    let password_policy = helpers::PasswordPolicy::init(config.password)?;
+    let oidc = oidc::OidcClient::init(config.oidc);
+    let pin_attempts = state::LoginThrottle::init(
+        config.login_max_attempts,
+        config.login_max_attempts_ip,
+        config.login_lockout_secs,
+        config.login_lockout_max_secs,
+    );
+    let arm_control = state::ArmControl::init(config.arm_pin, config.arm_max_delay, pin_attempts);
+
+    let mut figment = rocket::Config::figment()
+        .merge(("address", config.address))
+        .merge(("port", config.port));
+
+    if let Some(tls) = config.tls {
+        figment = figment.merge(("tls", rocket::config::TlsConfig::from_paths(tls.certs, tls.key)));
+    }
+
+    let mut rocket = rocket::custom(figment);
+
+    for (base, routes) in mounts() {
+        rocket = rocket.mount(base, routes);
+    }
+
+    #[cfg(feature = "swagger-ui")]
+    {
+        use utoipa::OpenApi;
+
+        rocket = rocket.mount(
+            "/",
+            utoipa_swagger_ui::SwaggerUi::new("/api/v1/docs/<_..>")
+                .url("/api/v1/docs/openapi.json", openapi::ApiDoc::openapi()),
+        );
+    }
+
+    let rocket = rocket
         .manage(validation)
+        .manage(throttle)
+        .manage(reset_throttle)
+        .manage(reset_mailer)
+        .manage(password_policy)
+        .manage(oidc)
+        .manage(arm_control)
+        .manage(config.plugin_health)
         .manage(db_state)
         .manage(core_api)
-        .attach(cors::CORS)
-        .launch()
-        .await?;
-    Ok(())
+        .attach(cors::CORS::init(config.cors_origins));
+
+    Ok(rocket)
+}
+
+/// Every route the API serves, grouped by where it is mounted
+pub fn mounts() -> Vec<(&'static str, Vec<Route>)>
+{
+    vec![
+        (
+            "/api/v1/auth",
+            routes![
+                auth::login,
+                auth::register,
+                auth::refresh,
+                auth::logout,
+                auth::logout_all,
+                auth::forgot,
+                auth::reset,
+                mfa::setup,
+                mfa::enable,
+                mfa::disable,
+                mfa::login,
+                oidc::login,
+                oidc::callback,
+            ],
+        ),
+        (
+            "/api/v1/data",
+            routes![
+                data::stats,
+                data::test,
+                data::toggle,
+                data::arm,
+                data::disarm,
+                data::search,
+            ],
+        ),
+        (
+            "/api/v1/users",
+            routes![
+                account::user,
+                account::users,
+                account::updatepassword,
+                account::get_user,
+                account::set_role,
+                account::disable,
+                account::enable,
+                account::delete_user,
+                account::force_reset,
+                account::unlock,
+                account::policy,
+                account::set_policy,
+            ],
+        ),
+        (
+            "/api/v1/invitations",
+            routes![invites::list, invites::create, invites::revoke],
+        ),
+        (
+            "/api/v1/keys",
+            routes![keys::list, keys::create, keys::rotate, keys::revoke],
+        ),
+        (
+            "/api/v1/devices",
+            routes![devices::list, devices::get_device, devices::update],
+        ),
+        ("/api/v1/plugins", routes![plugins::list]),
+        ("/api/v1/audit", routes![audit::list]),
+        ("/api/v1/stream", routes![stream::stream]),
+        ("/api/v1/core", routes![corefuncs::addmail]),
+        ("/api/v1", routes![openapi::spec]),
+        ("/", routes![cors::resp_options, signing::jwks]),
+    ]
 }
@@ -1,20 +1,59 @@
 use rocket::serde::json::Value;
 use rocket::State;
 use serde_json::json;
-use super::{auth::{Claims, TextError}, errors::{APIError, ErrorJson, self}};
+use super::{auth::{self, Claims, TextError}, errors::{APIError, ErrorJson, self}};
+use super::audit::AuditContext;
+use super::helpers::{self, PasswordPolicy};
+use super::permissions::{scope, Authorized};
+use super::returns::{UserList, UserSummary};
+use super::state::{CoreAPI, LoginThrottle};
+use crate::model::types::{Account, AuditOutcome, Role};
 use crate::model::AnzenDB;
+use crate::ResultT;
+use std::net::IpAddr;
 
 
 use serde::Deserialize;
 use rocket::serde::json::Json;
+use utoipa::ToSchema;
 
-#[derive(Deserialize)]
+const DEFAULT_PAGE_SIZE: i64 = 25;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct PasswordForm
 {
+    current_password: String,
     password: String,
 }
 
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct PolicyForm
+{
+    require_2fa_arm: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct RoleForm
+{
+    role: Option<Role>,
+    level: Option<u8>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/user",
+    operation_id = "account_user",
+    tag = "users",
+    responses(
+        (status = 200, description = "The logged in user"),
+        (status = 401, description = "Not logged in"),
+    ),
+    security(("bearer_token" = []))
+)]
 #[get("/user")]
 pub async fn user(
     claims: Result<Claims, TextError>,
@@ -25,62 +64,549 @@ pub async fn user(
 
     let db = db.inner();
 
-    let user = db.get_user(&email).await.unwrap();
+    let user = db.get_account(&email).await.unwrap();
 
     Ok(json!({
         "data": {
             "username": user.username,
             "email": user.email,
             "created": user.created,
-            "level": user.level
+            "level": user.level,
+            "role": user.role()
         }
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/updatepassword",
+    operation_id = "account_updatepassword",
+    tag = "users",
+    request_body = PasswordForm,
+    responses(
+        (status = 200, description = "Password changed and other sessions revoked"),
+        (status = 401, description = "Current password is wrong"),
+        (status = 422, description = "Password failed validation"),
+    ),
+    security(("bearer_token" = []))
+)]
 #[post("/updatepassword", data = "<form>")]
 pub async fn updatepassword(
     claims: Result<Claims, TextError>,
     db: &State<AnzenDB>,
+    policy: &State<PasswordPolicy>,
+    throttle: &State<LoginThrottle>,
+    core_api: &State<CoreAPI>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
     form: Json<PasswordForm>,
 ) -> Result<Value, TextError>
 {
-    let email = claims?.sub;
+    let claims = claims?;
+    let email = claims.sub;
 
     let db = db.inner();
+    let throttle = throttle.inner();
 
-    match db.change_password(&email, form.password.as_bytes()).await {
-        Ok(outcome) => Ok(json!({ "ok": outcome })),
-        Err(_) => Err(errors::APIError::Internal(ErrorJson::new(
+    if let Some(retry_after) = throttle.locked(&email, ip).await {
+        return Err(APIError::locked_out(retry_after));
+    }
+
+    match db.valid_user(&email, &form.current_password).await {
+        Ok(true) => throttle.success(&email).await,
+        _ => {
+            auth::login_failed(throttle, &audit, db, &email, ip).await;
+            return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_PWD)));
+        }
+    }
+
+    helpers::check(policy.validate_password(&form.password))?;
+
+    if db.change_password(&email, form.password.as_bytes()).await.is_err() {
+        audit.record(db, &email, "user.password.change", Some(&email), AuditOutcome::Failure).await;
+        return Err(errors::APIError::Internal(ErrorJson::new(
             "Internal error updating password"
-        )))
+        )));
     }
+
+    audit.record(db, &email, "user.password.change", Some(&email), AuditOutcome::Success).await;
+
+    // Every other login made with the old password stops working
+    let revoked = db.revoke_other_sessions(&email, &claims.sid).await.unwrap_or(0);
+
+    let _ = core_api.security_notification(email, "password-changed").await;
+
+    Ok(json!({ "ok": true, "revoked": revoked }))
 }
 
-#[get("/users")]
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/users",
+    operation_id = "account_users",
+    tag = "users",
+    params(
+        ("page" = Option<u64>, Query),
+        ("per_page" = Option<i64>, Query),
+        ("email" = Option<String>, Query),
+        ("role" = Option<String>, Query),
+        ("disabled" = Option<bool>, Query),
+    ),
+    responses(
+        (status = 200, description = "Page of users", body = UserList),
+        (status = 400, description = "Unknown role or page out of range"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[get("/users?<page>&<per_page>&<email>&<role>&<disabled>")]
 pub async fn users(
-    claims: Result<Claims, TextError>,
+    page: Option<u64>,
+    per_page: Option<i64>,
+    email: Option<String>,
+    role: Option<String>,
+    disabled: Option<bool>,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
     db: &State<AnzenDB>,
-) -> Result<Value, TextError>
+) -> Result<Json<UserList>, TextError>
 {
-    let email = claims?.sub;
+    auth?;
 
     let db = db.inner();
 
-    let user = db.get_user(&email).await.unwrap();
+    let page = page.unwrap_or(0);
+    let per_page = per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
 
-    if user.level != 0 {
-        return Err(APIError::Forbidden(ErrorJson::new(
-            "Must have admin credentials to access all users"
-        )))
+    if page.checked_mul(per_page as u64).is_none() {
+        return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_INVALID_PAGE)));
     }
 
-    Ok(json!({
-        "data": {
-            "username": user.username,
-            "email": user.email,
-            "created": user.created,
-            "level": user.level
+    let role = match role {
+        Some(role) => match parse_role(&role) {
+            Some(role) => Some(role),
+            None => return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_UNKNOWN_ROLE))),
+        },
+        None => None,
+    };
+
+    let (users, total) = match db.get_all_users(email, role, disabled, page, per_page).await {
+        Ok(v) => v,
+        Err(_) => {
+            return Err(APIError::Internal(ErrorJson::new(
+                errors::MSG_INTERNAL_DB_ERR,
+            )))
         }
+    };
+
+    Ok(Json(UserList {
+        users: users.into_iter().map(UserSummary::from).collect(),
+        page,
+        per_page,
+        total,
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{id}",
+    operation_id = "account_get_user",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "The user", body = UserSummary),
+        (status = 404, description = "User does not exist"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[get("/<id>")]
+pub async fn get_user(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Json<UserSummary>, TextError>
+{
+    auth?;
+
+    let account = find_account(db.inner(), id).await?;
+
+    Ok(Json(UserSummary::from(account)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/role",
+    operation_id = "account_set_role",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    request_body = RoleForm,
+    responses(
+        (status = 200, description = "The updated user", body = UserSummary),
+        (status = 404, description = "User does not exist"),
+        (status = 409, description = "Cannot change your own account, or it is the last admin"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/<id>/role", data = "<form>")]
+pub async fn set_role(
+    id: &str,
+    form: Json<RoleForm>,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Json<UserSummary>, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let role = match (form.role, form.level) {
+        (Some(role), _) => role,
+        (None, Some(level)) => Role::from_level(level),
+        (None, None) => return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_UNKNOWN_ROLE))),
+    };
+
+    let account = find_account(db, id).await?;
+    not_self(&actor, &account)?;
+
+    if role != Role::Admin {
+        keeps_an_admin(db, &account).await?;
+    }
+
+    let action = format!("user.role.{}", role.as_str());
+    let outcome = db.set_role(&account._id, role).await;
+    audit_outcome(&audit, db, &actor, &action, &account.email, &outcome).await;
+    outcome_or_internal(outcome)?;
+
+    // Two admins demoting each other at once both pass the check above
+    if role != Role::Admin && !admin_remains(db).await {
+        let _ = db.set_role(&account._id, account.role()).await;
+        return Err(APIError::Conflict(ErrorJson::new(errors::MSG_LAST_ADMIN)));
+    }
+
+    let account = find_account(db, id).await?;
+
+    Ok(Json(UserSummary::from(account)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/disable",
+    operation_id = "account_disable",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "User disabled"),
+        (status = 404, description = "User does not exist"),
+        (status = 409, description = "Cannot change your own account, or it is the last admin"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/<id>/disable")]
+pub async fn disable(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let account = find_account(db, id).await?;
+    not_self(&actor, &account)?;
+    keeps_an_admin(db, &account).await?;
+
+    let outcome = db.set_disabled(&account._id, true).await;
+    audit_outcome(&audit, db, &actor, "user.disable", &account.email, &outcome).await;
+    outcome_or_internal(outcome)?;
+
+    if !admin_remains(db).await {
+        let _ = db.set_disabled(&account._id, false).await;
+        return Err(APIError::Conflict(ErrorJson::new(errors::MSG_LAST_ADMIN)));
+    }
+
+    // A disabled account should not keep any of its existing logins
+    let _ = db.revoke_all_sessions(&account.email).await;
+
+    Ok(json!({ "ok": true }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/enable",
+    operation_id = "account_enable",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "User enabled"),
+        (status = 404, description = "User does not exist"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/<id>/enable")]
+pub async fn enable(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let account = find_account(db, id).await?;
+
+    let outcome = db.set_disabled(&account._id, false).await;
+    audit_outcome(&audit, db, &actor, "user.enable", &account.email, &outcome).await;
+    outcome_or_internal(outcome)?;
+
+    Ok(json!({ "ok": true }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/users/{id}",
+    operation_id = "account_delete_user",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "User deleted"),
+        (status = 404, description = "User does not exist"),
+        (status = 409, description = "Cannot change your own account, or it is the last admin"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[delete("/<id>")]
+pub async fn delete_user(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let account = find_account(db, id).await?;
+    not_self(&actor, &account)?;
+    keeps_an_admin(db, &account).await?;
+
+    let outcome = db.delete_user(&account._id).await;
+    audit_outcome(&audit, db, &actor, "user.delete", &account.email, &outcome).await;
+    outcome_or_internal(outcome)?;
+
+    let _ = db.revoke_all_sessions(&account.email).await;
+
+    Ok(json!({ "ok": true }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/reset",
+    operation_id = "account_force_reset",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "User must change their password"),
+        (status = 404, description = "User does not exist"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/<id>/reset")]
+pub async fn force_reset(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let account = find_account(db, id).await?;
+
+    let outcome = db.require_password_reset(&account._id).await;
+    audit_outcome(&audit, db, &actor, "user.force_reset", &account.email, &outcome).await;
+    outcome_or_internal(outcome)?;
+
+    let _ = db.revoke_all_sessions(&account.email).await;
+
+    Ok(json!({ "ok": true }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/unlock",
+    operation_id = "account_unlock",
+    tag = "users",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Login lockout cleared"),
+        (status = 404, description = "User does not exist"),
+        (status = 409, description = "Account is not locked"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/<id>/unlock")]
+pub async fn unlock(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    throttle: &State<LoginThrottle>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let account = find_account(db, id).await?;
+
+    if !throttle.unlock(&account.email).await {
+        return Err(APIError::Conflict(ErrorJson::new(errors::MSG_NOT_LOCKED)));
+    }
+
+    audit.record(db, &actor, "user.unlock", Some(&account.email), AuditOutcome::Success).await;
+
+    Ok(json!({ "ok": true }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/policy",
+    operation_id = "account_policy",
+    tag = "users",
+    responses(
+        (status = 200, description = "Security settings"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[get("/policy")]
+pub async fn policy(
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    auth?;
+
+    match db.security_settings().await {
+        Ok(settings) => Ok(json!({ "data": settings })),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/policy",
+    operation_id = "account_set_policy",
+    tag = "users",
+    request_body = PolicyForm,
+    responses(
+        (status = 200, description = "Security settings changed"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/policy", data = "<form>")]
+pub async fn set_policy(
+    form: Json<PolicyForm>,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let action = match form.require_2fa_arm {
+        true => "policy.require_2fa.on",
+        false => "policy.require_2fa.off",
+    };
+
+    let outcome = db.set_require_2fa(form.require_2fa_arm).await.map(|_| true);
+    audit_outcome(&audit, db, &actor, action, "security", &outcome).await;
+    outcome_or_internal(outcome)?;
+
+    Ok(json!({ "ok": true }))
+}
+
+fn parse_role(role: &str) -> Option<Role>
+{
+    match role {
+        "admin" => Some(Role::Admin),
+        "operator" => Some(Role::Operator),
+        "viewer" => Some(Role::Viewer),
+        _ => None,
+    }
+}
+
+async fn find_account(db: &AnzenDB, id: &str) -> Result<Account, TextError>
+{
+    match db.get_account_by_id(id).await {
+        Ok(Some(account)) => Ok(account),
+        _ => Err(APIError::NotFound(ErrorJson::new(errors::MSG_USER_NOT_FOUND))),
+    }
+}
+
+/// Admins cannot lock themselves out through the management endpoints
+fn not_self(actor: &String, account: &Account) -> Result<(), TextError>
+{
+    match actor == &account.email {
+        true => Err(APIError::Conflict(ErrorJson::new(errors::MSG_SELF_CHANGE))),
+        false => Ok(()),
+    }
+}
+
+/// Refuses to take away the last active admin, nobody could manage users
+pub(super) async fn keeps_an_admin(db: &AnzenDB, account: &Account) -> Result<(), TextError>
+{
+    if account.role() != Role::Admin || account.disabled {
+        return Ok(());
+    }
+
+    match db.count_active_admins().await {
+        Ok(count) if count > 1 => Ok(()),
+        Ok(_) => Err(APIError::Conflict(ErrorJson::new(errors::MSG_LAST_ADMIN))),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+pub(super) async fn admin_remains(db: &AnzenDB) -> bool
+{
+    db.count_active_admins().await.map(|count| count > 0).unwrap_or(true)
+}
+
+async fn audit_outcome(
+    audit: &AuditContext,
+    db: &AnzenDB,
+    actor: &str,
+    action: &str,
+    target: &str,
+    outcome: &ResultT<bool>,
+)
+{
+    let outcome = match outcome {
+        Ok(true) => AuditOutcome::Success,
+        _ => AuditOutcome::Failure,
+    };
+
+    audit.record(db, actor, action, Some(target), outcome).await;
+}
+
+fn outcome_or_internal(outcome: ResultT<bool>) -> Result<(), TextError>
+{
+    match outcome {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(APIError::NotFound(ErrorJson::new(errors::MSG_USER_NOT_FOUND))),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
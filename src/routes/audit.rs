@@ -0,0 +1,211 @@
+use std::convert::Infallible;
+
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson};
+use super::permissions::{scope, Authorized};
+use super::returns::{AuditLog, AuditRecord};
+use crate::model::types::{AuditEntry, AuditOutcome};
+use crate::model::AnzenDB;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use rocket::http::{ContentType, Header};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket::State;
+
+const DEFAULT_LIMIT: i64 = 500;
+const MAX_LIMIT: i64 = 10000;
+
+/// Request details recorded alongside every audit entry
+#[derive(Clone)]
+pub struct AuditContext
+{
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditContext
+{
+    pub async fn record(
+        &self,
+        db: &AnzenDB,
+        actor: &str,
+        action: &str,
+        target: Option<&str>,
+        outcome: AuditOutcome,
+    )
+    {
+        let entry = AuditEntry {
+            _id: ObjectId::new(),
+            timestamp: DateTime::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.map(|target| target.to_string()),
+            outcome,
+            ip: self.ip.clone(),
+            user_agent: self.user_agent.clone(),
+        };
+
+        // Auditing must never be the reason an action fails
+        let _ = db.audit(entry).await;
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuditContext
+{
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<AuditContext, Self::Error>
+    {
+        Outcome::Success(AuditContext {
+            ip: request.client_ip().map(|ip| ip.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(|ua| ua.to_string()),
+        })
+    }
+}
+
+#[derive(Responder)]
+pub enum AuditExport
+{
+    Json(Json<AuditLog>),
+    Csv(String, ContentType, Header<'static>),
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit",
+    operation_id = "audit_list",
+    tag = "audit",
+    params(
+        ("start" = Option<String>, Query, description = "RFC 3339"),
+        ("end" = Option<String>, Query, description = "RFC 3339"),
+        ("actor" = Option<String>, Query),
+        ("action" = Option<String>, Query, description = "Action prefix such as `user`"),
+        ("limit" = Option<i64>, Query),
+        ("format" = Option<String>, Query, description = "json or csv"),
+    ),
+    responses(
+        (status = 200, description = "Audit entries, newest first", body = AuditLog),
+        (status = 400, description = "Invalid time or format"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/?<start>&<end>&<actor>&<action>&<limit>&<format>")]
+pub async fn list(
+    start: Option<String>,
+    end: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    limit: Option<i64>,
+    format: Option<String>,
+    auth: Result<Authorized<scope::ViewAudit>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<AuditExport, TextError>
+{
+    auth?;
+
+    let start = parse_time(start)?;
+    let end = parse_time(end)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let csv = match format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_UNKNOWN_FORMAT))),
+    };
+
+    let entries = match db.audit_log(start, end, actor, action, limit).await {
+        Ok(entries) => entries,
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    };
+
+    let entries: Vec<AuditRecord> = entries.into_iter().map(AuditRecord::from).collect();
+
+    if csv {
+        return Ok(AuditExport::Csv(
+            to_csv(&entries),
+            ContentType::CSV,
+            Header::new("Content-Disposition", "attachment; filename=\"audit.csv\""),
+        ));
+    }
+
+    Ok(AuditExport::Json(Json(AuditLog {
+        count: entries.len(),
+        entries,
+    })))
+}
+
+fn parse_time(time: Option<String>) -> Result<Option<DateTime>, TextError>
+{
+    match time {
+        Some(time) => match DateTime::parse_rfc3339_str(&time) {
+            Ok(time) => Ok(Some(time)),
+            Err(_) => Err(APIError::BadRequest(ErrorJson::new(errors::MSG_INVALID_TIME))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn to_csv(entries: &[AuditRecord]) -> String
+{
+    let mut csv = String::from("id,timestamp,actor,action,target,outcome,ip,user_agent\n");
+
+    for entry in entries {
+        let outcome = match entry.outcome {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+            AuditOutcome::Denied => "denied",
+        };
+
+        let fields = [
+            entry.id.as_str(),
+            entry.timestamp.as_str(),
+            entry.actor.as_str(),
+            entry.action.as_str(),
+            entry.target.as_deref().unwrap_or(""),
+            outcome,
+            entry.ip.as_deref().unwrap_or(""),
+            entry.user_agent.as_deref().unwrap_or(""),
+        ];
+
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Quotes fields so user agents and targets cannot break the columns, and
+/// defuses anything a spreadsheet would run as a formula
+fn csv_field(field: &str) -> String
+{
+    if field.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) {
+        return format!("\"'{}\"", field.replace('"', "\"\""));
+    }
+
+    match field.contains(&[',', '"', '\n', '\r'][..]) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::csv_field;
+
+    #[test]
+    fn csv_fields_are_quoted_and_defused()
+    {
+        assert_eq!(csv_field("login"), "login");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+
+        for formula in ["=1+1", "+1", "-1", "@SUM(A1)", "\tx", "\rx"] {
+            assert_eq!(csv_field(formula), format!("\"'{}\"", formula));
+        }
+
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+    }
+}
@@ -1,20 +1,26 @@
+use super::audit::AuditContext;
 use super::errors::{self, ErrorJson};
+use super::helpers::{self, PasswordPolicy};
+use super::invites;
+use super::mfa;
+use super::signing::TokenType;
+use super::state::CoreAPI;
 use super::returns::*;
-use crate::{model::AnzenDB, routes::state};
-use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header, Validation};
+use crate::{model::{types::{AuditOutcome, Role}, AnzenDB}, routes::state};
 use rocket::http::Status;
 use rocket::outcome::Outcome::Success;
 use rocket::request::{FromRequest, Outcome, Request};
-use rocket::serde::{json::Json, Serialize};
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::serde::Serialize;
 use rocket::State;
 use serde::Deserialize;
+use std::net::IpAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
 
 pub type TextError = errors::APIError<&'static str>;
 
-const MONTH: u64 = 60 * 60 * 24 * 30;
-
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct UserCred
 {
@@ -22,13 +28,37 @@ pub struct UserCred
     password: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct UserRegister
 {
     email: String,
     username: String,
     password: String,
+    /// Invite code, only optional for the emails allowed in the config
+    invite: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct RefreshForm
+{
+    refresh_token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ForgotForm
+{
+    email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ResetForm
+{
+    token: String,
+    password: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -36,73 +66,278 @@ pub struct Claims
 {
     pub exp: usize,
     pub sub: String,
+    /// Refresh session the access token was issued under
+    pub sid: String,
 }
 
+/// Signs a short lived access token bound to the given session and pairs it
+/// with the refresh token for that session
+fn issue_tokens(
+    state: &state::Validation,
+    email: String,
+    sid: String,
+    secret: String,
+) -> Result<LoginResponse, TextError>
+{
+    let exp = SystemTime::now()
+        .checked_add(Duration::from_secs(state.access_ttl))
+        .unwrap();
+    let exp = exp.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    let refresh_token = format!("{}.{}", sid, secret);
+
+    let claims = Claims {
+        exp: exp.try_into().unwrap(),
+        sub: email.clone(),
+        sid,
+    };
+
+    let token = match state.keys.encode(&claims, TokenType::Access) {
+        Ok(v) => v,
+        Err(_) => {
+            return Err(errors::APIError::Unauthorized(ErrorJson::new(
+                errors::MSG_GEN_TOKEN,
+            )))
+        }
+    };
+
+    Ok(LoginResponse {
+        username: email,
+        token,
+        refresh_token,
+        expires_in: state.access_ttl,
+    })
+}
+
+/// Opens a new refresh session for a fully authenticated user
+pub async fn start_session(
+    state: &state::Validation,
+    db: &AnzenDB,
+    email: String,
+) -> Result<LoginResponse, TextError>
+{
+    let (sid, secret) = match db.create_session(&email, state.refresh_ttl).await {
+        Ok(v) => v,
+        Err(_) => {
+            return Err(errors::APIError::Internal(ErrorJson::new(
+                errors::MSG_INTERNAL_DB_ERR,
+            )))
+        }
+    };
+
+    issue_tokens(state, email, sid, secret)
+}
+
+/// Counts a failed login, recording a security event when it locks the
+/// email or address out
+pub async fn login_failed(
+    throttle: &state::LoginThrottle,
+    audit: &AuditContext,
+    db: &AnzenDB,
+    email: &String,
+    ip: Option<IpAddr>,
+)
+{
+    audit.record(db, email, "login", Some(email), AuditOutcome::Failure).await;
+
+    if let Some(lockout) = throttle.failure(email, ip).await {
+        let action = format!("login.lockout.{}s", lockout);
+        audit.record(db, email, &action, Some(email), AuditOutcome::Denied).await;
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/login",
+    operation_id = "auth_login",
+    tag = "auth",
+    request_body = UserCred,
+    responses(
+        (status = 200, description = "Tokens, or an `MfaChallenge` when the account has two-factor enabled", body = LoginResponse),
+        (status = 401, description = "Wrong email or password"),
+        (status = 403, description = "Account is disabled"),
+        (status = 429, description = "Too many failed logins"),
+    )
+)]
 #[post("/login", data = "<form>")]
 pub async fn login(
     form: Json<UserCred>,
     state: &State<state::Validation>,
+    throttle: &State<state::LoginThrottle>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
     db: &State<AnzenDB>,
-) -> Result<Json<LoginResponse>, TextError>
+) -> Result<Json<LoginResult>, TextError>
 {
     let valid = state.inner();
+    let throttle = throttle.inner();
     let db = db.inner();
 
+    if let Some(retry_after) = throttle.locked(&form.email, ip).await {
+        return Err(errors::APIError::locked_out(retry_after));
+    }
+
+    // Every way a login can fail counts towards the lockout, or the
+    // address limit could be dodged by failing some other way
     if !valid.email_allowed(&form.email).await {
+        login_failed(throttle, &audit, db, &form.email, ip).await;
         return Err(errors::APIError::Unauthorized(ErrorJson::new(
             errors::MSG_NO_LOGON_ALLOWED,
         )));
     };
 
-    let valid_user = match db.valid_user(&form.email, &form.password).await {
-        Ok(value) => value,
-        Err(_) => {
+    match db.valid_user(&form.email, &form.password).await {
+        Ok(true) => (),
+        _ => {
+            login_failed(throttle, &audit, db, &form.email, ip).await;
             return Err(errors::APIError::Unauthorized(ErrorJson::new(
-                errors::MSG_INVALID_TOKEN,
+                errors::MSG_INVALID_PWD,
+            )));
+        }
+    }
+
+    let account = match db.get_account(&form.email).await {
+        Ok(account) if !account.disabled => account,
+        _ => {
+            audit.record(db, &form.email, "login", Some(&form.email), AuditOutcome::Denied).await;
+            let _ = throttle.failure(&form.email, ip).await;
+            return Err(errors::APIError::Forbidden(ErrorJson::new(
+                errors::MSG_ACCOUNT_DISABLED,
             )))
         }
     };
-    if !valid_user {
-        return Err(errors::APIError::Unauthorized(ErrorJson::new(
-            errors::MSG_INVALID_PWD,
-        )));
+
+    throttle.success(&form.email).await;
+
+    // Accounts with TOTP only get a challenge to finish logging in with
+    if account.totp_enabled() {
+        let challenge = mfa::challenge(valid, &account)?;
+        return Ok(Json(LoginResult::Challenge(challenge)));
     }
 
-    let exp = SystemTime::now()
-        .checked_add(Duration::from_secs(MONTH))
-        .unwrap();
-    let exp = exp.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let response = start_session(valid, db, form.email.clone()).await?;
 
-    let claims = Claims {
-        exp: exp.try_into().unwrap(),
-        sub: form.email.clone(),
-    };
+    audit.record(db, &form.email, "login", Some(&form.email), AuditOutcome::Success).await;
 
-    let token = match encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(state.key.as_ref().as_bytes()),
-    ) {
-        Ok(v) => v,
-        Err(_) => {
-            return Err(errors::APIError::Unauthorized(ErrorJson::new(
-                errors::MSG_GEN_TOKEN,
-            )))
-        }
+    Ok(Json(LoginResult::Tokens(response)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/refresh",
+    operation_id = "auth_refresh",
+    tag = "auth",
+    request_body = RefreshForm,
+    responses(
+        (status = 200, description = "New access and refresh tokens", body = LoginResponse),
+        (status = 401, description = "Invalid or expired refresh token"),
+    )
+)]
+#[post("/refresh", data = "<form>")]
+pub async fn refresh(
+    form: Json<RefreshForm>,
+    state: &State<state::Validation>,
+    db: &State<AnzenDB>,
+) -> Result<Json<LoginResponse>, TextError>
+{
+    let invalid = errors::APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_REFRESH));
+    let valid = state.inner();
+    let db = db.inner();
+
+    let (sid, secret) = match form.refresh_token.split_once('.') {
+        Some(parts) => parts,
+        None => return Err(invalid),
     };
 
-    let response = LoginResponse {
-        username: form.email.clone(),
-        token,
+    let (email, new_secret) = match db.rotate_session(sid, secret, valid.refresh_ttl).await {
+        Ok(Some(v)) => v,
+        _ => return Err(invalid),
     };
 
+    if !valid.email_allowed(&email).await {
+        let _ = db.revoke_session(sid).await;
+        return Err(errors::APIError::Unauthorized(ErrorJson::new(
+            errors::MSG_NO_LOGON_ALLOWED,
+        )));
+    }
+
+    let response = issue_tokens(valid, email, sid.to_string(), new_secret)?;
+
     Ok(Json(response))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/logout",
+    operation_id = "auth_logout",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Session revoked"),
+        (status = 401, description = "Not logged in"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/logout")]
+pub async fn logout(
+    claims: Result<Claims, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let claims = claims?;
+
+    match db.revoke_session(&claims.sid).await {
+        Ok(_) => Ok(json!({ "ok": true })),
+        Err(_) => Err(errors::APIError::Internal(ErrorJson::new(
+            errors::MSG_INTERNAL_DB_ERR,
+        ))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/logout/all",
+    operation_id = "auth_logout_all",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Every session of the user revoked"),
+        (status = 401, description = "Not logged in"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/logout/all")]
+pub async fn logout_all(
+    claims: Result<Claims, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let claims = claims?;
+
+    match db.revoke_all_sessions(&claims.sub).await {
+        Ok(count) => Ok(json!({ "ok": true, "revoked": count })),
+        Err(_) => Err(errors::APIError::Internal(ErrorJson::new(
+            errors::MSG_INTERNAL_DB_ERR,
+        ))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/register",
+    operation_id = "auth_register",
+    tag = "auth",
+    request_body = UserRegister,
+    responses(
+        (status = 200, description = "Account created", body = RegisterResponse),
+        (status = 401, description = "Invitation is invalid"),
+        (status = 409, description = "User already exists"),
+        (status = 422, description = "Email or password failed validation"),
+    )
+)]
 #[post("/register", data = "<form>")]
 pub async fn register(
     form: Json<UserRegister>,
     state: &State<state::Validation>,
+    policy: &State<PasswordPolicy>,
     db: &State<AnzenDB>,
 ) -> Result<Json<RegisterResponse>, TextError>
 {
@@ -110,33 +345,177 @@ pub async fn register(
     let valid = state.inner();
     let db = db.inner();
 
-    let mut level: u8 = 2;
+    let mut failures = helpers::validate_email(&form.email);
+    failures.extend(policy.validate_password(&form.password));
+    helpers::check(failures)?;
 
-    if !valid.email_allowed(&form.email).await {
-        return Err(errors::APIError::Unauthorized(ErrorJson::new(
-            errors::MSG_NO_LOGON_ALLOWED,
-        )));
-    };
+    // The invite is checked first so a bad code says nothing about the email
+    let (role, invite) = match &form.invite {
+        Some(code) => {
+            let invalid = errors::APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_INVITE));
 
-    if form.username == "admin" {
-        level = 0;
-    }
+            let claims = match invites::verify_code(valid, code, &form.email) {
+                Some(claims) => claims,
+                None => return Err(invalid),
+            };
 
-    let ok = match db
-        .new_user(&form.email, &form.username, level, form.password.clone().as_bytes())
-        .await
-    {
-        Ok(v) => v,
-        Err(_) => return Err(error_user_exists),
+            match db.accept_invitation(&claims.iid, &form.email).await {
+                Ok(Some(role)) => (role, Some(claims.iid)),
+                _ => return Err(invalid),
+            }
+        }
+        None if valid.bootstrap_allowed(&form.email) => match valid.is_admin(&form.email) {
+            true => (Role::Admin, None),
+            false => (Role::Operator, None),
+        },
+        None => {
+            return Err(errors::APIError::Unauthorized(ErrorJson::new(
+                errors::MSG_NO_LOGON_ALLOWED,
+            )))
+        }
     };
 
-    if ok {
+    let created = match db.get_account(&form.email).await {
+        Ok(_) => Ok(false),
+        Err(_) => db.new_user(&form.email, &form.username, role, form.password.clone().as_bytes()).await,
+    };
+
+    if let Ok(true) = created {
         return Ok(Json(RegisterResponse { ok: true }));
     }
 
+    // The invitation was only used up if the account now exists
+    if let Some(iid) = invite {
+        let _ = db.release_invitation(&iid).await;
+    }
+
     Err(error_user_exists)
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/forgot",
+    operation_id = "auth_forgot",
+    tag = "auth",
+    request_body = ForgotForm,
+    responses(
+        (status = 200, description = "Reset sent if the account exists"),
+        (status = 404, description = "Password reset is not configured"),
+        (status = 429, description = "Too many reset requests for the email or address"),
+    )
+)]
+#[post("/forgot", data = "<form>")]
+pub async fn forgot(
+    form: Json<ForgotForm>,
+    state: &State<state::Validation>,
+    throttle: &State<state::ResetThrottle>,
+    mailer: &State<state::ResetMailer>,
+    core_api: &State<CoreAPI>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let valid = state.inner();
+    let throttle = &throttle.inner().0;
+    let db = db.inner();
+
+    if !mailer.enabled() {
+        return Err(errors::APIError::NotFound(ErrorJson::new(errors::MSG_RESET_DISABLED)));
+    }
+
+    if let Some(retry_after) = throttle.locked(&form.email, ip).await {
+        return Err(errors::APIError::too_many_resets(retry_after));
+    }
+
+    // Every request counts, whether or not the email has an account
+    let _ = throttle.failure(&form.email, ip).await;
+
+    let reset = match db.create_password_reset(&form.email, valid.reset_ttl).await {
+        Ok(reset) => reset,
+        Err(_) => {
+            return Err(errors::APIError::Internal(ErrorJson::new(
+                errors::MSG_INTERNAL_DB_ERR,
+            )))
+        }
+    };
+
+    // The response is the same whether or not the email has an account
+    if let Some((reset_id, token)) = reset {
+        audit.record(db, &form.email, "user.password.forgot", Some(&form.email), AuditOutcome::Success).await;
+
+        if let Ok(sealed) = mailer.seal(&token) {
+            let _ = core_api.send_password_reset(reset_id.to_hex(), sealed, valid.reset_ttl).await;
+        }
+    }
+
+    Ok(json!({ "ok": true }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/reset",
+    operation_id = "auth_reset",
+    tag = "auth",
+    request_body = ResetForm,
+    responses(
+        (status = 200, description = "Password changed"),
+        (status = 401, description = "Invalid or expired reset token"),
+        (status = 422, description = "Password failed validation"),
+    )
+)]
+#[post("/reset", data = "<form>")]
+pub async fn reset(
+    form: Json<ResetForm>,
+    policy: &State<PasswordPolicy>,
+    core_api: &State<CoreAPI>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let db = db.inner();
+
+    helpers::check(policy.validate_password(&form.password))?;
+
+    let email = match db.consume_password_reset(&form.token).await {
+        Ok(Some(email)) => email,
+        Ok(None) => {
+            return Err(errors::APIError::Unauthorized(ErrorJson::new(
+                errors::MSG_INVALID_RESET,
+            )))
+        }
+        Err(_) => {
+            return Err(errors::APIError::Internal(ErrorJson::new(
+                errors::MSG_INTERNAL_DB_ERR,
+            )))
+        }
+    };
+
+    // Tokens handed out before provider accounts were left out may still be live
+    if let Ok(Some(account)) = db.find_account(&email).await {
+        if account.external.is_some() {
+            audit.record(db, &email, "user.password.reset", Some(&email), AuditOutcome::Denied).await;
+            return Err(errors::APIError::Unauthorized(ErrorJson::new(
+                errors::MSG_INVALID_RESET,
+            )));
+        }
+    }
+
+    if db.change_password(&email, form.password.as_bytes()).await.is_err() {
+        audit.record(db, &email, "user.password.reset", Some(&email), AuditOutcome::Failure).await;
+        return Err(errors::APIError::Internal(ErrorJson::new(
+            errors::MSG_INTERNAL_DB_ERR,
+        )));
+    }
+
+    audit.record(db, &email, "user.password.reset", Some(&email), AuditOutcome::Success).await;
+
+    let _ = db.revoke_all_sessions(&email).await;
+    let _ = core_api.security_notification(email, "password-reset").await;
+
+    Ok(json!({ "ok": true }))
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Claims
 {
@@ -165,19 +544,24 @@ impl<'r> FromRequest<'r> for Claims
             None => return failure,
         };
 
-        let claims = match jsonwebtoken::decode::<Claims>(
-            &token,
-            &DecodingKey::from_secret(state.key.as_ref().as_bytes()),
-            &Validation::default(),
-        ) {
-            Ok(claims) => claims,
-            Err(_) => return failure,
+        let claims = match state.keys.decode::<Claims>(&token, TokenType::Access) {
+            Some(claims) => claims,
+            None => return failure,
         };
 
-        if state.email_allowed(&claims.claims.sub).await {
-            return Outcome::Success(claims.claims);
+        if !state.email_allowed(&claims.sub).await {
+            return failure;
         }
 
-        failure
+        let db = match request.guard::<&State<AnzenDB>>().await {
+            Success(db) => db,
+            _ => return failure,
+        };
+
+        // Tokens die with the session they were issued under
+        match db.session_active(&claims.sid).await {
+            Ok(true) => Outcome::Success(claims),
+            _ => failure,
+        }
     }
 }
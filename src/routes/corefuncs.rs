@@ -1,14 +1,19 @@
 use rocket::serde::json::Value;
 use rocket::State;
 use serde_json::json;
-use super::auth::{Claims, TextError};
+use super::audit::AuditContext;
+use super::auth::TextError;
+use super::permissions::{scope, Authorized};
 use super::state::CoreAPI;
 use super::errors::{APIError, ErrorJson};
+use crate::model::types::AuditOutcome;
+use crate::model::AnzenDB;
 
 use serde::Deserialize;
 use rocket::serde::json::Json;
+use utoipa::ToSchema;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct EmailForm
 {
@@ -16,14 +21,28 @@ pub struct EmailForm
     priority: Option<i64>
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/core/addmail",
+    operation_id = "corefuncs_addmail",
+    tag = "core",
+    request_body = EmailForm,
+    responses(
+        (status = 200, description = "Email handed to core"),
+        (status = 500, description = "Core is unreachable"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
 #[post("/addmail", data = "<form>")]
 pub async fn addmail(
-    claims: Result<Claims, TextError>,
+    auth: Result<Authorized<scope::AddEmail>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
     core_api: &State<CoreAPI>,
     form: Json<EmailForm>
 ) -> Result<Value, TextError>
 {
-    claims?;
+    let actor = auth?.sub;
 
     let core_api = core_api.inner();
 
@@ -31,11 +50,13 @@ pub async fn addmail(
 
     match core_api.add_email(form.email.clone(), priority).await {
         Ok(_) => {
+            audit.record(db, &actor, "core.email.add", Some(&form.email), AuditOutcome::Success).await;
             Ok(json!({
                 "ok": true
             }))
         },
         Err(_) => {
+            audit.record(db, &actor, "core.email.add", Some(&form.email), AuditOutcome::Failure).await;
             Err(APIError::Internal(ErrorJson::new(
                 "Could not send request to update email"
             )))
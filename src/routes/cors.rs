@@ -4,7 +4,33 @@ use rocket::{Request, Response};
 
 // Adapted from: https://stackoverflow.com/questions/62412361/how-to-set-up-cors-or-options-for-rocket-rs
 
-pub struct CORS;
+pub struct CORS
+{
+    origins: Vec<String>,
+}
+
+impl CORS
+{
+    pub fn init(origins: Vec<String>) -> CORS
+    {
+        CORS { origins }
+    }
+
+    /// The value to send back, browsers only accept a single origin
+    fn allowed_origin(&self, request: &Request<'_>) -> Option<String>
+    {
+        if self.origins.iter().any(|origin| origin == "*") {
+            return Some("*".into());
+        }
+
+        let origin = request.headers().get_one("Origin")?;
+
+        self.origins
+            .iter()
+            .find(|allowed| allowed.as_str() == origin)
+            .cloned()
+    }
+}
 
 #[rocket::async_trait]
 impl Fairing for CORS
@@ -17,12 +43,21 @@ impl Fairing for CORS
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>)
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>)
     {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        let origin = match self.allowed_origin(request) {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        if origin != "*" {
+            response.set_header(Header::new("Vary", "Origin"));
+        }
+
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
-            "POST, GET, OPTIONS",
+            "POST, GET, DELETE, OPTIONS",
         ));
         response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
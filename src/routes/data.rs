@@ -1,123 +1,407 @@
+use super::audit::AuditContext;
 use super::auth::{Claims, TextError};
 use super::errors::{self, APIError, ErrorJson};
+use super::helpers;
+use super::permissions::{scope, Authorized};
 use super::returns::CoreStatus;
-use super::state::CoreAPI;
-use crate::model::AnzenDB;
+use super::state::{ArmControl, CoreAPI};
+use crate::model::types::AuditOutcome;
+use crate::model::{AnzenDB, Cursor, SearchPage, SortOrder, TimeRange};
+use mongodb::bson::DateTime;
 use rocket::serde::json::serde_json::json;
-use rocket::serde::json::Value;
+use rocket::serde::json::{Json, Value};
 use rocket::State;
+use serde::Deserialize;
+use std::net::IpAddr;
+use utoipa::{IntoParams, ToSchema};
 
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery
+{
+    start: Option<String>,
+    end: Option<String>,
+    /// Relative range ending now, such as `24h` or `7d`
+    last: Option<String>,
+    /// A single calendar day in `tz`
+    day: Option<String>,
+    tz: Option<String>,
+    bucket: Option<String>,
+    armed: Option<bool>,
+    device: Option<String>,
+    plugin: Option<String>,
+    limit: Option<i64>,
+    order: Option<String>,
+    events_cursor: Option<String>,
+    commands_cursor: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ArmForm
+{
+    /// Seconds to wait before arming, giving people time to leave
+    delay: Option<u64>,
+    reason: Option<String>,
+    pin: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct DisarmForm
+{
+    reason: Option<String>,
+    pin: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/data/test",
+    operation_id = "data_test",
+    tag = "data",
+    responses(
+        (status = 200, description = "Email of the logged in user"),
+        (status = 401, description = "Not logged in"),
+    ),
+    security(("bearer_token" = []))
+)]
 #[get("/test")]
 pub async fn test(claims: Result<Claims, TextError>) -> Result<String, TextError>
 {
     Ok(claims?.sub)
 }
 
-#[get("/stats")]
+#[utoipa::path(
+    get,
+    path = "/api/v1/data/stats",
+    operation_id = "data_stats",
+    tag = "data",
+    params(
+        ("bucket" = Option<String>, Query, description = "minute, hour, day, week or month"),
+        ("tz" = Option<String>, Query, description = "IANA time zone for buckets"),
+    ),
+    responses(
+        (status = 200, description = "Chart data, latest records and core status, which is null while core is unreachable"),
+        (status = 400, description = "Unknown bucket or time zone"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/stats?<bucket>&<tz>")]
 pub async fn stats(
-    claims: Result<Claims, TextError>,
+    bucket: Option<String>,
+    tz: Option<String>,
+    auth: Result<Authorized<scope::ViewStats>, TextError>,
     db: &State<AnzenDB>,
     core_api: &State<CoreAPI>,
 ) -> Result<Value, TextError>
 {
-    claims?;
+    auth?;
 
     let db_fail = APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR));
-    let core_fail = APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_CORE_ERR));
 
     let db = db.inner();
     let core_api = core_api.inner();
 
-    let event_stats = match db.event_statistics().await {
-        Ok(v) => v,
-        Err(_) => return Err(db_fail),
-    };
+    let bucket = helpers::parse_bucket(bucket.as_deref())?;
+    let tz = helpers::parse_tz(tz.as_deref())?;
+    let range = TimeRange::default();
 
-    let hourly_totals = match db.count_status_time(None, None, None, None, None).await {
+    let event_stats = match db.event_statistics(&range, bucket, tz).await {
         Ok(v) => v,
         Err(_) => return Err(db_fail),
     };
 
-    let core_status = match core_api.get_stats().await {
+    let hourly_totals = match db.count_status_time(&range, None, None, None, bucket, tz).await {
         Ok(v) => v,
-        Err(_) => return Err(core_fail),
+        Err(_) => return Err(db_fail),
     };
 
     let last_n = match db.last_n(10).await {
         Ok(v) => v,
-        Err(_) => return Err(core_fail),
+        Err(_) => return Err(db_fail),
     };
 
-    // Must change to be able to serialize
-
-    let core_status = CoreStatus {
-        armed: core_status.armed,
-        store: core_status.values,
+    // While core is unreachable the stored data is still worth showing
+    let core_status = match core_api.get_stats().await {
+        Ok(v) => Some(CoreStatus {
+            armed: v.armed,
+            store: v.values,
+        }),
+        Err(_) => None,
     };
 
     Ok(json!({
         "data": {
             "hourlyTotals": hourly_totals,
             "eventStats": event_stats,
+            "bucket": bucket.as_str(),
             "coreStatus": core_status,
             "lastCE": last_n
         }
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/data/toggle",
+    operation_id = "data_toggle",
+    tag = "data",
+    responses(
+        (status = 200, description = "Arm state after flipping it"),
+        (status = 403, description = "Missing permission, or an arm PIN is configured"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
 #[post("/toggle")]
 pub async fn toggle(
-    claims: Result<Claims, TextError>,
+    auth: Result<Authorized<scope::ArmDisarm>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+    control: &State<ArmControl>,
+    core_api: &State<CoreAPI>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+
+    let core_api = core_api.inner();
+
+    // Toggling carries no PIN, so it would get around one
+    if control.pin_required() {
+        audit.record(db, &actor, "alarm.toggle", None, AuditOutcome::Denied).await;
+        return Err(APIError::Forbidden(ErrorJson::new(errors::MSG_INVALID_PIN)));
+    }
+
+    match control.toggle(core_api, &actor).await.ok() {
+        Some(armed) => {
+            audit.record(db, &actor, "alarm.toggle", None, AuditOutcome::Success).await;
+            Ok(json!({
+                "ok": true,
+                "armed": armed
+            }))
+        },
+        None => {
+            audit.record(db, &actor, "alarm.toggle", None, AuditOutcome::Failure).await;
+            Err(APIError::Internal(ErrorJson::new(errors::MSG_ARM_FAILED)))
+        },
+    }
+}
+
+/// Checks the arm PIN, locking the actor and address out after too many
+/// wrong ones
+async fn check_pin(
+    control: &ArmControl,
+    audit: &AuditContext,
+    db: &AnzenDB,
+    actor: &String,
+    action: &str,
+    pin: Option<&str>,
+    ip: Option<IpAddr>,
+) -> Result<(), TextError>
+{
+    if let Some(retry_after) = control.pin_attempts.locked(actor, ip).await {
+        audit.record(db, actor, action, None, AuditOutcome::Denied).await;
+        return Err(APIError::too_many_pins(retry_after));
+    }
+
+    if control.pin_valid(pin) {
+        control.pin_attempts.success(actor).await;
+        return Ok(());
+    }
+
+    audit.record(db, actor, action, None, AuditOutcome::Denied).await;
+
+    if control.pin_attempts.failure(actor, ip).await.is_some() {
+        audit.record(db, actor, "alarm.pin.lockout", Some(actor), AuditOutcome::Denied).await;
+    }
+
+    Err(APIError::Forbidden(ErrorJson::new(errors::MSG_INVALID_PIN)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/data/arm",
+    operation_id = "data_arm",
+    tag = "data",
+    request_body = ArmForm,
+    responses(
+        (status = 200, description = "Arm state, or when a delayed arm will happen"),
+        (status = 400, description = "Delay is too long"),
+        (status = 403, description = "Missing permission or wrong PIN"),
+        (status = 429, description = "Too many wrong PINs"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[post("/arm", data = "<form>")]
+pub async fn arm(
+    form: Json<ArmForm>,
+    auth: Result<Authorized<scope::ArmDisarm>, TextError>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
+    db: &State<AnzenDB>,
+    control: &State<ArmControl>,
     core_api: &State<CoreAPI>,
 ) -> Result<Value, TextError>
 {
-    claims?;
+    let actor = auth?.sub;
+    let control = control.inner();
+    let core_api = core_api.inner();
+
+    check_pin(control, &audit, db, &actor, "alarm.arm", form.pin.as_deref(), ip).await?;
+
+    let delay = form.delay.unwrap_or(0);
+
+    if delay > control.max_delay {
+        return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_ARM_DELAY)));
+    }
+
+    let reason = form.reason.clone();
+
+    if delay > 0 {
+        let at = control
+            .schedule(core_api.clone(), db.inner().clone(), audit.clone(), delay, actor.clone(), reason)
+            .await;
+        audit.record(db, &actor, "alarm.arm.scheduled", None, AuditOutcome::Success).await;
+
+        return Ok(json!({
+            "ok": true,
+            "armed": false,
+            "arming_at": DateTime::from_system_time(at).try_to_rfc3339_string().ok()
+        }));
+    }
+
+    // Arming now replaces any grace period that was still running
+    control.cancel().await;
+
+    match control.set(core_api, true, &actor, reason.as_deref()).await.ok() {
+        Some(armed) => {
+            audit.record(db, &actor, "alarm.arm", None, AuditOutcome::Success).await;
+            Ok(json!({
+                "ok": true,
+                "armed": armed
+            }))
+        },
+        None => {
+            audit.record(db, &actor, "alarm.arm", None, AuditOutcome::Failure).await;
+            Err(APIError::Internal(ErrorJson::new(errors::MSG_ARM_FAILED)))
+        },
+    }
+}
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/data/disarm",
+    operation_id = "data_disarm",
+    tag = "data",
+    request_body = DisarmForm,
+    responses(
+        (status = 200, description = "Arm state after disarming"),
+        (status = 403, description = "Missing permission or wrong PIN"),
+        (status = 429, description = "Too many wrong PINs"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[post("/disarm", data = "<form>")]
+pub async fn disarm(
+    form: Json<DisarmForm>,
+    auth: Result<Authorized<scope::ArmDisarm>, TextError>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
+    db: &State<AnzenDB>,
+    control: &State<ArmControl>,
+    core_api: &State<CoreAPI>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let control = control.inner();
     let core_api = core_api.inner();
 
-    match core_api.toggle_armed().await {
-        Ok(_) => Ok(json!({
-            "ok": true
-        })),
-        Err(_) => Err(APIError::Internal(ErrorJson::new(
-            "Could not toggle arm status",
-        ))),
+    check_pin(control, &audit, db, &actor, "alarm.disarm", form.pin.as_deref(), ip).await?;
+
+    let cancelled = control.cancel().await;
+
+    match control.set(core_api, false, &actor, form.reason.as_deref()).await.ok() {
+        Some(armed) => {
+            audit.record(db, &actor, "alarm.disarm", None, AuditOutcome::Success).await;
+            Ok(json!({
+                "ok": true,
+                "armed": armed,
+                "cancelled_pending": cancelled
+            }))
+        },
+        None => {
+            audit.record(db, &actor, "alarm.disarm", None, AuditOutcome::Failure).await;
+            Err(APIError::Internal(ErrorJson::new(errors::MSG_ARM_FAILED)))
+        },
     }
 }
 
-#[get("/search?<start>&<end>&<armed>&<device>&<plugin>")]
+#[utoipa::path(
+    get,
+    path = "/api/v1/data/search",
+    operation_id = "data_search",
+    tag = "data",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Matching events and commands with chart data and cursors"),
+        (status = 400, description = "Invalid range, cursor or option"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/search?<query..>")]
 pub async fn search(
-    start: Option<String>,
-    end: Option<String>,
-    armed: Option<bool>,
-    device: Option<String>,
-    plugin: Option<String>,
-    claims: Result<Claims, TextError>,
+    query: SearchQuery,
+    auth: Result<Authorized<scope::Search>, TextError>,
     db: &State<AnzenDB>,
 ) -> Result<Value, TextError>
 {
-    let email = claims?.sub;
+    auth?;
+
+    let SearchQuery {
+        start,
+        end,
+        last,
+        day,
+        tz,
+        bucket,
+        armed,
+        device,
+        plugin,
+        limit,
+        order,
+        events_cursor,
+        commands_cursor,
+    } = query;
 
     let db_fail = APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR));
-    let auth_fail = APIError::Forbidden(ErrorJson::new("Not authorized"));
 
     let db = db.inner();
 
-    let user = match db.get_user(&email).await {
-        Ok(user) => user,
-        Err(_) => return Err(auth_fail)
+    let bucket = helpers::parse_bucket(bucket.as_deref())?;
+    let tz = helpers::parse_tz(tz.as_deref())?;
+    let range = helpers::time_range(start.as_deref(), end.as_deref(), last.as_deref(), day.as_deref(), tz)?;
+
+    let order = match order.as_deref() {
+        None | Some("desc") => SortOrder::Descending,
+        Some("asc") => SortOrder::Ascending,
+        Some(_) => return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_UNKNOWN_ORDER))),
     };
 
-    if user.level != 0 {
-        return Err(auth_fail);
-    }
+    let page = SearchPage {
+        events_cursor: parse_cursor(events_cursor)?,
+        commands_cursor: parse_cursor(commands_cursor)?,
+        order,
+        limit: limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+    };
 
-    let data = match db.search(start.clone(), end.clone(), armed, device.clone(), plugin.clone()).await {
+    let (data, pages) = match db.search(&range, armed, device.clone(), plugin.clone(), &page).await {
         Ok(v) => v,
         Err(_) => return Err(db_fail)
     };
 
-    let count = match db.count_status_time(start, end, armed, device, plugin).await {
+    let count = match db.count_status_time(&range, armed, device, plugin, bucket, tz).await {
         Ok(v) => v,
         Err(_) => return Err(db_fail)
     };
@@ -125,7 +409,20 @@ pub async fn search(
     Ok(json!({
         "data": {
             "hourlyTotals": count,
-            "lastCE": data
+            "bucket": bucket.as_str(),
+            "lastCE": data,
+            "pagination": pages
         }
     }))
 }
+
+fn parse_cursor(cursor: Option<String>) -> Result<Option<Cursor>, TextError>
+{
+    match cursor {
+        Some(cursor) => match Cursor::decode(&cursor) {
+            Ok(cursor) => Ok(Some(cursor)),
+            Err(_) => Err(APIError::BadRequest(ErrorJson::new(errors::MSG_INVALID_CURSOR))),
+        },
+        None => Ok(None),
+    }
+}
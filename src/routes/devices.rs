@@ -0,0 +1,160 @@
+use super::audit::AuditContext;
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson};
+use super::permissions::{scope, Authorized};
+use super::returns::{DeviceList, DeviceSummary};
+use crate::model::types::AuditOutcome;
+use crate::model::AnzenDB;
+use mongodb::bson::oid::ObjectId;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceForm
+{
+    /// Display name, an empty string goes back to the plugin's identifier
+    name: Option<String>,
+    /// Room or zone, an empty string removes the device from its zone
+    zone: Option<String>,
+    /// Ignored devices are left out of the device list by default
+    ignored: Option<bool>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/devices",
+    operation_id = "devices_list",
+    tag = "devices",
+    params(
+        ("ignored" = Option<bool>, Query, description = "Include ignored devices"),
+        ("page" = Option<u64>, Query),
+        ("per_page" = Option<i64>, Query),
+    ),
+    responses(
+        (status = 200, description = "Page of devices", body = DeviceList),
+        (status = 400, description = "Page out of range"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/?<ignored>&<page>&<per_page>")]
+pub async fn list(
+    ignored: Option<bool>,
+    page: Option<u64>,
+    per_page: Option<i64>,
+    auth: Result<Authorized<scope::ViewStats>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Json<DeviceList>, TextError>
+{
+    auth?;
+
+    let page = page.unwrap_or(0);
+    let per_page = per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    if page.checked_mul(per_page as u64).is_none() {
+        return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_INVALID_PAGE)));
+    }
+
+    match db.list_devices(None, ignored.unwrap_or(false), page, per_page).await {
+        Ok((devices, total)) => Ok(Json(DeviceList {
+            devices,
+            page,
+            per_page,
+            total,
+        })),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/devices/{id}",
+    operation_id = "devices_get_device",
+    tag = "devices",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "The device", body = DeviceSummary),
+        (status = 404, description = "Device does not exist"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/<id>")]
+pub async fn get_device(
+    id: &str,
+    auth: Result<Authorized<scope::ViewStats>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Json<DeviceSummary>, TextError>
+{
+    auth?;
+
+    let device = find_device(db.inner(), id).await?;
+
+    Ok(Json(device))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/devices/{id}",
+    operation_id = "devices_update",
+    tag = "devices",
+    params(
+        ("id" = String, Path),
+    ),
+    request_body = DeviceForm,
+    responses(
+        (status = 200, description = "The updated device", body = DeviceSummary),
+        (status = 404, description = "Device does not exist"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[post("/<id>", data = "<form>")]
+pub async fn update(
+    id: &str,
+    form: Json<DeviceForm>,
+    auth: Result<Authorized<scope::ManageDevices>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Json<DeviceSummary>, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let device_id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Err(APIError::NotFound(ErrorJson::new(errors::MSG_DEVICE_NOT_FOUND))),
+    };
+
+    let form = form.into_inner();
+
+    match db.update_device(&device_id, form.name, form.zone, form.ignored, &actor).await {
+        Ok(true) => audit.record(db, &actor, "device.update", Some(id), AuditOutcome::Success).await,
+        Ok(false) => return Err(APIError::NotFound(ErrorJson::new(errors::MSG_DEVICE_NOT_FOUND))),
+        Err(_) => {
+            audit.record(db, &actor, "device.update", Some(id), AuditOutcome::Failure).await;
+            return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR)));
+        }
+    }
+
+    let device = find_device(db, id).await?;
+
+    Ok(Json(device))
+}
+
+async fn find_device(db: &AnzenDB, id: &str) -> Result<DeviceSummary, TextError>
+{
+    let not_found = || APIError::NotFound(ErrorJson::new(errors::MSG_DEVICE_NOT_FOUND));
+
+    let id = ObjectId::parse_str(id).map_err(|_| not_found())?;
+
+    match db.list_devices(Some(id), true, 0, 1).await {
+        Ok((mut devices, _)) => devices.pop().ok_or_else(not_found),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
@@ -1,14 +1,60 @@
+use rocket::http::Header;
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
-use std::{error, fmt};
+use utoipa::ToSchema;
 
 pub const MSG_NO_LOGON_ALLOWED: &str = "User logon is not currently allowed";
 pub const MSG_INVALID_PWD: &str = "Could not validate password";
 pub const MSG_GEN_TOKEN: &str = "Could not generate token";
 pub const MSG_INVALID_TOKEN: &str = "Invalid bearer token";
+pub const MSG_INVALID_REFRESH: &str = "Invalid or expired refresh token";
+pub const MSG_MISSING_PERMISSION: &str = "Missing permission for this action";
+pub const MSG_USER_NOT_FOUND: &str = "User does not exist";
+pub const MSG_ACCOUNT_DISABLED: &str = "Account is disabled";
+pub const MSG_PASSWORD_RESET: &str = "Password must be changed before continuing";
+pub const MSG_UNKNOWN_ROLE: &str = "Unknown role";
+pub const MSG_SELF_CHANGE: &str = "Cannot change your own account this way";
+pub const MSG_LAST_ADMIN: &str = "At least one active admin must remain";
+pub const MSG_INVALID_PAGE: &str = "Page is out of range";
+pub const MSG_INVALID_2FA: &str = "Invalid two-factor code";
+pub const MSG_2FA_ENABLED: &str = "Two-factor authentication is already enabled";
+pub const MSG_2FA_NOT_SETUP: &str = "Two-factor authentication has not been set up";
+pub const MSG_2FA_REQUIRED: &str = "Two-factor authentication must be enabled for this action";
+pub const MSG_LOCKED_OUT: &str = "Too many failed logins, try again later";
+pub const MSG_TOO_MANY_RESETS: &str = "Too many password reset requests, try again later";
+pub const MSG_NOT_LOCKED: &str = "Account is not locked";
+pub const MSG_VALIDATION: &str = "Validation failed";
+pub const MSG_INVALID_RESET: &str = "Invalid or expired reset token";
+pub const MSG_RESET_DISABLED: &str = "Password reset is not configured";
+pub const MSG_INVALID_INVITE: &str = "Invalid or expired invitation";
+pub const MSG_INVITE_NOT_FOUND: &str = "Invitation does not exist";
+pub const MSG_INVITE_USED: &str = "Invitation has already been used or revoked";
+pub const MSG_INVALID_API_KEY: &str = "Invalid API key";
+pub const MSG_API_KEY_NOT_FOUND: &str = "API key does not exist";
+pub const MSG_API_KEY_REVOKED: &str = "API key has been revoked";
+pub const MSG_OIDC_DISABLED: &str = "OpenID Connect login is not configured";
+pub const MSG_OIDC_PROVIDER: &str = "Identity provider is unreachable";
+pub const MSG_OIDC_FAILED: &str = "Identity provider login failed";
+pub const MSG_OIDC_BUSY: &str = "Too many logins in progress, try again later";
+pub const MSG_OIDC_NOT_LINKED: &str = "An account with this email already exists, log in with its password";
+pub const MSG_INVALID_TIME: &str = "Times must be RFC 3339 timestamps";
+pub const MSG_UNKNOWN_FORMAT: &str = "Unknown export format";
+pub const MSG_INVALID_PIN: &str = "Invalid arm PIN";
+pub const MSG_TOO_MANY_PINS: &str = "Too many wrong PINs, try again later";
+pub const MSG_ARM_DELAY: &str = "Arm delay is longer than allowed";
+pub const MSG_ARM_FAILED: &str = "Could not change arm status";
+pub const MSG_INVALID_ID: &str = "Invalid ID";
+pub const MSG_INVALID_CURSOR: &str = "Invalid pagination cursor";
+pub const MSG_UNKNOWN_ORDER: &str = "Sort order must be asc or desc";
+pub const MSG_INVALID_DATE: &str = "Times must be RFC 3339 timestamps or YYYY-MM-DD dates";
+pub const MSG_INVALID_LAST: &str = "Relative ranges look like 30m, 24h, 7d or 2w";
+pub const MSG_UNKNOWN_TZ: &str = "Unknown time zone";
+pub const MSG_RANGE_CONFLICT: &str = "Use only one of last, day, or start and end";
+pub const MSG_RANGE_ORDER: &str = "Range start must be before its end";
+pub const MSG_UNKNOWN_BUCKET: &str = "Bucket must be minute, hour, day, week or month";
+pub const MSG_DEVICE_NOT_FOUND: &str = "Device does not exist";
 pub const MSG_USER_EXISTS: &str = "User already exists";
 pub const MSG_INTERNAL_DB_ERR: &str = "There was an internal DB error";
-pub const MSG_INTERNAL_CORE_ERR: &str = "Core endpoints are offline";
 
 #[derive(Debug, Responder, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -26,50 +72,77 @@ impl ErrorJson<&'static str>
     }
 }
 
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationErrors
+{
+    error: &'static str,
+    rules: Vec<String>,
+}
+
+impl ValidationErrors
+{
+    pub fn new(rules: Vec<String>) -> Json<Self>
+    {
+        Json(Self {
+            error: MSG_VALIDATION,
+            rules,
+        })
+    }
+}
+
 #[derive(Debug, Responder)]
 pub enum APIError<R>
 {
+    #[response(status = 400, content_type = "json")]
+    BadRequest(Json<ErrorJson<R>>),
     #[response(status = 401, content_type = "json")]
     Unauthorized(Json<ErrorJson<R>>),
     #[response(status = 403, content_type = "json")]
     Forbidden(Json<ErrorJson<R>>),
+    #[response(status = 404, content_type = "json")]
+    NotFound(Json<ErrorJson<R>>),
     #[response(status = 409, content_type = "json")]
     Conflict(Json<ErrorJson<R>>),
+    #[response(status = 422, content_type = "json")]
+    Unprocessable(Json<ValidationErrors>),
+    #[response(status = 429, content_type = "json")]
+    TooManyRequests(Json<ErrorJson<R>>, Header<'static>),
     #[response(status = 500, content_type = "json")]
     Internal(Json<ErrorJson<R>>),
 }
 
-#[derive(Debug)]
-pub struct RegexError {
-    details: &'static str
-}
-
-impl error::Error for RegexError {
-    fn description(&self) -> &str {
-        self.details
+impl APIError<&'static str>
+{
+    pub fn locked_out(retry_after: u64) -> Self
+    {
+        APIError::TooManyRequests(
+            ErrorJson::new(MSG_LOCKED_OUT),
+            Header::new("Retry-After", retry_after.to_string()),
+        )
     }
-}
 
-impl RegexError {
-    pub fn new(details: &'static str) -> RegexError {
-        RegexError { details }
+    pub fn too_many_pins(retry_after: u64) -> Self
+    {
+        APIError::TooManyRequests(
+            ErrorJson::new(MSG_TOO_MANY_PINS),
+            Header::new("Retry-After", retry_after.to_string()),
+        )
     }
-}
 
-impl fmt::Display for RegexError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.details)
+    pub fn oidc_busy(retry_after: u64) -> Self
+    {
+        APIError::TooManyRequests(
+            ErrorJson::new(MSG_OIDC_BUSY),
+            Header::new("Retry-After", retry_after.to_string()),
+        )
     }
-}
 
-impl Into<Json<ErrorJson<&str>>> for RegexError {
-    fn into(self) -> Json<ErrorJson<&'static str>> {
-        let error = ErrorJson {
-            error: self.details
-        };
-        Json({
-            error
-        })
+    pub fn too_many_resets(retry_after: u64) -> Self
+    {
+        APIError::TooManyRequests(
+            ErrorJson::new(MSG_TOO_MANY_RESETS),
+            Header::new("Retry-After", retry_after.to_string()),
+        )
     }
 }
-
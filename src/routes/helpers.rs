@@ -1,48 +1,305 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use mongodb::bson::DateTime;
 use regex::Regex;
-use super::errors::RegexError;
-
-async fn validate_password(password: &str) -> Result<(), RegexError>
-{
-    // Lookahead is not allowed so we must use custom statements
-    // "(?=.{10,})(?=.+[a-z])(?=.+[A-Z])(?=.+\d)(?=.+[!@"£$%^&*#-])(?!.\s)"
-    //
-    // Original regex meanings
-    // (?=.{10,}) - Lookahead for at least 10 characters
-    // (?=.+[a-z]) - Lookahead for a lower case character
-    // (?=.+[A-Z]) - Lookahead for a upper case character
-    // (?=.+\n) - Lookahed for at least 1 digit
-    // (?=.+[!@"£$%^&*#-]) - Lookahead for a special character
-    // (?!.\s) - Ensures there is no whitespace
-
-    let validators = [
-        // Lowercase Characters
-        Regex::new("[a-z]").unwrap(),
-        // Upercase Characters
-        Regex::new("[A-Z]").unwrap(),
-        // digit regex
-        Regex::new(r#"\d"#).unwrap(),
-        // Special characters
-        Regex::new(r#"[!"£$%^&*\[\];'#~?><\\]"#).unwrap(),
-        // Length is 16 or longer
-        Regex::new(".{16,}").unwrap(),
-        // No whitespace
-        Regex::new(r#"^\S+$"#).unwrap()
-    ];
-
-    match validators.iter().all(|validator| validator.is_match(password)) {
-        true => Ok(()),
-        false => Err(RegexError::new("Password does not meet strength criteria"))
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson, ValidationErrors};
+use crate::config::PasswordRules;
+use crate::model::{local_midnight, Bucket, TimeRange};
+use crate::ResultT;
+
+/// Password strength rules from the config, checked on every password set
+pub struct PasswordPolicy
+{
+    rules: PasswordRules,
+    /// Patterns the password must match, with the rule shown when it does not
+    validators: Vec<(Regex, &'static str)>,
+    blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy
+{
+    pub fn init(rules: PasswordRules) -> ResultT<PasswordPolicy>
+    {
+        let blocklist = match &rules.blocklist {
+            Some(path) => std::fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        let patterns = [
+            (rules.require_lowercase, "[a-z]", "needs lowercase"),
+            (rules.require_uppercase, "[A-Z]", "needs uppercase"),
+            (rules.require_digit, r#"\d"#, "needs digit"),
+            (
+                rules.require_special,
+                r#"[!"£$%^&*\[\];'#~?><\\@\-_=+.,/|(){}:]"#,
+                "needs special character",
+            ),
+            (true, r#"^\S*$"#, "must not contain whitespace"),
+        ];
+
+        let mut validators = Vec::new();
+
+        for (enabled, pattern, rule) in patterns {
+            if enabled {
+                validators.push((Regex::new(pattern)?, rule));
+            }
+        }
+
+        Ok(PasswordPolicy { rules, validators, blocklist })
+    }
+
+    /// Returns every rule the password breaks
+    pub fn validate_password(&self, password: &str) -> Vec<String>
+    {
+        let mut failures: Vec<String> = self
+            .validators
+            .iter()
+            .filter(|(validator, _)| !validator.is_match(password))
+            .map(|(_, rule)| rule.to_string())
+            .collect();
+
+        if password.chars().count() < self.rules.min_length {
+            failures.push(format!("min length {}", self.rules.min_length));
+        }
+
+        if self.blocklist.contains(&password.to_lowercase()) {
+            failures.push("too common".to_string());
+        }
+
+        failures
     }
 }
 
-async fn validate_email(email: &str) -> Result<(), RegexError> {
+/// Returns every rule the email breaks
+pub fn validate_email(email: &str) -> Vec<String>
+{
+    static VALIDATOR: OnceLock<Regex> = OnceLock::new();
 
     // Regex modified from https://emailregex.com/
-    let validator = Regex::new(r#"(?:[a-z0-9!#$%&'*+/=?^_{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])"#).unwrap();
+    let validator = VALIDATOR.get_or_init(|| Regex::new(r#"^(?:[a-z0-9!#$%&'*+/=?^_{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])$"#).unwrap());
 
-    match validator.is_match(email) {
+    match validator.is_match(&email.to_lowercase()) {
+        true => Vec::new(),
+        false => vec!["invalid email address".to_string()],
+    }
+}
+
+/// Compares secrets without returning early on the first differing byte
+pub fn constant_eq(a: &[u8], b: &[u8]) -> bool
+{
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Turns the collected rule failures into a 422 listing all of them
+pub fn check(failures: Vec<String>) -> Result<(), TextError>
+{
+    match failures.is_empty() {
         true => Ok(()),
-        false => Err(RegexError::new("Invalid email address"))
+        false => Err(APIError::Unprocessable(ValidationErrors::new(failures))),
+    }
+}
+
+/// Time zone used for day boundaries, UTC unless the client names one
+pub fn parse_tz(tz: Option<&str>) -> Result<Tz, TextError>
+{
+    match tz {
+        Some(tz) => tz
+            .parse()
+            .map_err(|_| APIError::BadRequest(ErrorJson::new(errors::MSG_UNKNOWN_TZ))),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Size of the groups charts are counted in, hourly unless asked otherwise
+pub fn parse_bucket(bucket: Option<&str>) -> Result<Bucket, TextError>
+{
+    match bucket {
+        Some(bucket) => Bucket::parse(bucket)
+            .ok_or_else(|| APIError::BadRequest(ErrorJson::new(errors::MSG_UNKNOWN_BUCKET))),
+        None => Ok(Bucket::Hour),
     }
 }
 
+/// Builds a range from either `last`, a single `day`, or `start` and `end`.
+/// Plain dates mean midnight in `tz`, RFC 3339 timestamps are taken as given.
+pub fn time_range(
+    start: Option<&str>,
+    end: Option<&str>,
+    last: Option<&str>,
+    day: Option<&str>,
+    tz: Tz,
+) -> Result<TimeRange, TextError>
+{
+    let bad_request = |msg| APIError::BadRequest(ErrorJson::new(msg));
+
+    let explicit = start.is_some() || end.is_some();
+
+    let range = match (last, day) {
+        (Some(_), Some(_)) => return Err(bad_request(errors::MSG_RANGE_CONFLICT)),
+        (Some(_), None) | (None, Some(_)) if explicit => return Err(bad_request(errors::MSG_RANGE_CONFLICT)),
+        (Some(last), None) => {
+            let millis = parse_last(last).ok_or_else(|| bad_request(errors::MSG_INVALID_LAST))?;
+            let now = DateTime::now();
+
+            TimeRange {
+                start: Some(DateTime::from_millis(now.timestamp_millis() - millis)),
+                end: None,
+            }
+        }
+        (None, Some(day)) => {
+            let date = NaiveDate::parse_from_str(day, "%Y-%m-%d")
+                .map_err(|_| bad_request(errors::MSG_INVALID_DATE))?;
+
+            TimeRange {
+                start: local_day_start(date, tz),
+                end: date.succ_opt().and_then(|next| local_day_start(next, tz)),
+            }
+        }
+        (None, None) => TimeRange {
+            start: parse_instant(start, tz)?,
+            end: parse_instant(end, tz)?,
+        },
+    };
+
+    if let (Some(start), Some(end)) = (range.start, range.end) {
+        if start >= end {
+            return Err(bad_request(errors::MSG_RANGE_ORDER));
+        }
+    }
+
+    Ok(range)
+}
+
+fn parse_instant(value: Option<&str>, tz: Tz) -> Result<Option<DateTime>, TextError>
+{
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    if let Ok(time) = DateTime::parse_rfc3339_str(value) {
+        return Ok(Some(time));
+    }
+
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|date| local_day_start(date, tz)) {
+        Some(time) => Ok(Some(time)),
+        None => Err(APIError::BadRequest(ErrorJson::new(errors::MSG_INVALID_DATE))),
+    }
+}
+
+fn local_day_start(date: NaiveDate, tz: Tz) -> Option<DateTime>
+{
+    local_midnight(date, tz).map(|start| DateTime::from_millis(start.timestamp_millis()))
+}
+
+/// Parses spans like `30m`, `24h`, `7d` or `2w` into milliseconds
+fn parse_last(last: &str) -> Option<i64>
+{
+    let unit = last.chars().last()?;
+    let count: i64 = last[..last.len() - unit.len_utf8()].parse().ok()?;
+
+    let unit_secs = match unit {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        'w' => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+
+    match count > 0 {
+        true => count.checked_mul(unit_secs * 1000),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn rfc3339(time: Option<DateTime>) -> String
+    {
+        time.unwrap().try_to_rfc3339_string().unwrap()
+    }
+
+    /// The message a rejected range came back with
+    fn rejection(result: Result<TimeRange, TextError>) -> String
+    {
+        match result {
+            Err(APIError::BadRequest(json)) => format!("{:?}", json.into_inner()),
+            Err(other) => panic!("expected a bad request, got {:?}", other),
+            Ok(_) => panic!("expected the range to be rejected"),
+        }
+    }
+
+    #[test]
+    fn range_sources_cannot_be_mixed()
+    {
+        let tz = Tz::UTC;
+
+        for (start, last, day) in [
+            (None, Some("24h"), Some("2023-01-01")),
+            (Some("2023-01-01"), Some("24h"), None),
+            (Some("2023-01-01"), None, Some("2023-01-01")),
+        ] {
+            let message = rejection(time_range(start, None, last, day, tz));
+            assert!(message.contains(errors::MSG_RANGE_CONFLICT), "{}", message);
+        }
+    }
+
+    #[test]
+    fn range_start_must_come_before_its_end()
+    {
+        let tz = Tz::UTC;
+
+        for (start, end) in [("2023-01-02", "2023-01-01"), ("2023-01-01T00:00:00Z", "2023-01-01T00:00:00Z")] {
+            let message = rejection(time_range(Some(start), Some(end), None, None, tz));
+            assert!(message.contains(errors::MSG_RANGE_ORDER), "{}", message);
+        }
+    }
+
+    #[test]
+    fn days_start_at_local_midnight()
+    {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let range = time_range(None, None, None, Some("2023-07-01"), tz).unwrap();
+
+        assert_eq!(rfc3339(range.start), "2023-06-30T22:00:00Z");
+        assert_eq!(rfc3339(range.end), "2023-07-01T22:00:00Z");
+    }
+
+    #[test]
+    fn days_without_a_midnight_start_an_hour_later()
+    {
+        // Clocks in São Paulo went from 23:59:59 straight to 01:00 on this day
+        let tz: Tz = "America/Sao_Paulo".parse().unwrap();
+        let range = time_range(None, None, None, Some("2018-11-04"), tz).unwrap();
+
+        assert_eq!(rfc3339(range.start), "2018-11-04T03:00:00Z");
+        assert_eq!(rfc3339(range.end), "2018-11-05T02:00:00Z");
+
+        let range = time_range(Some("2018-11-04"), None, None, None, tz).unwrap();
+        assert_eq!(rfc3339(range.start), "2018-11-04T03:00:00Z");
+    }
+
+    #[test]
+    fn bad_relative_ranges_are_rejected()
+    {
+        for last in ["0h", "-1d", "3y", "h", "99999999999999w"] {
+            let message = rejection(time_range(None, None, Some(last), None, Tz::UTC));
+            assert!(message.contains(errors::MSG_INVALID_LAST), "{}", message);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use super::audit::AuditContext;
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson};
+use super::helpers;
+use super::permissions::{scope, Authorized};
+use super::returns::InvitationSummary;
+use super::signing::TokenType;
+use super::state;
+use crate::model::types::{AuditOutcome, Invitation, Role};
+use crate::model::AnzenDB;
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::serde::Serialize;
+use rocket::State;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+const INVITE_PURPOSE: &str = "invite";
+/// Longest an invitation may be valid for unless `invite_ttl` is longer
+const MAX_INVITE_TTL: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct InviteForm
+{
+    email: String,
+    role: Role,
+    /// Seconds until the invitation expires, defaults to the configured ttl
+    /// and is capped at 30 days or the configured ttl if that is longer
+    expires_in: Option<i64>,
+}
+
+/// Signed invite code handed to the invitee. The invitation it points to
+/// must still be unused when they register.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteClaims
+{
+    pub exp: usize,
+    pub sub: String,
+    pub iid: String,
+    pub purpose: String,
+}
+
+fn invite_code(state: &state::Validation, invitation: &Invitation) -> Result<String, TextError>
+{
+    let claims = InviteClaims {
+        exp: (invitation.expires.timestamp_millis() / 1000) as usize,
+        sub: invitation.email.clone(),
+        iid: invitation._id.to_hex(),
+        purpose: INVITE_PURPOSE.to_string(),
+    };
+
+    match state.keys.encode(&claims, TokenType::Invite) {
+        Ok(code) => Ok(code),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_GEN_TOKEN))),
+    }
+}
+
+/// Checks the signature and expiry of an invite code issued for `email`
+pub fn verify_code(state: &state::Validation, code: &str, email: &String) -> Option<InviteClaims>
+{
+    let claims = state.keys.decode::<InviteClaims>(code, TokenType::Invite)?;
+
+    match claims.purpose == INVITE_PURPOSE && &claims.sub == email {
+        true => Some(claims),
+        false => None,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/invitations",
+    operation_id = "invites_list",
+    tag = "invitations",
+    params(
+        ("all" = Option<bool>, Query, description = "Include used, revoked and expired invitations"),
+    ),
+    responses(
+        (status = 200, description = "Invitations", body = [InvitationSummary]),
+    ),
+    security(("bearer_token" = []))
+)]
+#[get("/?<all>")]
+pub async fn list(
+    all: Option<bool>,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Json<Vec<InvitationSummary>>, TextError>
+{
+    auth?;
+
+    match db.list_invitations(all.unwrap_or(false)).await {
+        Ok(invitations) => Ok(Json(
+            invitations.into_iter().map(InvitationSummary::from).collect(),
+        )),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/invitations",
+    operation_id = "invites_create",
+    tag = "invitations",
+    request_body = InviteForm,
+    responses(
+        (status = 200, description = "Invitation and its code"),
+        (status = 422, description = "Email or expiry failed validation"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/", data = "<form>")]
+pub async fn create(
+    form: Json<InviteForm>,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    state: &State<state::Validation>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let valid = state.inner();
+    let db = db.inner();
+
+    let mut failures = helpers::validate_email(&form.email);
+
+    if form.expires_in.map(|ttl| ttl <= 0).unwrap_or(false) {
+        failures.push("expires_in must be more than 0".to_string());
+    }
+
+    helpers::check(failures)?;
+
+    if db.get_account(&form.email).await.is_ok() {
+        return Err(APIError::Conflict(ErrorJson::new(errors::MSG_USER_EXISTS)));
+    }
+
+    let ttl = form
+        .expires_in
+        .map(|ttl| ttl as u64)
+        .unwrap_or(valid.invite_ttl)
+        .min(valid.invite_ttl.max(MAX_INVITE_TTL));
+
+    let invitation = match db.create_invitation(&form.email, form.role, &actor, ttl).await {
+        Ok(invitation) => invitation,
+        Err(_) => {
+            audit.record(db, &actor, "invite.create", Some(&form.email), AuditOutcome::Failure).await;
+            return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR)));
+        }
+    };
+
+    audit.record(db, &actor, "invite.create", Some(&form.email), AuditOutcome::Success).await;
+
+    let code = invite_code(valid, &invitation)?;
+
+    Ok(json!({
+        "data": InvitationSummary::from(invitation),
+        "code": code
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/invitations/{id}",
+    operation_id = "invites_revoke",
+    tag = "invitations",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Invitation revoked"),
+        (status = 404, description = "Invitation does not exist"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[delete("/<id>")]
+pub async fn revoke(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let actor = auth?.sub;
+    let db = db.inner();
+
+    let invitation = match db.get_invitation(id).await {
+        Ok(Some(invitation)) => invitation,
+        _ => return Err(APIError::NotFound(ErrorJson::new(errors::MSG_INVITE_NOT_FOUND))),
+    };
+
+    match db.revoke_invitation(&invitation._id).await {
+        Ok(true) => {
+            audit.record(db, &actor, "invite.revoke", Some(&invitation.email), AuditOutcome::Success).await;
+            Ok(json!({ "ok": true }))
+        }
+        Ok(false) => Err(APIError::Conflict(ErrorJson::new(errors::MSG_INVITE_USED))),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
@@ -0,0 +1,227 @@
+use super::audit::AuditContext;
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson};
+use super::helpers;
+use super::permissions::{scope, Authorized, Principal, Scope};
+use super::returns::ApiKeySummary;
+use crate::model::types::{AuditOutcome, Permission};
+use crate::model::AnzenDB;
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::State;
+use serde::Deserialize;
+use std::net::IpAddr;
+use utoipa::ToSchema;
+
+/// Longest lifetime a key may be given, in seconds
+const MAX_KEY_TTL: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyForm
+{
+    name: String,
+    scopes: Vec<Permission>,
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+    /// Seconds until the key expires, at most a year. Keys without one
+    /// never expire.
+    expires_in: Option<i64>,
+}
+
+/// Keys are managed by people, a key cannot be used to mint more keys
+fn require_user<S: Scope>(auth: &Authorized<S>) -> Result<(), TextError>
+{
+    match auth.principal {
+        Principal::User(_) => Ok(()),
+        Principal::ApiKey(_) => Err(APIError::Forbidden(ErrorJson::new(
+            errors::MSG_MISSING_PERMISSION,
+        ))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/keys",
+    operation_id = "keys_list",
+    tag = "keys",
+    responses(
+        (status = 200, description = "API keys", body = [ApiKeySummary]),
+    ),
+    security(("bearer_token" = []))
+)]
+#[get("/")]
+pub async fn list(
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Json<Vec<ApiKeySummary>>, TextError>
+{
+    require_user(&auth?)?;
+
+    match db.list_api_keys().await {
+        Ok(keys) => Ok(Json(keys.into_iter().map(ApiKeySummary::from).collect())),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/keys",
+    operation_id = "keys_create",
+    tag = "keys",
+    request_body = KeyForm,
+    responses(
+        (status = 200, description = "Key details and the key, shown only once"),
+        (status = 422, description = "Invalid scopes or addresses"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/", data = "<form>")]
+pub async fn create(
+    form: Json<KeyForm>,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let auth = auth?;
+    require_user(&auth)?;
+    let actor = auth.sub;
+    let db = db.inner();
+
+    let mut failures = Vec::new();
+
+    if form.name.trim().is_empty() {
+        failures.push("name is required".to_string());
+    }
+
+    if form.scopes.is_empty() {
+        failures.push("at least one scope is required".to_string());
+    }
+
+    if form.scopes.contains(&Permission::ManageUsers) {
+        failures.push("manage-users cannot be granted to API keys".to_string());
+    }
+
+    match form.expires_in {
+        Some(ttl) if ttl <= 0 => failures.push("expires_in must be more than 0".to_string()),
+        Some(ttl) if ttl as u64 > MAX_KEY_TTL => failures.push(format!("expires_in must be at most {} seconds", MAX_KEY_TTL)),
+        _ => (),
+    }
+
+    form.allowed_ips
+        .iter()
+        .filter(|ip| ip.parse::<IpAddr>().is_err())
+        .for_each(|ip| failures.push(format!("invalid ip {}", ip)));
+
+    helpers::check(failures)?;
+
+    let created = db
+        .create_api_key(
+            &form.name,
+            form.scopes.clone(),
+            form.allowed_ips.clone(),
+            form.expires_in.map(|ttl| ttl as u64),
+            &actor,
+        )
+        .await;
+
+    let (api_key, key) = match created {
+        Ok(v) => v,
+        Err(_) => {
+            audit.record(db, &actor, "apikey.create", Some(&form.name), AuditOutcome::Failure).await;
+            return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR)));
+        }
+    };
+
+    let target = api_key._id.to_hex();
+    audit.record(db, &actor, "apikey.create", Some(&target), AuditOutcome::Success).await;
+
+    Ok(json!({
+        "data": ApiKeySummary::from(api_key),
+        "key": key
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/keys/{id}/rotate",
+    operation_id = "keys_rotate",
+    tag = "keys",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "The new key, shown only once"),
+        (status = 404, description = "API key does not exist"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/<id>/rotate")]
+pub async fn rotate(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let auth = auth?;
+    require_user(&auth)?;
+    let actor = auth.sub;
+    let db = db.inner();
+
+    let api_key = match db.get_api_key(id).await {
+        Ok(Some(api_key)) => api_key,
+        _ => return Err(APIError::NotFound(ErrorJson::new(errors::MSG_API_KEY_NOT_FOUND))),
+    };
+
+    match db.rotate_api_key(&api_key._id).await {
+        Ok(Some(key)) => {
+            audit.record(db, &actor, "apikey.rotate", Some(id), AuditOutcome::Success).await;
+            Ok(json!({ "ok": true, "key": key }))
+        }
+        Ok(None) => Err(APIError::Conflict(ErrorJson::new(errors::MSG_API_KEY_REVOKED))),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/keys/{id}",
+    operation_id = "keys_revoke",
+    tag = "keys",
+    params(
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Key revoked"),
+        (status = 404, description = "API key does not exist"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[delete("/<id>")]
+pub async fn revoke(
+    id: &str,
+    auth: Result<Authorized<scope::ManageUsers>, TextError>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let auth = auth?;
+    require_user(&auth)?;
+    let actor = auth.sub;
+    let db = db.inner();
+
+    let api_key = match db.get_api_key(id).await {
+        Ok(Some(api_key)) => api_key,
+        _ => return Err(APIError::NotFound(ErrorJson::new(errors::MSG_API_KEY_NOT_FOUND))),
+    };
+
+    match db.revoke_api_key(&api_key._id).await {
+        Ok(true) => {
+            audit.record(db, &actor, "apikey.revoke", Some(id), AuditOutcome::Success).await;
+            Ok(json!({ "ok": true }))
+        }
+        Ok(false) => Err(APIError::Conflict(ErrorJson::new(errors::MSG_API_KEY_REVOKED))),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
@@ -0,0 +1,348 @@
+use super::audit::AuditContext;
+use super::auth::{self, Claims, TextError};
+use super::errors::{self, APIError, ErrorJson};
+use super::helpers;
+use super::returns::*;
+use super::signing::TokenType;
+use crate::model::types::{Account, AuditOutcome};
+use crate::model::AnzenDB;
+use crate::routes::state;
+use rand::{thread_rng, RngCore};
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::serde::Serialize;
+use rocket::State;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+use utoipa::ToSchema;
+
+const ISSUER: &str = "Anzen";
+const CHALLENGE_TTL: u64 = 60 * 5;
+const CHALLENGE_PURPOSE: &str = "mfa";
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct CodeForm
+{
+    code: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct DisableForm
+{
+    password: String,
+    code: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ChallengeForm
+{
+    challenge: String,
+    code: String,
+}
+
+/// Proof that the password step of a login succeeded. It cannot be used as
+/// an access token as it carries no session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims
+{
+    pub exp: usize,
+    pub sub: String,
+    pub purpose: String,
+    /// Whether a password change was already required at the password step
+    #[serde(default)]
+    pub reset: bool,
+}
+
+fn totp(secret: &str, email: &str) -> Option<TOTP>
+{
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        secret,
+        Some(ISSUER.to_string()),
+        email.to_string(),
+    )
+    .ok()
+}
+
+/// Checks a TOTP code, falling back to the user's recovery codes
+pub async fn verify_code(db: &AnzenDB, email: &String, code: &str) -> bool
+{
+    let account = match db.get_account(email).await {
+        Ok(account) => account,
+        Err(_) => return false,
+    };
+
+    let secret = match &account.totp {
+        Some(totp) => totp.secret.clone(),
+        None => return false,
+    };
+
+    let code = code.trim();
+
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        let totp = match totp(&secret, email) {
+            Some(totp) => totp,
+            None => return false,
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let current = now / totp.step;
+
+        // The same window as check_current, one step either side
+        let step = (current.saturating_sub(1)..=current + 1).find(|step| {
+            helpers::constant_eq(totp.generate(step * totp.step).as_bytes(), code.as_bytes())
+        });
+
+        return match step {
+            Some(step) => db.use_totp_step(email, step as i64).await.unwrap_or(false),
+            None => false,
+        };
+    }
+
+    account.totp_enabled() && db.use_recovery_code(email, code).await.unwrap_or(false)
+}
+
+pub fn challenge(state: &state::Validation, account: &Account) -> Result<MfaChallenge, TextError>
+{
+    let exp = SystemTime::now()
+        .checked_add(Duration::from_secs(CHALLENGE_TTL))
+        .unwrap();
+    let exp = exp.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    let claims = ChallengeClaims {
+        exp: exp.try_into().unwrap(),
+        sub: account.email.clone(),
+        purpose: CHALLENGE_PURPOSE.to_string(),
+        reset: account.password_reset,
+    };
+
+    match state.keys.encode(&claims, TokenType::Challenge) {
+        Ok(challenge) => Ok(MfaChallenge {
+            mfa_required: true,
+            challenge,
+            expires_in: CHALLENGE_TTL,
+        }),
+        Err(_) => Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_GEN_TOKEN))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/2fa/setup",
+    operation_id = "mfa_setup",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Secret and otpauth URL to enroll an authenticator"),
+        (status = 401, description = "Not logged in"),
+        (status = 409, description = "Two-factor is already enabled"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/2fa/setup")]
+pub async fn setup(
+    claims: Result<Claims, TextError>,
+    db: &State<AnzenDB>,
+) -> Result<Value, TextError>
+{
+    let email = claims?.sub;
+    let db = db.inner();
+
+    let mut bytes = [0u8; 20];
+    thread_rng().fill_bytes(&mut bytes);
+    let secret = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+
+    let uri = match totp(&secret, &email) {
+        Some(totp) => totp.get_url(),
+        None => return Err(APIError::Internal(ErrorJson::new(errors::MSG_GEN_TOKEN))),
+    };
+
+    match db.begin_totp(&email, &secret).await {
+        Ok(true) => Ok(json!({
+            "secret": secret,
+            "otpauth_uri": uri
+        })),
+        Ok(false) => Err(APIError::Conflict(ErrorJson::new(errors::MSG_2FA_ENABLED))),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/2fa/enable",
+    operation_id = "mfa_enable",
+    tag = "auth",
+    request_body = CodeForm,
+    responses(
+        (status = 200, description = "Two-factor enabled, with recovery codes"),
+        (status = 401, description = "Invalid code"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/2fa/enable", data = "<form>")]
+pub async fn enable(
+    claims: Result<Claims, TextError>,
+    db: &State<AnzenDB>,
+    form: Json<CodeForm>,
+) -> Result<Value, TextError>
+{
+    let email = claims?.sub;
+    let db = db.inner();
+
+    let account = match db.get_account(&email).await {
+        Ok(account) => account,
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    };
+
+    if account.totp_enabled() {
+        return Err(APIError::Conflict(ErrorJson::new(errors::MSG_2FA_ENABLED)));
+    }
+
+    if account.totp.is_none() {
+        return Err(APIError::BadRequest(ErrorJson::new(errors::MSG_2FA_NOT_SETUP)));
+    }
+
+    if !verify_code(db, &email, &form.code).await {
+        return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_2FA)));
+    }
+
+    match db.enable_totp(&email).await {
+        Ok(codes) => Ok(json!({
+            "ok": true,
+            "recovery_codes": codes
+        })),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/2fa/disable",
+    operation_id = "mfa_disable",
+    tag = "auth",
+    request_body = DisableForm,
+    responses(
+        (status = 200, description = "Two-factor disabled"),
+        (status = 401, description = "Invalid password or code"),
+        (status = 429, description = "Too many failed attempts"),
+    ),
+    security(("bearer_token" = []))
+)]
+#[post("/2fa/disable", data = "<form>")]
+pub async fn disable(
+    claims: Result<Claims, TextError>,
+    db: &State<AnzenDB>,
+    throttle: &State<state::LoginThrottle>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
+    form: Json<DisableForm>,
+) -> Result<Value, TextError>
+{
+    let email = claims?.sub;
+    let db = db.inner();
+    let throttle = throttle.inner();
+
+    // Guessing the password here must cost as much as at login
+    if let Some(retry_after) = throttle.locked(&email, ip).await {
+        return Err(APIError::locked_out(retry_after));
+    }
+
+    match db.valid_user(&email, &form.password).await {
+        Ok(true) => (),
+        _ => {
+            auth::login_failed(throttle, &audit, db, &email, ip).await;
+            return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_PWD)));
+        }
+    }
+
+    if !verify_code(db, &email, &form.code).await {
+        auth::login_failed(throttle, &audit, db, &email, ip).await;
+        return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_2FA)));
+    }
+
+    throttle.success(&email).await;
+
+    match db.disable_totp(&email).await {
+        Ok(_) => Ok(json!({ "ok": true })),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/login/2fa",
+    operation_id = "mfa_login",
+    tag = "auth",
+    request_body = ChallengeForm,
+    responses(
+        (status = 200, description = "Tokens once the challenge is answered", body = LoginResponse),
+        (status = 401, description = "Invalid challenge or code, or the code was already used"),
+        (status = 403, description = "Account was disabled or reset since the password step"),
+        (status = 429, description = "Too many failed logins"),
+    )
+)]
+#[post("/login/2fa", data = "<form>")]
+pub async fn login(
+    form: Json<ChallengeForm>,
+    state: &State<state::Validation>,
+    throttle: &State<state::LoginThrottle>,
+    audit: AuditContext,
+    ip: Option<IpAddr>,
+    db: &State<AnzenDB>,
+) -> Result<Json<LoginResponse>, TextError>
+{
+    let valid = state.inner();
+    let throttle = throttle.inner();
+    let db = db.inner();
+
+    let claims = match valid.keys.decode::<ChallengeClaims>(&form.challenge, TokenType::Challenge) {
+        Some(claims) if claims.purpose == CHALLENGE_PURPOSE => claims,
+        _ => return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_TOKEN))),
+    };
+
+    if !valid.email_allowed(&claims.sub).await {
+        return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_NO_LOGON_ALLOWED)));
+    }
+
+    if let Some(retry_after) = throttle.locked(&claims.sub, ip).await {
+        return Err(APIError::locked_out(retry_after));
+    }
+
+    if !verify_code(db, &claims.sub, &form.code).await {
+        auth::login_failed(throttle, &audit, db, &claims.sub, ip).await;
+        return Err(APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_2FA)));
+    }
+
+    throttle.success(&claims.sub).await;
+
+    // The account may have changed since the password step
+    let account = match db.get_account(&claims.sub).await {
+        Ok(account) => account,
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    };
+
+    if account.disabled {
+        audit.record(db, &claims.sub, "login.2fa", Some(&claims.sub), AuditOutcome::Denied).await;
+        return Err(APIError::Forbidden(ErrorJson::new(errors::MSG_ACCOUNT_DISABLED)));
+    }
+
+    // A reset forced in between also ended every session, start over
+    if account.password_reset && !claims.reset {
+        audit.record(db, &claims.sub, "login.2fa", Some(&claims.sub), AuditOutcome::Denied).await;
+        return Err(APIError::Forbidden(ErrorJson::new(errors::MSG_PASSWORD_RESET)));
+    }
+
+    let response = auth::start_session(valid, db, claims.sub.clone()).await?;
+
+    audit.record(db, &claims.sub, "login.2fa", Some(&claims.sub), AuditOutcome::Success).await;
+
+    Ok(Json(response))
+}
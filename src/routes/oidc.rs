@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::account;
+use super::auth::{self, TextError};
+use super::audit::AuditContext;
+use super::errors::{self, APIError, ErrorJson};
+use super::mfa;
+use super::returns::*;
+use super::state;
+use crate::config::OidcConfig;
+use crate::model::types::{Account, AuditOutcome, ExternalIdentity, Role};
+use crate::model::AnzenDB;
+use crate::ResultT;
+use jsonwebtoken::{decode_header, Algorithm, DecodingKey, Validation};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rocket::serde::json::{Json, Value};
+use rocket::State;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+/// How long a user has to finish logging in at the provider
+const PENDING_TTL: Duration = Duration::from_secs(60 * 10);
+/// Logins that may be waiting on the provider at once, anyone can start one
+const MAX_PENDING: usize = 10_000;
+
+#[derive(Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct CallbackForm
+{
+    code: String,
+    state: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct Discovery
+{
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse
+{
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks
+{
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk
+{
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+struct Pending
+{
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+/// Authorization code + PKCE login against the configured provider
+pub struct OidcClient
+{
+    config: Option<OidcConfig>,
+    http: reqwest::Client,
+    discovery: Mutex<Option<Discovery>>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl OidcClient
+{
+    pub fn init(config: Option<OidcConfig>) -> OidcClient
+    {
+        OidcClient {
+            config,
+            http: reqwest::Client::new(),
+            discovery: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn discovery(&self, config: &OidcConfig) -> ResultT<Discovery>
+    {
+        let mut cached = self.discovery.lock().await;
+
+        if let Some(discovery) = cached.as_ref() {
+            return Ok(discovery.clone());
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = self.http.get(url).send().await?.error_for_status()?.json().await?;
+
+        *cached = Some(discovery.clone());
+
+        Ok(discovery)
+    }
+
+    /// Verifies the ID token against the provider's published keys
+    async fn verify_id_token(
+        &self,
+        config: &OidcConfig,
+        discovery: &Discovery,
+        token: &str,
+    ) -> ResultT<HashMap<String, Value>>
+    {
+        let header = decode_header(token)?;
+
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+                | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512
+        ) {
+            return Err("Unsupported ID token algorithm".into());
+        }
+
+        let jwks: Jwks = self
+            .http
+            .get(&discovery.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let jwk = jwks
+            .keys
+            .iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .find(|jwk| header.kid.is_none() || jwk.kid == header.kid)
+            .ok_or("No matching provider key")?;
+
+        let key = match (&jwk.n, &jwk.e) {
+            (Some(n), Some(e)) => DecodingKey::from_rsa_components(n, e)?,
+            _ => return Err("Provider key is missing its components".into()),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_audience(&[&config.client_id]);
+
+        Ok(jsonwebtoken::decode::<HashMap<String, Value>>(token, &key, &validation)?.claims)
+    }
+}
+
+fn random_string(length: usize) -> String
+{
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+fn pkce_challenge(verifier: &str) -> String
+{
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Picks the most privileged role granted by any of the user's groups
+fn map_role(config: &OidcConfig, claims: &HashMap<String, Value>) -> Option<Role>
+{
+    let groups: Vec<&str> = match claims.get(&config.groups_claim) {
+        Some(Value::Array(groups)) => groups.iter().filter_map(|group| group.as_str()).collect(),
+        Some(Value::String(group)) => vec![group.as_str()],
+        _ => Vec::new(),
+    };
+
+    groups
+        .iter()
+        .filter_map(|group| config.role_mapping.get(*group))
+        .min_by_key(|role| role.level())
+        .copied()
+        .or(config.default_role)
+}
+
+/// Follows the provider's groups, except that the last active admin keeps
+/// the role so someone can still manage users
+async fn sync_role(db: &AnzenDB, audit: &AuditContext, account: &Account, role: Role) -> Result<(), TextError>
+{
+    let action = format!("user.role.{}", role.as_str());
+
+    if role != Role::Admin && account::keeps_an_admin(db, account).await.is_err() {
+        audit.record(db, &account.email, &action, Some(&account.email), AuditOutcome::Denied).await;
+        return Ok(());
+    }
+
+    if db.set_role(&account._id, role).await.is_err() {
+        return Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR)));
+    }
+
+    // Two admins losing the group at once both pass the check above
+    if role != Role::Admin && !account::admin_remains(db).await {
+        let _ = db.set_role(&account._id, account.role()).await;
+        audit.record(db, &account.email, &action, Some(&account.email), AuditOutcome::Denied).await;
+        return Ok(());
+    }
+
+    audit.record(db, &account.email, &action, Some(&account.email), AuditOutcome::Success).await;
+
+    Ok(())
+}
+
+fn configured(oidc: &OidcClient) -> Result<&OidcConfig, TextError>
+{
+    match &oidc.config {
+        Some(config) => Ok(config),
+        None => Err(APIError::NotFound(ErrorJson::new(errors::MSG_OIDC_DISABLED))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/oidc/login",
+    operation_id = "oidc_login",
+    tag = "auth",
+    responses(
+        (status = 200, description = "URL to send the user to at the identity provider"),
+        (status = 404, description = "OpenID Connect is not configured"),
+        (status = 429, description = "Too many logins waiting on the provider"),
+    )
+)]
+#[get("/oidc/login")]
+pub async fn login(oidc: &State<OidcClient>) -> Result<Value, TextError>
+{
+    let oidc = oidc.inner();
+    let config = configured(oidc)?;
+
+    let discovery = match oidc.discovery(config).await {
+        Ok(discovery) => discovery,
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_OIDC_PROVIDER))),
+    };
+
+    let state = random_string(32);
+    let nonce = random_string(32);
+    let verifier = random_string(64);
+
+    let mut url = match reqwest::Url::parse(&discovery.authorization_endpoint) {
+        Ok(url) => url,
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_OIDC_PROVIDER))),
+    };
+
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_uri)
+        .append_pair("scope", &config.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &pkce_challenge(&verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    let mut pending = oidc.pending.lock().await;
+    pending.retain(|_, login| login.created.elapsed() < PENDING_TTL);
+
+    if pending.len() >= MAX_PENDING {
+        let oldest = pending.values().map(|login| login.created).min().unwrap_or_else(Instant::now);
+        let retry_after = PENDING_TTL.saturating_sub(oldest.elapsed()).as_secs().max(1);
+        return Err(APIError::oidc_busy(retry_after));
+    }
+
+    pending.insert(state, Pending {
+        verifier,
+        nonce,
+        created: Instant::now(),
+    });
+
+    Ok(json!({ "authorization_url": url.to_string() }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/oidc/callback",
+    operation_id = "oidc_callback",
+    tag = "auth",
+    request_body = CallbackForm,
+    responses(
+        (status = 200, description = "Tokens for the provider account, or an `MfaChallenge` when it has two-factor enabled", body = LoginResponse),
+        (status = 401, description = "Provider login failed or the email is not verified"),
+        (status = 403, description = "No role is mapped for the user, or the account is disabled"),
+        (status = 409, description = "A password account already uses the email"),
+    )
+)]
+#[post("/oidc/callback", data = "<form>")]
+pub async fn callback(
+    form: Json<CallbackForm>,
+    oidc: &State<OidcClient>,
+    state: &State<state::Validation>,
+    audit: AuditContext,
+    db: &State<AnzenDB>,
+) -> Result<Json<LoginResult>, TextError>
+{
+    let oidc = oidc.inner();
+    let valid = state.inner();
+    let db = db.inner();
+    let config = configured(oidc)?;
+
+    let failed = || APIError::Unauthorized(ErrorJson::new(errors::MSG_OIDC_FAILED));
+
+    let pending = match oidc.pending.lock().await.remove(&form.state) {
+        Some(pending) if pending.created.elapsed() < PENDING_TTL => pending,
+        _ => return Err(failed()),
+    };
+
+    let discovery = match oidc.discovery(config).await {
+        Ok(discovery) => discovery,
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_OIDC_PROVIDER))),
+    };
+
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", form.code.as_str()),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", pending.verifier.as_str()),
+    ];
+
+    if let Some(secret) = &config.client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+
+    let tokens: TokenResponse = match oidc.http.post(&discovery.token_endpoint).form(&params).send().await {
+        Ok(response) => match response.error_for_status() {
+            Ok(response) => match response.json().await {
+                Ok(tokens) => tokens,
+                Err(_) => return Err(failed()),
+            },
+            Err(_) => return Err(failed()),
+        },
+        Err(_) => return Err(APIError::Internal(ErrorJson::new(errors::MSG_OIDC_PROVIDER))),
+    };
+
+    let claims = match oidc.verify_id_token(config, &discovery, &tokens.id_token).await {
+        Ok(claims) => claims,
+        Err(_) => return Err(failed()),
+    };
+
+    if claims.get("nonce").and_then(|nonce| nonce.as_str()) != Some(pending.nonce.as_str()) {
+        return Err(failed());
+    }
+
+    // Providers that leave the claim out have not checked the address
+    if claims.get("email_verified").and_then(|verified| verified.as_bool()) != Some(true) {
+        return Err(failed());
+    }
+
+    let (email, subject) = match (
+        claims.get("email").and_then(|email| email.as_str()),
+        claims.get("sub").and_then(|sub| sub.as_str()),
+    ) {
+        (Some(email), Some(subject)) => (email.to_string(), subject.to_string()),
+        _ => return Err(failed()),
+    };
+
+    let role = match map_role(config, &claims) {
+        Some(role) => role,
+        None => {
+            audit.record(db, &email, "login.oidc", Some(&email), AuditOutcome::Denied).await;
+            return Err(APIError::Forbidden(ErrorJson::new(errors::MSG_NO_LOGON_ALLOWED)));
+        }
+    };
+
+    let db_error = || APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR));
+
+    let account = match db.find_external_account(&discovery.issuer, &subject).await {
+        Ok(Some(account)) => {
+            // Only accounts the provider created follow its groups
+            if account.role() != role {
+                sync_role(db, &audit, &account, role).await?;
+            }
+            account
+        }
+        Ok(None) => {
+            // Existing password accounts are never taken over by an email match
+            match db.find_account(&email).await {
+                Ok(None) => (),
+                Ok(Some(_)) => {
+                    audit.record(db, &email, "login.oidc", Some(&email), AuditOutcome::Denied).await;
+                    return Err(APIError::Conflict(ErrorJson::new(errors::MSG_OIDC_NOT_LINKED)));
+                }
+                Err(_) => return Err(db_error()),
+            }
+
+            let username = claims
+                .get("preferred_username")
+                .and_then(|name| name.as_str())
+                .unwrap_or(email.as_str())
+                .to_string();
+
+            let external = ExternalIdentity {
+                issuer: discovery.issuer.clone(),
+                subject,
+            };
+
+            match db.new_external_user(&external, &email, &username, role).await {
+                Ok(true) => (),
+                Ok(false) => return Err(APIError::Conflict(ErrorJson::new(errors::MSG_OIDC_NOT_LINKED))),
+                Err(_) => return Err(db_error()),
+            }
+
+            match db.find_account(&email).await {
+                Ok(Some(account)) => account,
+                _ => return Err(db_error()),
+            }
+        }
+        Err(_) => return Err(db_error()),
+    };
+
+    // The provider may report a new address, the account keeps its own
+    let email = account.email.clone();
+
+    if account.disabled {
+        audit.record(db, &email, "login.oidc", Some(&email), AuditOutcome::Denied).await;
+        return Err(APIError::Forbidden(ErrorJson::new(errors::MSG_ACCOUNT_DISABLED)));
+    }
+
+    // Two-factor is still asked for, the provider only stands in for the password
+    if account.totp_enabled() {
+        let challenge = mfa::challenge(valid, &account)?;
+        return Ok(Json(LoginResult::Challenge(challenge)));
+    }
+
+    audit.record(db, &email, "login.oidc", Some(&email), AuditOutcome::Success).await;
+
+    let response = auth::start_session(valid, db, email).await?;
+
+    Ok(Json(LoginResult::Tokens(response)))
+}
@@ -0,0 +1,275 @@
+use super::permissions::API_KEY_HEADER;
+use super::{
+    account, audit, auth, corefuncs, data, devices, errors, invites, keys, mfa, oidc, plugins,
+    returns, signing, stream,
+};
+use crate::model::types::{AuditOutcome, Permission, Role};
+use rocket::serde::json::Json;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "anzen web API",
+        description = "Errors are returned as `{ \"error\": \"message\" }`, validation failures add a `rules` list."
+    ),
+    paths(
+        auth::login,
+        auth::register,
+        auth::refresh,
+        auth::logout,
+        auth::logout_all,
+        auth::forgot,
+        auth::reset,
+        mfa::setup,
+        mfa::enable,
+        mfa::disable,
+        mfa::login,
+        oidc::login,
+        oidc::callback,
+        data::stats,
+        data::test,
+        data::toggle,
+        data::arm,
+        data::disarm,
+        data::search,
+        account::user,
+        account::users,
+        account::updatepassword,
+        account::get_user,
+        account::set_role,
+        account::disable,
+        account::enable,
+        account::delete_user,
+        account::force_reset,
+        account::unlock,
+        account::policy,
+        account::set_policy,
+        invites::list,
+        invites::create,
+        invites::revoke,
+        keys::list,
+        keys::create,
+        keys::rotate,
+        keys::revoke,
+        devices::list,
+        devices::get_device,
+        devices::update,
+        plugins::list,
+        audit::list,
+        stream::stream,
+        corefuncs::addmail,
+        signing::jwks,
+        spec,
+    ),
+    components(schemas(
+        auth::UserCred,
+        auth::UserRegister,
+        auth::RefreshForm,
+        auth::ForgotForm,
+        auth::ResetForm,
+        mfa::CodeForm,
+        mfa::DisableForm,
+        mfa::ChallengeForm,
+        oidc::CallbackForm,
+        data::ArmForm,
+        data::DisarmForm,
+        account::PasswordForm,
+        account::PolicyForm,
+        account::RoleForm,
+        invites::InviteForm,
+        keys::KeyForm,
+        devices::DeviceForm,
+        corefuncs::EmailForm,
+        errors::ValidationErrors,
+        returns::LoginResponse,
+        returns::MfaChallenge,
+        returns::RegisterResponse,
+        returns::UserSummary,
+        returns::UserList,
+        returns::InvitationSummary,
+        returns::ApiKeySummary,
+        returns::CoreStatus,
+        returns::CursorPage,
+        returns::SearchPages,
+        returns::EventCommandN,
+        returns::SourceRef,
+        returns::EventView,
+        returns::CommandView,
+        returns::BucketCount,
+        returns::ReadingStats,
+        returns::AuditRecord,
+        returns::AuditLog,
+        returns::DeviceSummary,
+        returns::DeviceList,
+        returns::PluginHealth,
+        returns::PluginSummary,
+        Role,
+        Permission,
+        AuditOutcome,
+    )),
+    modifiers(&SecurityAddon),
+    tags(
+        (name = "auth", description = "Logins, sessions and two-factor"),
+        (name = "data", description = "Stats, search and arm control"),
+        (name = "users", description = "The current user and user management"),
+        (name = "invitations", description = "Invitations to register"),
+        (name = "keys", description = "API keys for integrations"),
+        (name = "devices", description = "Device registry"),
+        (name = "plugins", description = "Plugin inventory and health"),
+        (name = "audit", description = "Audit log"),
+        (name = "stream", description = "Live updates"),
+        (name = "core", description = "Requests passed on to anzen core"),
+        (name = "docs", description = "This document"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Access tokens go in the `Authorization` header, API keys in their own header
+struct SecurityAddon;
+
+impl Modify for SecurityAddon
+{
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi)
+    {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer_token",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+            );
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/openapi.json",
+    operation_id = "openapi_spec",
+    tag = "docs",
+    responses(
+        (status = 200, description = "OpenAPI 3 document for this API")
+    )
+)]
+#[get("/openapi.json")]
+pub fn spec() -> Json<utoipa::openapi::OpenApi>
+{
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::ApiDoc;
+    use rocket::http::Method;
+    use utoipa::openapi::PathItemType;
+    use utoipa::OpenApi;
+
+    fn item_type(method: Method) -> Option<PathItemType>
+    {
+        match method {
+            Method::Get => Some(PathItemType::Get),
+            Method::Post => Some(PathItemType::Post),
+            Method::Put => Some(PathItemType::Put),
+            Method::Delete => Some(PathItemType::Delete),
+            Method::Patch => Some(PathItemType::Patch),
+            _ => None,
+        }
+    }
+
+    /// `/api/v1/users/<id>` as the spec writes it, `/api/v1/users/{id}`
+    fn spec_path(path: &str) -> String
+    {
+        let path: Vec<String> = path
+            .split('/')
+            .map(|segment| match segment.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect();
+
+        match path.join("/").trim_end_matches('/') {
+            "" => "/".to_string(),
+            path => path.to_string(),
+        }
+    }
+
+    #[test]
+    fn every_route_is_documented()
+    {
+        let spec = ApiDoc::openapi();
+        let mut rocket = rocket::build();
+
+        for (base, routes) in super::super::mounts() {
+            rocket = rocket.mount(base, routes);
+        }
+
+        let mut missing = Vec::new();
+
+        for route in rocket.routes() {
+            // CORS preflight answers every path and is not part of the API
+            let method = match item_type(route.method) {
+                Some(method) => method,
+                None => continue,
+            };
+
+            let path = spec_path(route.uri.path().as_str());
+
+            let documented = spec
+                .paths
+                .paths
+                .get(&path)
+                .map(|item| item.operations.contains_key(&method))
+                .unwrap_or(false);
+
+            if !documented {
+                missing.push(format!("{} {}", route.method, path));
+            }
+        }
+
+        assert!(missing.is_empty(), "routes missing from the OpenAPI spec: {:?}", missing);
+    }
+
+    #[test]
+    fn documented_paths_are_mounted()
+    {
+        let mut rocket = rocket::build();
+
+        for (base, routes) in super::super::mounts() {
+            rocket = rocket.mount(base, routes);
+        }
+
+        let mounted: Vec<String> = rocket.routes().map(|route| spec_path(route.uri.path().as_str())).collect();
+
+        for path in ApiDoc::openapi().paths.paths.keys() {
+            assert!(mounted.contains(path), "{} is documented but not mounted", path);
+        }
+    }
+
+    #[test]
+    fn user_management_only_takes_bearer_tokens()
+    {
+        // API keys can never be granted manage-users
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            if !path.starts_with("/api/v1/keys") && !path.starts_with("/api/v1/invitations") {
+                continue;
+            }
+
+            for (method, operation) in item.as_object().unwrap() {
+                let security = operation["security"].to_string();
+                assert!(!security.contains("api_key"), "{} {} advertises API keys", method, path);
+            }
+        }
+    }
+}
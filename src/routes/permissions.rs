@@ -0,0 +1,220 @@
+use std::marker::PhantomData;
+use std::net::IpAddr;
+
+use super::auth::{Claims, TextError};
+use super::errors::{self, ErrorJson};
+use crate::model::types::Permission;
+use crate::model::AnzenDB;
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// Marker for the permission a route requires
+pub trait Scope: Send + Sync + 'static
+{
+    const PERMISSION: Permission;
+}
+
+pub mod scope
+{
+    use super::Scope;
+    use crate::model::types::Permission;
+
+    pub struct ViewStats;
+    pub struct ArmDisarm;
+    pub struct Search;
+    pub struct ManageUsers;
+    pub struct AddEmail;
+    pub struct ViewAudit;
+    pub struct ManageDevices;
+
+    impl Scope for ViewStats
+    {
+        const PERMISSION: Permission = Permission::ViewStats;
+    }
+
+    impl Scope for ArmDisarm
+    {
+        const PERMISSION: Permission = Permission::ArmDisarm;
+    }
+
+    impl Scope for Search
+    {
+        const PERMISSION: Permission = Permission::Search;
+    }
+
+    impl Scope for ManageUsers
+    {
+        const PERMISSION: Permission = Permission::ManageUsers;
+    }
+
+    impl Scope for AddEmail
+    {
+        const PERMISSION: Permission = Permission::AddEmail;
+    }
+
+    impl Scope for ViewAudit
+    {
+        const PERMISSION: Permission = Permission::ViewAudit;
+    }
+
+    impl Scope for ManageDevices
+    {
+        const PERMISSION: Permission = Permission::ManageDevices;
+    }
+}
+
+/// Who an authorized request is acting as
+pub enum Principal
+{
+    User(Claims),
+    ApiKey(ApiKeyAuth),
+}
+
+/// A live API key presented through the `X-API-Key` header
+pub struct ApiKeyAuth
+{
+    pub id: String,
+    pub scopes: Vec<Permission>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKeyAuth
+{
+    type Error = TextError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<ApiKeyAuth, Self::Error>
+    {
+        let failure = Outcome::Failure((
+            Status::Unauthorized,
+            errors::APIError::Unauthorized(ErrorJson::new(errors::MSG_INVALID_API_KEY)),
+        ));
+
+        let key = match request.headers().get_one(API_KEY_HEADER) {
+            Some(key) => key,
+            None => return failure,
+        };
+
+        let db = match request.guard::<&State<AnzenDB>>().await {
+            Outcome::Success(db) => db,
+            _ => return failure,
+        };
+
+        let api_key = match db.find_api_key(key).await {
+            Ok(Some(api_key)) => api_key,
+            _ => return failure,
+        };
+
+        // A key can do no more than the person who made it still can
+        let creator = match db.get_account(&api_key.created_by).await {
+            Ok(creator) if !creator.disabled => creator,
+            _ => return failure,
+        };
+
+        if !api_key.allowed_ips.is_empty() {
+            let allowed = match request.client_ip() {
+                Some(ip) => api_key
+                    .allowed_ips
+                    .iter()
+                    .any(|allowed| allowed.parse::<IpAddr>().map(|allowed| allowed == ip).unwrap_or(false)),
+                None => false,
+            };
+
+            if !allowed {
+                return failure;
+            }
+        }
+
+        Outcome::Success(ApiKeyAuth {
+            id: api_key._id.to_hex(),
+            scopes: api_key
+                .scopes
+                .into_iter()
+                .filter(|scope| creator.role().grants(*scope))
+                .collect(),
+        })
+    }
+}
+
+/// A user or API key that has been granted the permission of `S`
+pub struct Authorized<S: Scope>
+{
+    /// Email of the user, or `key:<id>` for API keys
+    pub sub: String,
+    pub principal: Principal,
+    _scope: PhantomData<S>,
+}
+
+#[rocket::async_trait]
+impl<'r, S: Scope> FromRequest<'r> for Authorized<S>
+{
+    type Error = TextError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error>
+    {
+        let forbidden = Outcome::Failure((
+            Status::Forbidden,
+            errors::APIError::Forbidden(ErrorJson::new(errors::MSG_MISSING_PERMISSION)),
+        ));
+
+        if request.headers().contains(API_KEY_HEADER) {
+            let key = try_outcome!(request.guard::<ApiKeyAuth>().await);
+
+            if !key.scopes.contains(&S::PERMISSION) {
+                return forbidden;
+            }
+
+            return Outcome::Success(Authorized {
+                sub: format!("key:{}", key.id),
+                principal: Principal::ApiKey(key),
+                _scope: PhantomData,
+            });
+        }
+
+        let claims = try_outcome!(request.guard::<Claims>().await);
+
+        let db = match request.guard::<&State<AnzenDB>>().await {
+            Outcome::Success(db) => db,
+            _ => return forbidden,
+        };
+
+        let account = match db.get_account(&claims.sub).await {
+            Ok(account) => account,
+            Err(_) => return forbidden,
+        };
+
+        if account.disabled || !account.role().grants(S::PERMISSION) {
+            return forbidden;
+        }
+
+        if S::PERMISSION == Permission::ArmDisarm && !account.totp_enabled() {
+            let required = match db.security_settings().await {
+                Ok(settings) => settings.require_2fa_arm,
+                Err(_) => true,
+            };
+
+            if required {
+                return Outcome::Failure((
+                    Status::Forbidden,
+                    errors::APIError::Forbidden(ErrorJson::new(errors::MSG_2FA_REQUIRED)),
+                ));
+            }
+        }
+
+        if account.password_reset {
+            return Outcome::Failure((
+                Status::Forbidden,
+                errors::APIError::Forbidden(ErrorJson::new(errors::MSG_PASSWORD_RESET)),
+            ));
+        }
+
+        Outcome::Success(Authorized {
+            sub: claims.sub.clone(),
+            principal: Principal::User(claims),
+            _scope: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,33 @@
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson};
+use super::permissions::{scope, Authorized};
+use super::returns::PluginSummary;
+use crate::config::PluginHealthConfig;
+use crate::model::AnzenDB;
+use rocket::serde::json::Json;
+use rocket::State;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/plugins",
+    operation_id = "plugins_list",
+    tag = "plugins",
+    responses(
+        (status = 200, description = "Plugins with activity and health", body = [PluginSummary]),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/")]
+pub async fn list(
+    auth: Result<Authorized<scope::ViewStats>, TextError>,
+    health: &State<PluginHealthConfig>,
+    db: &State<AnzenDB>,
+) -> Result<Json<Vec<PluginSummary>>, TextError>
+{
+    auth?;
+
+    match db.list_plugins(health.stale_after, health.silent_after).await {
+        Ok(plugins) => Ok(Json(plugins)),
+        Err(_) => Err(APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR))),
+    }
+}
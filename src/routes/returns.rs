@@ -1,14 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use mongodb::bson::Document;
+use mongodb::bson::{Bson, Document};
+use rocket::serde::json::Value;
 use rocket::serde::Serialize;
 
-#[derive(Serialize)]
+use crate::model::types::{Account, ApiKey, AuditEntry, AuditOutcome, Invitation, Permission, Role};
+use utoipa::ToSchema;
+
+/// Command requests carrying details meant only for the plugin acting on
+/// them, viewers just see the request name
+pub const PRIVATE_REQUESTS: &[&str] = &["password-reset"];
+
+#[derive(Serialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct LoginResponse
 {
     pub username: String,
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: u64,
 }
 
 #[derive(Serialize)]
@@ -21,7 +31,135 @@ pub struct UserDetails
     pub email: String,
 }
 
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct MfaChallenge
+{
+    pub mfa_required: bool,
+    pub challenge: String,
+    pub expires_in: u64,
+}
+
 #[derive(Serialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum LoginResult
+{
+    Tokens(LoginResponse),
+    Challenge(MfaChallenge),
+}
+
+/// User record safe to hand out, never carries the hash or salt
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct UserSummary
+{
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub created: String,
+    pub level: u8,
+    pub role: Role,
+    pub disabled: bool,
+    pub password_reset: bool,
+    pub totp_enabled: bool,
+}
+
+impl From<Account> for UserSummary
+{
+    fn from(account: Account) -> Self
+    {
+        UserSummary {
+            id: account._id.to_hex(),
+            role: account.role(),
+            totp_enabled: account.totp_enabled(),
+            created: account.created.try_to_rfc3339_string().unwrap_or_default(),
+            username: account.username,
+            email: account.email,
+            level: account.level,
+            disabled: account.disabled,
+            password_reset: account.password_reset,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct UserList
+{
+    pub users: Vec<UserSummary>,
+    pub page: u64,
+    pub per_page: i64,
+    pub total: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct InvitationSummary
+{
+    pub id: String,
+    pub email: String,
+    pub role: Role,
+    pub created_by: String,
+    pub created: String,
+    pub expires: String,
+    pub used: bool,
+    pub revoked: bool,
+}
+
+impl From<Invitation> for InvitationSummary
+{
+    fn from(invitation: Invitation) -> Self
+    {
+        InvitationSummary {
+            id: invitation._id.to_hex(),
+            created: invitation.created.try_to_rfc3339_string().unwrap_or_default(),
+            expires: invitation.expires.try_to_rfc3339_string().unwrap_or_default(),
+            email: invitation.email,
+            role: invitation.role,
+            created_by: invitation.created_by,
+            used: invitation.used,
+            revoked: invitation.revoked,
+        }
+    }
+}
+
+/// API key details, the key itself is only returned when it is created
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiKeySummary
+{
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Permission>,
+    pub allowed_ips: Vec<String>,
+    pub expires: Option<String>,
+    pub created_by: String,
+    pub created: String,
+    pub last_used: Option<String>,
+    pub revoked: bool,
+}
+
+impl From<ApiKey> for ApiKeySummary
+{
+    fn from(key: ApiKey) -> Self
+    {
+        ApiKeySummary {
+            id: key._id.to_hex(),
+            expires: key.expires.and_then(|date| date.try_to_rfc3339_string().ok()),
+            created: key.created.try_to_rfc3339_string().unwrap_or_default(),
+            last_used: key.last_used.and_then(|date| date.try_to_rfc3339_string().ok()),
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes,
+            allowed_ips: key.allowed_ips,
+            created_by: key.created_by,
+            revoked: key.revoked,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct RegisterResponse
 {
@@ -35,7 +173,7 @@ pub struct StatsResponse
     pub ok: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct CoreStatus
 {
@@ -43,10 +181,309 @@ pub struct CoreStatus
     pub store: HashMap<String, String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct CursorPage
+{
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct SearchPages
+{
+    pub events: CursorPage,
+    pub commands: CursorPage,
+}
+
+#[derive(Serialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct EventCommandN
 {
-    pub events: Vec<Document>,
-    pub commands: Vec<Document>,
+    pub events: Vec<EventView>,
+    pub commands: Vec<CommandView>,
+}
+
+/// The device or plugin a record came from. Only the fields clients need
+/// are copied out of the joined document.
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct SourceRef
+{
+    pub id: String,
+    pub name: Option<String>,
+}
+
+impl SourceRef
+{
+    /// Uses the joined document when the pipeline looked it up, otherwise just the id
+    fn from_lookup(doc: &Document, joined: &str, id_field: &str) -> Option<SourceRef>
+    {
+        if let Ok(source) = doc.get_document(joined) {
+            return Some(SourceRef {
+                id: source.get_object_id("_id").ok()?.to_hex(),
+                name: ["name", "id"]
+                    .iter()
+                    .find_map(|field| source.get_str(field).ok())
+                    .map(|name| name.to_string()),
+            });
+        }
+
+        let metadata = doc.get_document("metadata").ok()?;
+
+        Some(SourceRef {
+            id: metadata.get_object_id(id_field).ok()?.to_hex(),
+            name: None,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct EventView
+{
+    pub id: String,
+    pub timestamp: String,
+    pub armed: Option<bool>,
+    pub device: Option<SourceRef>,
+    pub plugin: Option<SourceRef>,
+    #[schema(value_type = Object)]
+    pub readings: BTreeMap<String, Value>,
+}
+
+impl EventView
+{
+    pub fn from_document(doc: &Document) -> Option<EventView>
+    {
+        Some(EventView {
+            id: doc.get_object_id("_id").ok()?.to_hex(),
+            timestamp: doc.get_datetime("timestamp").ok()?.try_to_rfc3339_string().ok()?,
+            armed: doc.get_document("metadata").ok().and_then(|metadata| metadata.get_bool("armed").ok()),
+            device: SourceRef::from_lookup(doc, "device", "device_id"),
+            plugin: SourceRef::from_lookup(doc, "plugin", "plugin_id"),
+            readings: doc.get_document("data").map(readings).unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct CommandView
+{
+    pub id: String,
+    pub timestamp: String,
+    pub command_type: Option<i64>,
+    pub origin: Option<String>,
+    pub arm_status: Option<i64>,
+    pub data: Option<String>,
+    pub plugin: Option<SourceRef>,
+}
+
+impl CommandView
+{
+    pub fn from_document(doc: &Document) -> Option<CommandView>
+    {
+        let field = |name: &str| doc.get(name).or_else(|| {
+            doc.get_document("metadata").ok().and_then(|metadata| metadata.get(name))
+        });
+
+        Some(CommandView {
+            id: doc.get_object_id("_id").ok()?.to_hex(),
+            timestamp: doc.get_datetime("timestamp").ok()?.try_to_rfc3339_string().ok()?,
+            command_type: field("command_type").and_then(as_i64),
+            origin: field("origin").and_then(Bson::as_str).map(|origin| origin.to_string()),
+            arm_status: field("arm_status").and_then(as_i64),
+            data: field("data").and_then(Bson::as_str).map(redact_command),
+            plugin: SourceRef::from_lookup(doc, "plugin", "plugin_id"),
+        })
+    }
+}
+
+/// Event counts in one bucket of a chart
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct BucketCount
+{
+    pub date: String,
+    pub armed: bool,
+    pub count: i64,
+}
+
+impl BucketCount
+{
+    pub fn from_document(doc: &Document) -> Option<BucketCount>
+    {
+        Some(BucketCount {
+            date: doc.get_datetime("date").ok()?.try_to_rfc3339_string().ok()?,
+            armed: doc.get_bool("armed").ok()?,
+            count: doc.get("count").and_then(as_i64).unwrap_or(0),
+        })
+    }
+}
+
+/// How often one reading was reported in a bucket and its average values
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ReadingStats
+{
+    pub date: String,
+    pub reading: String,
+    pub total_occurences: i64,
+    pub float_avg: Option<f64>,
+    pub int_avg: Option<f64>,
+    pub binary_avg: Option<f64>,
+}
+
+impl ReadingStats
+{
+    pub fn from_document(doc: &Document) -> Option<ReadingStats>
+    {
+        let id = doc.get_document("_id").ok()?;
+        let avg = |field: &str| doc.get(field).and_then(|avg| avg.as_f64().or_else(|| as_i64(avg).map(|avg| avg as f64)));
+
+        Some(ReadingStats {
+            date: id.get_datetime("date").ok()?.try_to_rfc3339_string().ok()?,
+            reading: id.get_str("data").ok()?.to_string(),
+            total_occurences: doc.get("total_occurences").and_then(as_i64).unwrap_or(0),
+            float_avg: avg("float_avg"),
+            int_avg: avg("int_avg"),
+            binary_avg: avg("binary_avg"),
+        })
+    }
+}
+
+/// Replaces the data of private requests with just their name
+fn redact_command(data: &str) -> String
+{
+    let request = rocket::serde::json::from_str::<Value>(data)
+        .ok()
+        .and_then(|value| value.get("request")?.as_str().map(|request| request.to_string()));
+
+    match request {
+        Some(request) if PRIVATE_REQUESTS.contains(&request.as_str()) => {
+            rocket::serde::json::json!({ "request": request }).to_string()
+        }
+        _ => data.to_string(),
+    }
+}
+
+fn as_i64(value: &Bson) -> Option<i64>
+{
+    match value {
+        Bson::Int32(value) => Some(*value as i64),
+        Bson::Int64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Flattens each reading to the value it carries, whatever its type
+pub fn readings(data: &Document) -> BTreeMap<String, Value>
+{
+    data.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Bson::Document(reading) => reading
+                    .iter()
+                    .find(|(_, value)| !matches!(value, Bson::Null))
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or(Bson::Null),
+                value => value.clone(),
+            };
+
+            (key.clone(), value.into_relaxed_extjson())
+        })
+        .collect()
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditRecord
+{
+    pub id: String,
+    pub timestamp: String,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub outcome: AuditOutcome,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl From<AuditEntry> for AuditRecord
+{
+    fn from(entry: AuditEntry) -> Self
+    {
+        AuditRecord {
+            id: entry._id.to_hex(),
+            timestamp: entry.timestamp.try_to_rfc3339_string().unwrap_or_default(),
+            actor: entry.actor,
+            action: entry.action,
+            target: entry.target,
+            outcome: entry.outcome,
+            ip: entry.ip,
+            user_agent: entry.user_agent,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditLog
+{
+    pub entries: Vec<AuditRecord>,
+    pub count: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceSummary
+{
+    pub id: String,
+    /// Identifier the owning plugin reports the device by
+    pub device_id: Option<String>,
+    pub name: Option<String>,
+    pub zone: Option<String>,
+    pub ignored: bool,
+    pub plugin_id: Option<String>,
+    pub plugin: Option<String>,
+    pub last_seen: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    pub latest: Option<BTreeMap<String, Value>>,
+    /// Events over the last 30 days
+    pub event_count: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceList
+{
+    pub devices: Vec<DeviceSummary>,
+    pub page: u64,
+    pub per_page: i64,
+    pub total: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum PluginHealth
+{
+    Healthy,
+    Stale,
+    Silent,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct PluginSummary
+{
+    pub id: String,
+    pub name: Option<String>,
+    pub plugin_type: Option<String>,
+    pub last_activity: Option<String>,
+    pub events_last_hour: i64,
+    pub events_last_day: i64,
+    pub commands_last_hour: i64,
+    pub commands_last_day: i64,
+    pub health: PluginHealth,
 }
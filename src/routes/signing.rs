@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use crate::config::{JwtConfig, JwtKey};
+use crate::ResultT;
+use super::state;
+use jsonwebtoken::{decode_header, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::serde::json::{serde_json::json, Value};
+use rocket::State;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{PublicKeyParts, RsaPublicKey};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Kid given to the shared secret, also used for tokens issued without one
+const SECRET_KID: &str = "hs256";
+
+/// What a token is for, carried in its `typ` header so one kind can never
+/// be accepted as another. Only access tokens are meant for other services.
+#[derive(Clone, Copy)]
+pub enum TokenType
+{
+    Access,
+    Challenge,
+    Invite,
+}
+
+impl TokenType
+{
+    fn typ(self) -> &'static str
+    {
+        match self {
+            TokenType::Access => "at+jwt",
+            TokenType::Challenge => "anzen-2fa+jwt",
+            TokenType::Invite => "anzen-invite+jwt",
+        }
+    }
+}
+
+struct Verifier
+{
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Signs tokens with the active key and verifies them against every key
+/// still configured, so tokens survive a rotation until they expire
+pub struct Keyring
+{
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    verifiers: HashMap<String, Verifier>,
+    /// Public halves of the asymmetric keys, published as a JWKS
+    jwks: Vec<Value>,
+}
+
+impl Keyring
+{
+    pub fn init(secret: Option<String>, config: JwtConfig) -> ResultT<Keyring>
+    {
+        let mut verifiers = HashMap::new();
+        let mut jwks = Vec::new();
+        let mut active = None;
+
+        for key in &config.keys {
+            let public = std::fs::read(&key.public_key)?;
+
+            let (decoding, jwk) = match key.algorithm {
+                Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+                | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+                    (DecodingKey::from_rsa_pem(&public)?, rsa_jwk(key, &public)?)
+                }
+                Algorithm::EdDSA => (DecodingKey::from_ed_pem(&public)?, ed_jwk(key, &public)?),
+                _ => return Err(format!("Unsupported algorithm for key {}", key.kid).into()),
+            };
+
+            verifiers.insert(key.kid.clone(), Verifier {
+                algorithm: key.algorithm,
+                key: decoding,
+            });
+            jwks.push(jwk);
+
+            if config.active_kid.as_ref() == Some(&key.kid) {
+                let path = match &key.private_key {
+                    Some(path) => path,
+                    None => return Err(format!("Active key {} has no private key", key.kid).into()),
+                };
+
+                let private = std::fs::read(path)?;
+
+                let encoding = match key.algorithm {
+                    Algorithm::EdDSA => EncodingKey::from_ed_pem(&private)?,
+                    _ => EncodingKey::from_rsa_pem(&private)?,
+                };
+
+                active = Some((key.kid.clone(), key.algorithm, encoding));
+            }
+        }
+
+        if let Some(secret) = &secret {
+            verifiers.insert(SECRET_KID.to_string(), Verifier {
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            });
+        }
+
+        let (kid, algorithm, encoding) = match (active, config.active_kid, secret) {
+            (Some(active), _, _) => active,
+            (None, Some(kid), _) => return Err(format!("Active key {} is not configured", kid).into()),
+            (None, None, Some(secret)) => (
+                SECRET_KID.to_string(),
+                Algorithm::HS256,
+                EncodingKey::from_secret(secret.as_bytes()),
+            ),
+            (None, None, None) => return Err("No JWT signing key configured".into()),
+        };
+
+        Ok(Keyring {
+            kid,
+            algorithm,
+            encoding,
+            verifiers,
+            jwks,
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T, token_type: TokenType) -> jsonwebtoken::errors::Result<String>
+    {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        header.typ = Some(token_type.typ().to_string());
+
+        jsonwebtoken::encode(&header, claims, &self.encoding)
+    }
+
+    /// Verifies a token of the given type with the key named by its `kid`.
+    /// The algorithm comes from our own key config, never from the token.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str, token_type: TokenType) -> Option<T>
+    {
+        let header = decode_header(token).ok()?;
+
+        if header.typ.as_deref() != Some(token_type.typ()) {
+            return None;
+        }
+        let kid = header.kid.unwrap_or_else(|| SECRET_KID.to_string());
+        let verifier = self.verifiers.get(&kid)?;
+
+        jsonwebtoken::decode::<T>(token, &verifier.key, &Validation::new(verifier.algorithm))
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+fn rsa_jwk(key: &JwtKey, pem: &[u8]) -> ResultT<Value>
+{
+    let public = RsaPublicKey::from_public_key_pem(std::str::from_utf8(pem)?)?;
+
+    Ok(json!({
+        "kty": "RSA",
+        "use": "sig",
+        "kid": key.kid,
+        "alg": key.algorithm,
+        "n": base64::encode_config(public.n().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+        "e": base64::encode_config(public.e().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+    }))
+}
+
+fn ed_jwk(key: &JwtKey, pem: &[u8]) -> ResultT<Value>
+{
+    let body: String = std::str::from_utf8(pem)?
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    // An Ed25519 SubjectPublicKeyInfo ends with the 32 byte raw key
+    let der = base64::decode(body)?;
+    if der.len() < 32 {
+        return Err(format!("Invalid Ed25519 public key for {}", key.kid).into());
+    }
+
+    Ok(json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "use": "sig",
+        "kid": key.kid,
+        "alg": key.algorithm,
+        "x": base64::encode_config(&der[der.len() - 32..], base64::URL_SAFE_NO_PAD),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    operation_id = "signing_jwks",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Public keys that verify access tokens"),
+    )
+)]
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(state: &State<state::Validation>) -> Value
+{
+    json!({ "keys": state.keys.jwks })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::config::JwtConfig;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct TestClaims
+    {
+        sub: String,
+        exp: usize,
+    }
+
+    #[test]
+    fn tokens_are_only_accepted_as_their_own_type()
+    {
+        let keys = Keyring::init(Some("test-secret".into()), JwtConfig::default()).unwrap();
+        let claims = TestClaims {
+            sub: "admin@anzen.test".into(),
+            exp: (chrono::Utc::now().timestamp() + 60) as usize,
+        };
+
+        let challenge = keys.encode(&claims, TokenType::Challenge).unwrap();
+
+        assert!(keys.decode::<TestClaims>(&challenge, TokenType::Challenge).is_some());
+        assert!(keys.decode::<TestClaims>(&challenge, TokenType::Access).is_none());
+        assert!(keys.decode::<TestClaims>(&challenge, TokenType::Invite).is_none());
+    }
+}
@@ -1,68 +1,749 @@
 use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use anzen_lib::anzen;
-use anzen_lib::client::ClientRef;
+use anzen_lib::client::{ClientRef, PluginData};
 
+use crate::config::CoreConnection;
+use crate::model::types::AuditOutcome;
+use crate::model::AnzenDB;
+use super::audit::AuditContext;
+use super::helpers;
+use super::signing::Keyring;
 use crate::ResultT;
 
+use rand::Rng;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{PaddingScheme, PublicKey, RsaPublicKey};
 use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{error::Elapsed, timeout, MissedTickBehavior};
+
+/// How often core is asked for the arm state while anyone is streaming it,
+/// other plugins and API instances can change it without this one hearing
+const ARMED_POLL: Duration = Duration::from_secs(10);
 
 pub struct Validation
 {
-    pub key: Arc<String>,
+    pub keys: Arc<Keyring>,
+    /// Emails that may register without an invitation, used to set up the
+    /// first accounts
     pub allowed_emails: Arc<HashSet<String>>,
+    pub admin_emails: Arc<HashSet<String>>,
+    pub access_ttl: u64,
+    pub refresh_ttl: u64,
+    pub reset_ttl: u64,
+    pub invite_ttl: u64,
+    db: AnzenDB,
 }
 
 impl Validation
 {
-    pub fn init(key: String, allowed: HashSet<String>) -> Validation
+    pub fn init(
+        keys: Keyring,
+        allowed: HashSet<String>,
+        admins: HashSet<String>,
+        access_ttl: u64,
+        refresh_ttl: u64,
+        reset_ttl: u64,
+        invite_ttl: u64,
+        db: AnzenDB,
+    ) -> Validation
     {
         Validation {
-            key: Arc::new(key),
+            keys: Arc::new(keys),
             allowed_emails: Arc::new(allowed),
+            admin_emails: Arc::new(admins),
+            access_ttl,
+            refresh_ttl,
+            reset_ttl,
+            invite_ttl,
+            db,
         }
     }
 
+    /// Accounts may log in until they are disabled or deleted
     pub async fn email_allowed(&self, name: &String) -> bool
+    {
+        match self.db.get_account(name).await {
+            Ok(account) => !account.disabled,
+            Err(_) => false,
+        }
+    }
+
+    pub fn bootstrap_allowed(&self, name: &String) -> bool
     {
         self.allowed_emails.get(name).is_some()
     }
+
+    pub fn is_admin(&self, name: &String) -> bool
+    {
+        self.admin_emails.get(name).is_some()
+    }
 }
 
-pub struct CoreAPI
+struct Attempts
+{
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks failed logins per email and per client address, locking either
+/// out with exponential backoff once it passes its threshold
+pub struct LoginThrottle
+{
+    max_attempts: u32,
+    max_attempts_ip: u32,
+    lockout: Duration,
+    max_lockout: Duration,
+    emails: Mutex<HashMap<String, Attempts>>,
+    ips: Mutex<HashMap<IpAddr, Attempts>>,
+}
+
+impl LoginThrottle
+{
+    pub fn init(max_attempts: u32, max_attempts_ip: u32, lockout: u64, max_lockout: u64) -> LoginThrottle
+    {
+        LoginThrottle {
+            max_attempts,
+            max_attempts_ip,
+            lockout: Duration::from_secs(lockout),
+            max_lockout: Duration::from_secs(max_lockout),
+            emails: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Seconds until the email or address may try again, if locked out
+    pub async fn locked(&self, email: &String, ip: Option<IpAddr>) -> Option<u64>
+    {
+        let now = Instant::now();
+
+        let email_wait = remaining(self.emails.lock().await.get(email), now);
+        let ip_wait = match ip {
+            Some(ip) => remaining(self.ips.lock().await.get(&ip), now),
+            None => None,
+        };
+
+        email_wait.max(ip_wait)
+    }
+
+    /// Records a failed login. Returns the lockout in seconds when this
+    /// failure caused one.
+    pub async fn failure(&self, email: &String, ip: Option<IpAddr>) -> Option<u64>
+    {
+        let email_lock = self.record(&self.emails, email.clone(), self.max_attempts).await;
+        let ip_lock = match ip {
+            Some(ip) => self.record(&self.ips, ip, self.max_attempts_ip).await,
+            None => None,
+        };
+
+        email_lock.max(ip_lock)
+    }
+
+    /// Only clears the email, otherwise logging in to an account of your own
+    /// between guesses would reset the address limit
+    pub async fn success(&self, email: &String)
+    {
+        self.emails.lock().await.remove(email);
+    }
+
+    pub async fn unlock(&self, email: &String) -> bool
+    {
+        self.emails.lock().await.remove(email).is_some()
+    }
+
+    async fn record<K: Eq + Hash>(
+        &self,
+        map: &Mutex<HashMap<K, Attempts>>,
+        key: K,
+        max: u32,
+    ) -> Option<u64>
+    {
+        let now = Instant::now();
+        let mut map = map.lock().await;
+
+        // Forget failures once they are older than the longest lockout
+        map.retain(|_, attempts| now.duration_since(attempts.last_failure) < self.max_lockout);
+
+        let attempts = map.entry(key).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+
+        attempts.failures += 1;
+        attempts.last_failure = now;
+
+        if attempts.failures < max {
+            return None;
+        }
+
+        let exponent = (attempts.failures - max).min(16);
+        let lockout = self
+            .lockout
+            .saturating_mul(2u32.pow(exponent))
+            .min(self.max_lockout);
+
+        attempts.locked_until = Some(now + lockout);
+
+        Some(lockout.as_secs().max(1))
+    }
+}
+
+/// Password reset requests per email and address, kept apart from failed
+/// logins so asking for resets cannot lock anyone out of logging in
+pub struct ResetThrottle(pub LoginThrottle);
+
+/// Public key of the mail plugin. Reset tokens are encrypted to it so that
+/// neither core nor the database ever holds one in plaintext.
+pub struct ResetMailer
+{
+    key: Option<RsaPublicKey>,
+}
+
+impl ResetMailer
+{
+    /// Without a key password resets are turned off
+    pub fn init(path: Option<&str>) -> ResultT<ResetMailer>
+    {
+        let key = match path {
+            Some(path) => Some(RsaPublicKey::from_public_key_pem(&std::fs::read_to_string(path)?)?),
+            None => None,
+        };
+
+        Ok(ResetMailer { key })
+    }
+
+    pub fn enabled(&self) -> bool
+    {
+        self.key.is_some()
+    }
+
+    /// RSA-OAEP with SHA-256, base64 encoded
+    pub fn seal(&self, token: &str) -> ResultT<String>
+    {
+        let key = self.key.as_ref().ok_or("password resets are not configured")?;
+        let sealed = key.encrypt(&mut rand::thread_rng(), PaddingScheme::new_oaep::<Sha256>(), token.as_bytes())?;
+
+        Ok(base64::encode(sealed))
+    }
+}
+
+fn remaining(attempts: Option<&Attempts>, now: Instant) -> Option<u64>
+{
+    match attempts.and_then(|attempts| attempts.locked_until) {
+        Some(until) if until > now => Some((until - now).as_secs().max(1)),
+        _ => None,
+    }
+}
+
+/// Settings and the scheduled arm shared by the arm and disarm routes
+pub struct ArmControl
+{
+    pin: Option<String>,
+    pub max_delay: u64,
+    /// Wrong PINs per actor and address
+    pub pin_attempts: LoginThrottle,
+    /// A delayed arm waiting for its grace period to run out
+    pending: Mutex<Option<JoinHandle<()>>>,
+    /// Held while asking core for a new state, so a toggle reads and flips
+    /// the state without another change landing in between
+    changing: Arc<Mutex<()>>,
+}
+
+impl ArmControl
+{
+    pub fn init(pin: Option<String>, max_delay: u64, pin_attempts: LoginThrottle) -> ArmControl
+    {
+        ArmControl {
+            pin,
+            max_delay,
+            pin_attempts,
+            pending: Mutex::new(None),
+            changing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn pin_required(&self) -> bool
+    {
+        self.pin.is_some()
+    }
+
+    /// Without a configured PIN any value is accepted
+    pub fn pin_valid(&self, pin: Option<&str>) -> bool
+    {
+        match (&self.pin, pin) {
+            (None, _) => true,
+            (Some(expected), Some(pin)) => helpers::constant_eq(expected.as_bytes(), pin.as_bytes()),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Arms once the delay has passed, replacing any earlier scheduled arm.
+    /// The outcome is audited against the request that scheduled it.
+    pub async fn schedule(
+        &self,
+        core_api: CoreAPI,
+        db: AnzenDB,
+        audit: AuditContext,
+        delay: u64,
+        actor: String,
+        reason: Option<String>,
+    ) -> SystemTime
+    {
+        let at = SystemTime::now() + Duration::from_secs(delay);
+        let changing = self.changing.clone();
+
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+
+            let armed = {
+                let _changing = changing.lock().await;
+                core_api.set_armed(true, &actor, reason.as_deref()).await.is_ok()
+            };
+
+            let outcome = match armed {
+                true => AuditOutcome::Success,
+                false => AuditOutcome::Failure,
+            };
+
+            audit.record(&db, &actor, "alarm.arm.delayed", None, outcome).await;
+        });
+
+        if let Some(previous) = self.pending.lock().await.replace(task) {
+            previous.abort();
+        }
+
+        at
+    }
+
+    /// Asks core for an explicit state, one change at a time
+    pub async fn set(&self, core_api: &CoreAPI, armed: bool, actor: &str, reason: Option<&str>) -> ResultT<bool>
+    {
+        let _changing = self.changing.lock().await;
+
+        core_api.set_armed(armed, actor, reason).await
+    }
+
+    /// Flips the state core reports, holding off other changes until core
+    /// has answered
+    pub async fn toggle(&self, core_api: &CoreAPI, actor: &str) -> ResultT<bool>
+    {
+        let _changing = self.changing.lock().await;
+
+        let armed = !core_api.get_stats().await?.armed;
+
+        core_api.set_armed(armed, actor, None).await
+    }
+
+    /// Cancels a scheduled arm, returning whether one was waiting
+    pub async fn cancel(&self) -> bool
+    {
+        match self.pending.lock().await.take() {
+            Some(task) => {
+                let waiting = !task.is_finished();
+                task.abort();
+                waiting
+            }
+            None => false,
+        }
+    }
+}
+
+/// Stops calling core after repeated failures, so requests fail fast instead
+/// of each waiting out its deadline. After the cooldown a single probe is let
+/// through, its outcome closes the breaker or opens it for another cooldown.
+struct CircuitBreaker
+{
+    threshold: u32,
+    cooldown: Duration,
+    state: std::sync::Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState
+{
+    failures: u32,
+    open_until: Option<Instant>,
+    /// When the probe in flight was let through, a probe whose caller went
+    /// away without an outcome is replaced after a cooldown
+    probe_since: Option<Instant>,
+}
+
+impl CircuitBreaker
+{
+    fn allow(&self) -> bool
+    {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        match (state.open_until, state.probe_since) {
+            (None, _) => true,
+            (Some(until), _) if now < until => false,
+            (Some(_), Some(since)) if now < since + self.cooldown => false,
+            (Some(_), _) => {
+                state.probe_since = Some(now);
+                true
+            }
+        }
+    }
+
+    fn success(&self)
+    {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    fn failure(&self)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        state.probe_since = None;
+
+        if state.failures >= self.threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// Exponential backoff with up to half again of random jitter
+fn backoff(attempt: u32, max: Duration) -> Duration
+{
+    let base = Duration::from_millis(250).saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    let jitter = rand::thread_rng().gen_range(0..=base.as_millis() as u64 / 2);
+
+    base + Duration::from_millis(jitter)
+}
+
+/// Client and token from the last successful registration
+#[derive(Clone)]
+struct CoreSession
 {
-    token: Arc<String>,
-    name: Arc<String>,
     client: ClientRef,
+    token: String,
+}
+
+/// Ways a call to core can fail, only an expired token is worth retrying
+enum CallError
+{
+    Unauthenticated,
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[derive(Clone)]
+pub struct CoreAPI
+{
+    connection: Arc<CoreConnection>,
+    session: Arc<RwLock<Option<CoreSession>>>,
+    /// Held while registering so concurrent callers do not all re-register
+    registering: Arc<Mutex<()>>,
+    breaker: Arc<CircuitBreaker>,
+    /// Arm state changes, for live subscribers
+    armed: broadcast::Sender<bool>,
+    /// Last arm state core reported, so new subscribers start with it
+    last_armed: Arc<std::sync::Mutex<Option<bool>>>,
+    /// Set once the shared arm state poll has been started
+    polling: Arc<AtomicBool>,
 }
 
 impl CoreAPI
 {
-    pub fn init(token: String, client: ClientRef, name: String) -> CoreAPI
+    /// Starts without a session, `register` has to succeed before calls can
+    pub fn init(connection: CoreConnection) -> CoreAPI
     {
+        let (armed, _) = broadcast::channel(16);
+
         CoreAPI {
-            token: Arc::new(token),
-            name: Arc::new(name),
-            client,
+            breaker: Arc::new(CircuitBreaker {
+                threshold: connection.core_breaker_threshold,
+                cooldown: Duration::from_secs(connection.core_breaker_cooldown),
+                state: std::sync::Mutex::new(BreakerState::default()),
+            }),
+            connection: Arc::new(connection),
+            session: Arc::new(RwLock::new(None)),
+            registering: Arc::new(Mutex::new(())),
+            armed,
+            last_armed: Arc::new(std::sync::Mutex::new(None)),
+            polling: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub async fn get_stats(&self) -> ResultT<anzen::InfoResponse>
+    /// The last known arm state and its changes from now on. Every
+    /// subscriber shares one poll of core.
+    pub fn subscribe_armed(&self) -> (Option<bool>, broadcast::Receiver<bool>)
+    {
+        let receiver = self.armed.subscribe();
+        self.poll_armed_in_background();
+
+        (*self.last_armed.lock().unwrap(), receiver)
+    }
+
+    fn publish_armed(&self, armed: bool)
+    {
+        *self.last_armed.lock().unwrap() = Some(armed);
+
+        // Nobody listening is not an error
+        let _ = self.armed.send(armed);
+    }
+
+    /// Starts polling core for the arm state, once
+    fn poll_armed_in_background(&self)
     {
-        let mut req = tonic::Request::new(anzen::InfoRequest {});
+        if self.polling.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let core_api = self.clone();
+
+        tokio::spawn(async move {
+            let mut poll = tokio::time::interval(ARMED_POLL);
+            poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                poll.tick().await;
+
+                if core_api.armed.receiver_count() == 0 {
+                    continue;
+                }
+
+                let armed = match core_api.get_stats().await {
+                    Ok(stats) => stats.armed,
+                    Err(_) => continue,
+                };
+
+                // Polling mostly finds the same state
+                if *core_api.last_armed.lock().unwrap() != Some(armed) {
+                    core_api.publish_armed(armed);
+                }
+            }
+        });
+    }
+
+    pub async fn connected(&self) -> bool
+    {
+        self.session.read().await.is_some()
+    }
+
+    /// Registers with core once, returning the plugin options it sent. A
+    /// failure counts towards the breaker like any other failed call.
+    pub async fn register(&self) -> ResultT<String>
+    {
+        let register_data = PluginData {
+            name: self.connection.plugin_name.clone(),
+            login_key: self.connection.login_key.clone(),
+            plugin_type: anzen::PluginType::Output,
+            server_socket: self.connection.core_endpoint.clone(),
+        };
+
+        let deadline = Duration::from_secs(self.connection.core_timeout);
+
+        let (client, resp) = match timeout(deadline, anzen_lib::client::register(&register_data)).await {
+            Ok(Ok(registered)) => registered,
+            Ok(Err(e)) => {
+                self.breaker.failure();
+                return Err(format!("registration with core failed: {:?}", e).into());
+            }
+            Err(_) => {
+                self.breaker.failure();
+                return Err("registration with core timed out".into());
+            }
+        };
+
+        let token = anzen_lib::client::get_login_key(&resp.token);
+
+        *self.session.write().await = Some(CoreSession { client, token });
+        self.breaker.success();
+
+        Ok(resp.plugin_opts)
+    }
+
+    /// Keeps registering with backoff, giving up after `attempts` if given
+    pub async fn register_retrying(&self, attempts: Option<u32>) -> ResultT<String>
+    {
+        let max = Duration::from_secs(self.connection.core_retry_max);
+        let mut attempt = 0;
+
+        loop {
+            // Other registrations may go ahead while this one waits
+            let error = {
+                let _registering = self.registering.lock().await;
+
+                match self.register().await {
+                    Ok(opts) => return Ok(opts),
+                    Err(e) => e.to_string(),
+                }
+            };
+
+            attempt += 1;
+
+            if attempts.map(|attempts| attempt >= attempts).unwrap_or(false) {
+                return Err(error.into());
+            }
+
+            // Startup registers before rocket is built and has set up logging
+            let wait = backoff(attempt, max);
+            eprintln!("{}, retrying in {:.1}s", error, wait.as_secs_f32());
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Registers again in the background until core answers, unless a
+    /// request got there first
+    pub fn reconnect_in_background(&self)
+    {
+        let core_api = self.clone();
+
+        tokio::spawn(async move {
+            let max = Duration::from_secs(core_api.connection.core_retry_max);
+            let mut attempt = 0;
+
+            loop {
+                let error = {
+                    let _registering = core_api.registering.lock().await;
+
+                    if core_api.connected().await {
+                        return;
+                    }
+
+                    match core_api.register().await {
+                        Ok(_) => return,
+                        Err(e) => e.to_string(),
+                    }
+                };
+
+                attempt += 1;
+
+                let wait = backoff(attempt, max);
+                warn!("{}, retrying in {:.1}s", error, wait.as_secs_f32());
+                tokio::time::sleep(wait).await;
+            }
+        });
+    }
+
+    /// Registers again after the token was refused, unless another caller
+    /// already replaced the session that failed
+    async fn reregister(&self, failed: &CoreSession) -> Result<CoreSession, CallError>
+    {
+        let _registering = self.registering.lock().await;
+
+        if let Some(session) = self.session.read().await.as_ref() {
+            if session.token != failed.token {
+                return Ok(session.clone());
+            }
+        }
+
+        self.register().await.map_err(CallError::Other)?;
+
+        match self.session.read().await.clone() {
+            Some(session) => Ok(session),
+            None => Err(CallError::Other("no session after registering".into())),
+        }
+    }
+
+    async fn session(&self) -> ResultT<CoreSession>
+    {
+        if !self.breaker.allow() {
+            return Err("core is unavailable".into());
+        }
+
+        if let Some(session) = self.session.read().await.clone() {
+            return Ok(session);
+        }
+
+        let _registering = self.registering.lock().await;
+
+        if let Some(session) = self.session.read().await.clone() {
+            return Ok(session);
+        }
+
+        // A failed registration counts towards the breaker in `register`
+        self.register().await?;
+
+        match self.session.read().await.clone() {
+            Some(session) => Ok(session),
+            None => Err("core is unavailable".into()),
+        }
+    }
+
+    fn request<T>(&self, session: &CoreSession, message: T) -> tonic::Request<T>
+    {
+        let mut req = tonic::Request::new(message);
+        req.set_timeout(Duration::from_secs(self.connection.core_timeout));
 
         anzen_lib::client::insert_authorization(
             &mut req,
-            self.token.to_string(),
-            self.name.to_string(),
+            session.token.clone(),
+            self.connection.plugin_name.clone(),
         );
 
-        let mut client = self.client.lock().await;
+        req
+    }
+
+    /// Maps a finished call, counting failures towards the breaker
+    fn outcome<T>(
+        &self,
+        result: Result<Result<tonic::Response<T>, tonic::Status>, Elapsed>,
+    ) -> Result<T, CallError>
+    {
+        match result {
+            Ok(Ok(resp)) => {
+                self.breaker.success();
+                Ok(resp.into_inner())
+            }
+            Ok(Err(status)) if status.code() == tonic::Code::Unauthenticated => Err(CallError::Unauthenticated),
+            Ok(Err(status)) => {
+                self.breaker.failure();
+                Err(CallError::Other(Box::new(status)))
+            }
+            Err(elapsed) => {
+                self.breaker.failure();
+                Err(CallError::Other(Box::new(elapsed)))
+            }
+        }
+    }
+
+    async fn info_once(&self, session: &CoreSession) -> Result<anzen::InfoResponse, CallError>
+    {
+        let req = self.request(session, anzen::InfoRequest {});
+        let deadline = Duration::from_secs(self.connection.core_timeout);
+
+        let result = timeout(deadline, async {
+            let mut client = session.client.lock().await;
+            client.info(req).await
+        })
+        .await;
+
+        self.outcome(result)
+    }
+
+    pub async fn get_stats(&self) -> ResultT<anzen::InfoResponse>
+    {
+        let session = self.session().await?;
+
+        match self.info_once(&session).await {
+            Ok(data) => return Ok(data),
+            Err(CallError::Other(e)) => return Err(e),
+            Err(CallError::Unauthenticated) => (),
+        }
 
-        let data = client.info(req).await?;
+        let session = match self.reregister(&session).await {
+            Ok(session) => session,
+            Err(CallError::Other(e)) => return Err(e),
+            Err(CallError::Unauthenticated) => return Err("core refused the plugin".into()),
+        };
 
-        Ok(data.into_inner())
+        match self.info_once(&session).await {
+            Ok(data) => Ok(data),
+            Err(CallError::Other(e)) => Err(e),
+            Err(CallError::Unauthenticated) => Err("core refused the new token".into()),
+        }
     }
 
     pub async fn add_email(&self, email: String, priority: i64) -> ResultT<()> {
@@ -74,7 +755,7 @@ impl CoreAPI
 
         let command = anzen::Command {
             command_type: 2,
-            origin: self.name.to_string(),
+            origin: self.connection.plugin_name.clone(),
             data: data.to_string(),
             arm_status: Some(anzen::ArmStatus::Unspecified as i32),
             set_info: HashMap::new()
@@ -83,40 +764,189 @@ impl CoreAPI
         self.post_command(command).await
     }
 
-    pub async fn toggle_armed(&self) -> ResultT<()>
+    /// Lets output plugins tell the user about changes to their account
+    pub async fn security_notification(&self, email: String, event: &str) -> ResultT<()>
     {
-        let new_status = match self.get_stats().await?.armed {
-            true => anzen::ArmStatus::Disarmed,
-            false => anzen::ArmStatus::Armed,
+        let data = json!({
+            "request": "security-notification",
+            "email": email,
+            "event": event
+        });
+
+        let command = anzen::Command {
+            command_type: 2,
+            origin: self.connection.plugin_name.clone(),
+            data: data.to_string(),
+            arm_status: Some(anzen::ArmStatus::Unspecified as i32),
+            set_info: HashMap::new()
         };
 
+        self.post_command(command).await
+    }
+
+    /// Tells the mail plugin a reset is waiting. Core keeps every command,
+    /// so the token is only sent sealed to the plugin's key and the plugin
+    /// looks the email up by the reset id.
+    pub async fn send_password_reset(&self, reset_id: String, sealed_token: String, expires_in: u64) -> ResultT<()>
+    {
+        let data = json!({
+            "request": "password-reset",
+            "reset_id": reset_id,
+            "token": sealed_token,
+            "expires_in": expires_in
+        });
+
         let command = anzen::Command {
-            command_type: 0,
-            origin: self.name.to_string(),
-            data: "".into(),
-            arm_status: Some(new_status.into()),
-            set_info: HashMap::new(),
+            command_type: 2,
+            origin: self.connection.plugin_name.clone(),
+            data: data.to_string(),
+            arm_status: Some(anzen::ArmStatus::Unspecified as i32),
+            set_info: HashMap::new()
         };
 
         self.post_command(command).await
     }
 
-    async fn post_command(&self, command: anzen::Command) -> ResultT<()> {
+    /// Asks core for an explicit state, so repeating it changes nothing.
+    /// Returns the state core reports afterwards, or an error when core
+    /// cannot say. Changes go through `ArmControl` so they do not overlap.
+    pub async fn set_armed(&self, armed: bool, actor: &str, reason: Option<&str>) -> ResultT<bool>
+    {
+        let status = match armed {
+            true => anzen::ArmStatus::Armed,
+            false => anzen::ArmStatus::Disarmed,
+        };
+
+        let data = json!({
+            "request": "set-armed",
+            "armed": armed,
+            "actor": actor,
+            "reason": reason
+        });
 
-        let mut req = tonic::Request::new(anzen::PostSingleCommandRequest {
+        let command = anzen::Command {
+            command_type: 0,
+            origin: self.connection.plugin_name.clone(),
+            data: data.to_string(),
+            arm_status: Some(status.into()),
+            set_info: HashMap::new(),
+        };
+
+        self.post_command(command).await?;
+
+        let armed = self.get_stats().await?.armed;
+        self.publish_armed(armed);
+
+        Ok(armed)
+    }
+
+    async fn post_command_once(&self, session: &CoreSession, command: anzen::Command) -> Result<(), CallError>
+    {
+        let req = self.request(session, anzen::PostSingleCommandRequest {
             command: Some(command),
         });
+        let deadline = Duration::from_secs(self.connection.core_timeout);
 
-        anzen_lib::client::insert_authorization(
-            &mut req,
-            self.token.to_string(),
-            self.name.to_string(),
-        );
+        let result = timeout(deadline, async {
+            let mut client = session.client.lock().await;
+            client.post_single_command(req).await
+        })
+        .await;
+
+        self.outcome(result).map(|_| ())
+    }
+
+    /// Commands are only retried when core refused the token, so they are
+    /// never sent twice
+    async fn post_command(&self, command: anzen::Command) -> ResultT<()>
+    {
+        let session = self.session().await?;
+
+        match self.post_command_once(&session, command.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(CallError::Other(e)) => return Err(e),
+            Err(CallError::Unauthenticated) => (),
+        }
+
+        let session = match self.reregister(&session).await {
+            Ok(session) => session,
+            Err(CallError::Other(e)) => return Err(e),
+            Err(CallError::Unauthenticated) => return Err("core refused the plugin".into()),
+        };
+
+        match self.post_command_once(&session, command).await {
+            Ok(()) => Ok(()),
+            Err(CallError::Other(e)) => Err(e),
+            Err(CallError::Unauthenticated) => Err("core refused the new token".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn breaker(cooldown: Duration) -> CircuitBreaker
+    {
+        CircuitBreaker {
+            threshold: 2,
+            cooldown,
+            state: std::sync::Mutex::new(BreakerState::default()),
+        }
+    }
+
+    #[test]
+    fn opens_after_the_threshold()
+    {
+        let breaker = breaker(Duration::from_secs(60));
+
+        breaker.failure();
+        assert!(breaker.allow());
+
+        breaker.failure();
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn lets_one_probe_through_after_the_cooldown()
+    {
+        let cooldown = Duration::from_millis(50);
+        let breaker = breaker(cooldown);
+
+        breaker.failure();
+        breaker.failure();
+        std::thread::sleep(cooldown);
+
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        // A failed probe waits out another cooldown
+        breaker.failure();
+        assert!(!breaker.allow());
+        std::thread::sleep(cooldown);
+
+        assert!(breaker.allow());
+        breaker.success();
+
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn replaces_a_probe_that_never_finished()
+    {
+        let cooldown = Duration::from_millis(50);
+        let breaker = breaker(cooldown);
 
-        let mut client = self.client.lock().await;
+        breaker.failure();
+        breaker.failure();
+        std::thread::sleep(cooldown);
 
-        let _resp = client.post_single_command(req).await?;
+        assert!(breaker.allow());
+        std::thread::sleep(cooldown);
 
-        Ok(())
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
     }
 }
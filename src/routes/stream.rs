@@ -0,0 +1,190 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::auth::TextError;
+use super::errors::{self, APIError, ErrorJson};
+use super::permissions::{scope, Authorized, Principal};
+use super::returns::{CommandView, EventView};
+use super::state::CoreAPI;
+use crate::model::AnzenDB;
+use mongodb::bson::{oid::ObjectId, DateTime, Document};
+use mongodb::change_stream::{event::ChangeStreamEvent, ChangeStream};
+use rocket::futures::StreamExt;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::serde_json::json;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::time::{interval_at, sleep_until, Instant, MissedTickBehavior};
+use rocket::{Shutdown, State};
+
+/// How often the stream checks that its session or API key is still good
+const CREDENTIAL_CHECK: Duration = Duration::from_secs(60);
+
+/// What the stream was opened with, checked again while it runs
+enum Credential
+{
+    Session(String),
+    ApiKey(String),
+}
+
+/// Pushes new events, commands and arm state changes as they happen
+#[utoipa::path(
+    get,
+    path = "/api/v1/stream",
+    operation_id = "stream_stream",
+    tag = "stream",
+    params(
+        ("device" = Option<String>, Query),
+        ("plugin" = Option<String>, Query),
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events named event, command and status, until the token expires or is revoked"),
+        (status = 400, description = "Invalid device or plugin id"),
+    ),
+    security(("bearer_token" = []), ("api_key" = []))
+)]
+#[get("/?<device>&<plugin>")]
+pub async fn stream(
+    device: Option<String>,
+    plugin: Option<String>,
+    auth: Result<Authorized<scope::ViewStats>, TextError>,
+    db: &State<AnzenDB>,
+    core_api: &State<CoreAPI>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], TextError>
+{
+    let auth = auth?;
+
+    let (credential, expires) = match auth.principal {
+        Principal::User(claims) => (Credential::Session(claims.sid), Some(claims.exp)),
+        Principal::ApiKey(key) => (Credential::ApiKey(key.id), None),
+    };
+
+    let deadline = expires.map(|exp| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        Instant::now() + Duration::from_secs((exp as u64).saturating_sub(now))
+    });
+
+    let device = parse_id(device)?;
+    let plugin = parse_id(plugin)?;
+
+    let db_fail = || APIError::Internal(ErrorJson::new(errors::MSG_INTERNAL_DB_ERR));
+
+    let mut events = match db.watch_events(device, plugin).await {
+        Ok(v) => v,
+        Err(_) => return Err(db_fail()),
+    };
+
+    // Device filtered streams have no commands to show
+    let mut commands = match device {
+        Some(_) => None,
+        None => match db.watch_commands(plugin).await {
+            Ok(v) => Some(v),
+            Err(_) => return Err(db_fail()),
+        },
+    };
+
+    let db = db.inner().clone();
+    let (current, mut armed) = core_api.subscribe_armed();
+    let mut recheck = interval_at(Instant::now() + CREDENTIAL_CHECK, CREDENTIAL_CHECK);
+    recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    Ok(EventStream! {
+        let mut last_armed = current;
+
+        if let Some(state) = current {
+            yield Event::json(&json!({ "armed": state })).event("status");
+        }
+
+        loop {
+            let update = select! {
+                Some(Ok(change)) = events.next() => match change.full_document {
+                    Some(doc) => match EventView::from_document(&doc) {
+                        Some(view) => Update::Send(Event::json(&view).event("event")),
+                        None => continue,
+                    },
+                    None => continue,
+                },
+                Some(Ok(change)) = next_change(&mut commands) => match change.full_document {
+                    Some(doc) => match CommandView::from_document(&doc) {
+                        Some(view) => Update::Send(Event::json(&view).event("command")),
+                        None => continue,
+                    },
+                    None => continue,
+                },
+                state = armed.recv() => match state {
+                    Ok(state) => Update::Armed(state),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = recheck.tick() => match still_valid(&db, &credential).await {
+                    true => continue,
+                    false => break,
+                },
+                _ = expiry(deadline) => break,
+                _ = &mut shutdown => break,
+            };
+
+            let event = match update {
+                Update::Send(event) => event,
+                // Only changes are sent, setting the same state again is not one
+                Update::Armed(state) if last_armed != Some(state) => {
+                    last_armed = Some(state);
+                    Event::json(&json!({ "armed": state })).event("status")
+                },
+                Update::Armed(_) => continue,
+            };
+
+            yield event;
+        }
+    })
+}
+
+enum Update
+{
+    Send(Event),
+    Armed(bool),
+}
+
+/// Whether the session is still live, or the key is still unrevoked and
+/// unexpired. A failed lookup counts as no.
+async fn still_valid(db: &AnzenDB, credential: &Credential) -> bool
+{
+    match credential {
+        Credential::Session(sid) => db.session_active(sid).await.unwrap_or(false),
+        Credential::ApiKey(id) => match db.get_api_key(id).await {
+            Ok(Some(key)) => !key.revoked && key.expires.map(|expires| expires > DateTime::now()).unwrap_or(true),
+            _ => false,
+        },
+    }
+}
+
+/// Waits until the token expires, or forever for credentials without an expiry
+async fn expiry(deadline: Option<Instant>)
+{
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits forever when there is no stream, so `select!` skips it
+async fn next_change(
+    stream: &mut Option<ChangeStream<ChangeStreamEvent<Document>>>,
+) -> Option<mongodb::error::Result<ChangeStreamEvent<Document>>>
+{
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn parse_id(id: Option<String>) -> Result<Option<ObjectId>, TextError>
+{
+    match id {
+        Some(id) => match ObjectId::parse_str(&id) {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => Err(APIError::BadRequest(ErrorJson::new(errors::MSG_INVALID_ID))),
+        },
+        None => Ok(None),
+    }
+}
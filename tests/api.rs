@@ -0,0 +1,1245 @@
+mod common;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::{bearer, Harness, ADMIN_EMAIL, BREAKER_THRESHOLD, OPERATOR_EMAIL};
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use rocket::http::{ContentType, Header, Status};
+use rocket::serde::json::{serde_json::json, Value};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+#[rocket::async_test]
+async fn registers_with_core()
+{
+    let harness = Harness::new().await;
+
+    assert_eq!(harness.core.lock().await.registrations, vec!["web-api".to_string()]);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+async fn serves_public_documents()
+{
+    let harness = Harness::new().await;
+
+    let resp = harness.client.get("/api/v1/openapi.json").dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    let spec: Value = resp.into_json().await.unwrap();
+    assert!(spec["paths"]["/api/v1/data/stats"]["get"].is_object());
+
+    let resp = harness.client.get("/.well-known/jwks.json").dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+async fn rejects_requests_without_credentials()
+{
+    let harness = Harness::new().await;
+
+    let gets = [
+        "/api/v1/data/test",
+        "/api/v1/data/stats",
+        "/api/v1/data/search",
+        "/api/v1/users/user",
+        "/api/v1/users/users",
+        "/api/v1/invitations",
+        "/api/v1/keys",
+        "/api/v1/devices",
+        "/api/v1/plugins",
+        "/api/v1/audit",
+        "/api/v1/stream",
+    ];
+
+    for path in gets {
+        let resp = harness.client.get(path).dispatch().await;
+        assert_eq!(resp.status(), Status::Forbidden, "GET {}", path);
+    }
+
+    let resp = harness
+        .client
+        .post("/api/v1/data/arm")
+        .header(ContentType::JSON)
+        .body("{}")
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    let resp = harness
+        .client
+        .get("/api/v1/data/stats")
+        .header(Header::new("X-API-Key", "not-a-key"))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn logs_in_and_reads_the_account()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness.client.get("/api/v1/data/test").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.into_string().await.unwrap(), ADMIN_EMAIL);
+
+    let resp = harness.client.get("/api/v1/users/user").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login")
+        .header(ContentType::JSON)
+        .body(json!({ "email": ADMIN_EMAIL, "password": "wrong" }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn stats_include_core_status()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    {
+        let mut core = harness.core.lock().await;
+        core.armed = true;
+        core.values.insert("zone".into(), "home".into());
+    }
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness.client.get("/api/v1/data/stats").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["data"]["coreStatus"]["armed"], json!(true));
+    assert_eq!(body["data"]["coreStatus"]["store"]["zone"], json!("home"));
+
+    let resp = harness
+        .client
+        .get("/api/v1/data/stats?bucket=fortnight")
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::BadRequest);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn search_charts_by_the_requested_bucket()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .get("/api/v1/data/search?bucket=day")
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["data"]["bucket"], json!("day"));
+
+    let resp = harness
+        .client
+        .get("/api/v1/data/search?bucket=bogus")
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::BadRequest);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn stats_degrade_when_core_is_down()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+    harness.core.lock().await.offline = true;
+
+    let resp = harness.client.get("/api/v1/data/stats").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["data"]["coreStatus"], Value::Null);
+    assert!(body["data"]["eventStats"].is_array());
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+async fn reregisters_when_the_token_expires()
+{
+    let harness = Harness::new().await;
+
+    harness.core.lock().await.token_expired = true;
+
+    assert!(harness.core_api().get_stats().await.is_ok());
+    assert_eq!(harness.core.lock().await.registrations.len(), 2);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+async fn stops_calling_core_after_repeated_failures()
+{
+    let harness = Harness::new().await;
+
+    harness.core.lock().await.offline = true;
+
+    for _ in 0..BREAKER_THRESHOLD {
+        assert!(harness.core_api().get_stats().await.is_err());
+    }
+
+    // Core is back, but the breaker waits out its cooldown before trying
+    harness.core.lock().await.offline = false;
+    let calls = harness.core.lock().await.info_calls;
+
+    assert!(harness.core_api().get_stats().await.is_err());
+    assert_eq!(harness.core.lock().await.info_calls, calls);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn arms_and_disarms_through_core()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(OPERATOR_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/data/arm")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "reason": "leaving" }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(harness.core.lock().await.armed);
+
+    let resp = harness
+        .client
+        .post("/api/v1/data/disarm")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body("{}")
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(!harness.core.lock().await.armed);
+
+    let resp = harness.client.post("/api/v1/data/toggle").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(harness.core.lock().await.armed);
+
+    let core = harness.core.lock().await;
+    assert_eq!(core.commands.len(), 3);
+    assert!(core.commands.iter().all(|command| command.origin == "web-api"));
+    drop(core);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn arming_fails_when_core_cannot_report_the_state()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(OPERATOR_EMAIL).await;
+    harness.core.lock().await.info_unavailable = true;
+
+    let resp = harness
+        .client
+        .post("/api/v1/data/arm")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body("{}")
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+
+    // Toggling needs the current state before it can send anything
+    let resp = harness.client.post("/api/v1/data/toggle").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+    assert_eq!(harness.core.lock().await.commands.len(), 1);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn adds_emails_through_core()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/core/addmail")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "email": "alerts@anzen.test", "priority": 2 }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let core = harness.core.lock().await;
+    let data: Value = rocket::serde::json::from_str(&core.commands[0].data).unwrap();
+    assert_eq!(data["request"], json!("add-email"));
+    assert_eq!(data["email"], json!("alerts@anzen.test"));
+    drop(core);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn api_keys_are_limited_to_their_scopes()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/keys")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "name": "panel", "scopes": ["view-stats"] }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    let key = body["key"].as_str().unwrap().to_string();
+
+    let resp = harness
+        .client
+        .get("/api/v1/data/stats")
+        .header(Header::new("X-API-Key", key.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let resp = harness
+        .client
+        .post("/api/v1/data/toggle")
+        .header(Header::new("X-API-Key", key))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn admin_views_are_recorded_and_listed()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    for path in [
+        "/api/v1/users/users",
+        "/api/v1/invitations",
+        "/api/v1/keys",
+        "/api/v1/devices",
+        "/api/v1/plugins",
+        "/api/v1/data/search",
+        "/api/v1/users/policy",
+    ] {
+        let resp = harness.client.get(path).header(bearer(&token)).dispatch().await;
+        assert_eq!(resp.status(), Status::Ok, "GET {}", path);
+    }
+
+    let resp = harness
+        .client
+        .get("/api/v1/audit?action=login")
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    assert!(body["count"].as_u64().unwrap() >= 1);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn operators_cannot_manage_users()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(OPERATOR_EMAIL).await;
+
+    let resp = harness.client.get("/api/v1/users/users").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    let resp = harness.client.get("/api/v1/audit").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn password_resets_keep_the_token_out_of_core()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    harness.register(ADMIN_EMAIL).await;
+
+    let forgot = || {
+        harness
+            .client
+            .post("/api/v1/auth/forgot")
+            .header(ContentType::JSON)
+            .body(json!({ "email": ADMIN_EMAIL }).to_string())
+    };
+
+    let resp = forgot().dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let core = harness.core.lock().await;
+    let data: Value = rocket::serde::json::from_str(&core.commands[0].data).unwrap();
+    assert_eq!(data["request"], json!("password-reset"));
+    assert!(data.get("email").is_none());
+    drop(core);
+
+    // Core only sees the token sealed, the database only its hash
+    let sealed = data["token"].as_str().unwrap();
+    let token = harness.open_reset(sealed);
+    assert_ne!(sealed, token);
+
+    let reset_id = ObjectId::parse_str(data["reset_id"].as_str().unwrap()).unwrap();
+    let stored = harness
+        .database()
+        .await
+        .collection::<Document>("password_resets")
+        .find_one(doc! { "_id": reset_id }, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!stored.to_string().contains(&token));
+
+    // Three requests per email by default
+    for _ in 0..2 {
+        assert_eq!(forgot().dispatch().await.status(), Status::Ok);
+    }
+    assert_eq!(forgot().dispatch().await.status(), Status::TooManyRequests);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn invitations_are_capped_and_used_once()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let invite = |expires_in: i64| {
+        harness
+            .client
+            .post("/api/v1/invitations")
+            .header(bearer(&token))
+            .header(ContentType::JSON)
+            .body(json!({ "email": "guest@anzen.test", "role": "viewer", "expires_in": expires_in }).to_string())
+    };
+
+    for expires_in in [0, -60] {
+        assert_eq!(invite(expires_in).dispatch().await.status(), Status::UnprocessableEntity);
+    }
+
+    let resp = invite(i64::MAX).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    let code = body["code"].as_str().unwrap().to_string();
+    let expires = chrono::DateTime::parse_from_rfc3339(body["data"]["expires"].as_str().unwrap()).unwrap();
+    assert!(expires < chrono::Utc::now() + chrono::Duration::days(31));
+
+    let register = |code: &str| {
+        harness
+            .client
+            .post("/api/v1/auth/register")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "email": "guest@anzen.test",
+                    "username": "guest",
+                    "password": common::PASSWORD,
+                    "invite": code
+                })
+                .to_string(),
+            )
+    };
+
+    assert_eq!(register("not-a-code").dispatch().await.status(), Status::Unauthorized);
+    assert_eq!(register(&code).dispatch().await.status(), Status::Ok);
+    assert_eq!(register(&code).dispatch().await.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn api_keys_follow_their_creator()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let admin = harness.login(ADMIN_EMAIL).await;
+    let deputy = harness.login_invited(&admin, "deputy@anzen.test", "admin").await;
+
+    let create = |expires_in: i64| {
+        harness
+            .client
+            .post("/api/v1/keys")
+            .header(bearer(&deputy))
+            .header(ContentType::JSON)
+            .body(json!({ "name": "panel", "scopes": ["view-stats"], "expires_in": expires_in }).to_string())
+    };
+
+    for expires_in in [i64::MAX, 0, -60] {
+        assert_eq!(create(expires_in).dispatch().await.status(), Status::UnprocessableEntity);
+    }
+
+    let resp = create(3600).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: Value = resp.into_json().await.unwrap();
+    let key = body["key"].as_str().unwrap().to_string();
+
+    let stats = || {
+        harness
+            .client
+            .get("/api/v1/data/stats")
+            .header(Header::new("X-API-Key", key.clone()))
+    };
+
+    assert_eq!(stats().dispatch().await.status(), Status::Ok);
+
+    let id = harness.user_id(&admin, "deputy@anzen.test").await;
+    let resp = harness
+        .client
+        .post(format!("/api/v1/users/{}/disable", id))
+        .header(bearer(&admin))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    assert_eq!(stats().dispatch().await.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn oidc_logs_in_by_provider_subject()
+{
+    let harness = Harness::with_oidc().await;
+    require_db!(harness);
+
+    let claims = json!({ "sub": "idp-user-1", "email": "sso@anzen.test", "email_verified": true });
+
+    let (status, body) = harness.oidc_login(claims.clone()).await;
+    assert_eq!(status, Status::Ok);
+    let token = body["token"].as_str().unwrap().to_string();
+
+    let resp = harness.client.get("/api/v1/users/user").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    // The same provider account finds its user again, whatever email it reports
+    let (status, _) = harness
+        .oidc_login(json!({ "sub": "idp-user-1", "email": "renamed@anzen.test", "email_verified": true }))
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn oidc_does_not_take_over_password_accounts()
+{
+    let harness = Harness::with_oidc().await;
+    require_db!(harness);
+
+    harness.register(ADMIN_EMAIL).await;
+
+    let (status, _) = harness
+        .oidc_login(json!({ "sub": "idp-user-2", "email": ADMIN_EMAIL, "email_verified": true }))
+        .await;
+    assert_eq!(status, Status::Conflict);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+async fn oidc_rejects_a_nonce_mismatch()
+{
+    let harness = Harness::with_oidc().await;
+
+    let (status, _) = harness
+        .oidc_login(json!({
+            "sub": "idp-user-3",
+            "email": "sso@anzen.test",
+            "email_verified": true,
+            "nonce": "not-the-nonce"
+        }))
+        .await;
+    assert_eq!(status, Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+async fn oidc_requires_a_verified_email()
+{
+    let harness = Harness::with_oidc().await;
+
+    for verified in [json!(false), json!("true"), Value::Null] {
+        let mut claims = json!({ "sub": "idp-user-4", "email": "sso@anzen.test" });
+        if !verified.is_null() {
+            claims["email_verified"] = verified.clone();
+        }
+
+        let (status, _) = harness.oidc_login(claims).await;
+        assert_eq!(status, Status::Unauthorized, "email_verified {}", verified);
+    }
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn wrong_arm_pins_lock_the_actor_out()
+{
+    let harness = Harness::with_options("arm_pin = \"4821\"\nlogin_max_attempts = 2\n").await;
+    require_db!(harness);
+
+    let token = harness.login(OPERATOR_EMAIL).await;
+
+    let arm = |pin: &str| {
+        harness
+            .client
+            .post("/api/v1/data/arm")
+            .header(bearer(&token))
+            .header(ContentType::JSON)
+            .body(json!({ "pin": pin }).to_string())
+    };
+
+    let resp = harness.client.post("/api/v1/data/toggle").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    assert_eq!(arm("0000").dispatch().await.status(), Status::Forbidden);
+    assert_eq!(arm("0000").dispatch().await.status(), Status::Forbidden);
+    assert_eq!(arm("4821").dispatch().await.status(), Status::TooManyRequests);
+    assert!(!harness.core.lock().await.armed);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn delayed_arms_are_audited_when_they_fire()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let operator = harness.login(OPERATOR_EMAIL).await;
+    let admin = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/data/arm")
+        .header(bearer(&operator))
+        .header(ContentType::JSON)
+        .body(json!({ "delay": 1 }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(!harness.core.lock().await.armed);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(harness.core.lock().await.armed);
+
+    let resp = harness
+        .client
+        .get("/api/v1/audit?action=alarm.arm.delayed")
+        .header(bearer(&admin))
+        .dispatch()
+        .await;
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["count"], json!(1));
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn refreshes_and_logs_out()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    harness.register(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login")
+        .header(ContentType::JSON)
+        .body(json!({ "email": ADMIN_EMAIL, "password": common::PASSWORD }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: Value = resp.into_json().await.unwrap();
+
+    let refresh = |refresh_token: &Value| {
+        harness
+            .client
+            .post("/api/v1/auth/refresh")
+            .header(ContentType::JSON)
+            .body(json!({ "refresh_token": refresh_token }).to_string())
+    };
+
+    let resp = refresh(&body["refresh_token"]).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    let rotated: Value = resp.into_json().await.unwrap();
+    let token = rotated["token"].as_str().unwrap().to_string();
+
+    let resp = harness.client.post("/api/v1/auth/logout").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    assert_eq!(refresh(&rotated["refresh_token"]).dispatch().await.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn reused_refresh_tokens_end_every_session()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    harness.register(ADMIN_EMAIL).await;
+
+    let login = || async {
+        let resp = harness
+            .client
+            .post("/api/v1/auth/login")
+            .header(ContentType::JSON)
+            .body(json!({ "email": ADMIN_EMAIL, "password": common::PASSWORD }).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json::<Value>().await.unwrap()
+    };
+
+    let refresh = |refresh_token: &Value| {
+        harness
+            .client
+            .post("/api/v1/auth/refresh")
+            .header(ContentType::JSON)
+            .body(json!({ "refresh_token": refresh_token }).to_string())
+    };
+
+    let laptop = login().await;
+    let phone = login().await;
+
+    let resp = refresh(&laptop["refresh_token"]).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    let rotated: Value = resp.into_json().await.unwrap();
+
+    // The first secret was already used, someone copied it
+    assert_eq!(refresh(&laptop["refresh_token"]).dispatch().await.status(), Status::Unauthorized);
+
+    assert_eq!(refresh(&rotated["refresh_token"]).dispatch().await.status(), Status::Unauthorized);
+    assert_eq!(refresh(&phone["refresh_token"]).dispatch().await.status(), Status::Unauthorized);
+
+    let token = phone["token"].as_str().unwrap();
+    let resp = harness.client.get("/api/v1/users/user").header(bearer(token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn logs_in_with_two_factor()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness.client.post("/api/v1/auth/2fa/setup").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: Value = resp.into_json().await.unwrap();
+
+    let secret = Secret::Encoded(body["secret"].as_str().unwrap().to_string()).to_bytes().unwrap();
+    let totp = TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, None, ADMIN_EMAIL.to_string()).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/2fa/enable")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "code": totp.generate(now) }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login")
+        .header(ContentType::JSON)
+        .body(json!({ "email": ADMIN_EMAIL, "password": common::PASSWORD }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["mfa_required"], json!(true));
+    assert!(body.get("token").is_none());
+
+    // Each step is accepted once, answer with the next one
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login/2fa")
+        .header(ContentType::JSON)
+        .body(json!({ "challenge": body["challenge"], "code": totp.generate(now + 30) }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: Value = resp.into_json().await.unwrap();
+
+    let token = body["token"].as_str().unwrap();
+    let resp = harness.client.get("/api/v1/users/user").header(bearer(token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn resets_a_forgotten_password()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let old = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/forgot")
+        .header(ContentType::JSON)
+        .body(json!({ "email": ADMIN_EMAIL }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let core = harness.core.lock().await;
+    let data: Value = rocket::serde::json::from_str(&core.commands[0].data).unwrap();
+    drop(core);
+
+    let token = harness.open_reset(data["token"].as_str().unwrap());
+
+    let reset = |password: &str| {
+        harness
+            .client
+            .post("/api/v1/auth/reset")
+            .header(ContentType::JSON)
+            .body(json!({ "token": token, "password": password }).to_string())
+    };
+
+    assert_eq!(reset("Another-Horse-43").dispatch().await.status(), Status::Ok);
+    assert_eq!(reset("Third-Horse-44").dispatch().await.status(), Status::Unauthorized);
+
+    let resp = harness.client.get("/api/v1/users/user").header(bearer(&old)).dispatch().await;
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login")
+        .header(ContentType::JSON)
+        .body(json!({ "email": ADMIN_EMAIL, "password": "Another-Horse-43" }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn rotates_and_revokes_api_keys()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .post("/api/v1/keys")
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "name": "panel", "scopes": ["view-stats"] }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    let id = body["data"]["id"].as_str().unwrap().to_string();
+    let first = body["key"].as_str().unwrap().to_string();
+
+    let stats = |key: &str| {
+        harness
+            .client
+            .get("/api/v1/data/stats")
+            .header(Header::new("X-API-Key", key.to_string()))
+    };
+
+    let resp = harness
+        .client
+        .post(format!("/api/v1/keys/{}/rotate", id))
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    let second = body["key"].as_str().unwrap().to_string();
+
+    assert_eq!(stats(&first).dispatch().await.status(), Status::Unauthorized);
+    assert_eq!(stats(&second).dispatch().await.status(), Status::Ok);
+
+    let resp = harness
+        .client
+        .delete(format!("/api/v1/keys/{}", id))
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    assert_eq!(stats(&second).dispatch().await.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn updates_devices()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    // Plugins register their devices in the database, not through this API
+    let id = ObjectId::new();
+    harness
+        .database()
+        .await
+        .collection::<Document>("devices")
+        .insert_one(doc! { "_id": id, "id": "hall-sensor" }, None)
+        .await
+        .unwrap();
+
+    let resp = harness
+        .client
+        .post(format!("/api/v1/devices/{}", id.to_hex()))
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "name": "Hall", "zone": "ground floor", "ignored": true }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let device: Value = resp.into_json().await.unwrap();
+    assert_eq!(device["device_id"], json!("hall-sensor"));
+    assert_eq!(device["name"], json!("Hall"));
+    assert_eq!(device["zone"], json!("ground floor"));
+    assert_eq!(device["ignored"], json!(true));
+
+    // Ignored devices are left out unless asked for
+    let resp = harness.client.get("/api/v1/devices").header(bearer(&token)).dispatch().await;
+    let devices: Value = resp.into_json().await.unwrap();
+    assert!(!devices.to_string().contains("hall-sensor"));
+
+    let resp = harness
+        .client
+        .post(format!("/api/v1/devices/{}", ObjectId::new().to_hex()))
+        .header(bearer(&token))
+        .header(ContentType::JSON)
+        .body(json!({ "name": "Nowhere" }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::NotFound);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn pages_devices_with_their_activity()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let db = harness.database().await;
+    let now = mongodb::bson::DateTime::now().timestamp_millis();
+    let at = |days_ago: i64| mongodb::bson::DateTime::from_millis(now - days_ago * 24 * 60 * 60 * 1000);
+
+    let ids = [ObjectId::new(), ObjectId::new(), ObjectId::new()];
+    db.collection::<Document>("devices")
+        .insert_many(ids.iter().enumerate().map(|(n, id)| doc! { "_id": id, "id": format!("sensor-{}", n) }), None)
+        .await
+        .unwrap();
+
+    db.collection::<Document>("events")
+        .insert_many(
+            [
+                doc! { "timestamp": at(1), "metadata": { "device_id": ids[0] }, "data": {} },
+                doc! { "timestamp": at(2), "metadata": { "device_id": ids[0] }, "data": {} },
+                doc! { "timestamp": at(90), "metadata": { "device_id": ids[1] }, "data": {} },
+            ],
+            None,
+        )
+        .await
+        .unwrap();
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let resp = harness
+        .client
+        .get("/api/v1/devices?per_page=2")
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["total"], json!(3));
+    assert_eq!(body["devices"].as_array().unwrap().len(), 2);
+    assert_eq!(body["devices"][0]["event_count"], json!(2));
+
+    // Events older than the window are not counted, the device was still seen
+    assert_eq!(body["devices"][1]["event_count"], json!(0));
+    assert!(body["devices"][1]["last_seen"].is_string());
+
+    let resp = harness
+        .client
+        .get("/api/v1/devices?per_page=2&page=1")
+        .header(bearer(&token))
+        .dispatch()
+        .await;
+    let body: Value = resp.into_json().await.unwrap();
+    assert_eq!(body["devices"].as_array().unwrap().len(), 1);
+    assert_eq!(body["devices"][0]["device_id"], json!("sensor-2"));
+    assert_eq!(body["devices"][0]["last_seen"], Value::Null);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn disabling_two_factor_shares_the_login_lockout()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let token = harness.login(ADMIN_EMAIL).await;
+
+    let disable = || {
+        harness
+            .client
+            .post("/api/v1/auth/2fa/disable")
+            .header(bearer(&token))
+            .header(ContentType::JSON)
+            .body(json!({ "password": "wrong", "code": "000000" }).to_string())
+    };
+
+    // Five failures per email by default
+    for _ in 0..5 {
+        assert_eq!(disable().dispatch().await.status(), Status::Unauthorized);
+    }
+    assert_eq!(disable().dispatch().await.status(), Status::TooManyRequests);
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login")
+        .header(ContentType::JSON)
+        .body(json!({ "email": ADMIN_EMAIL, "password": common::PASSWORD }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::TooManyRequests);
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn oidc_accounts_have_no_local_password()
+{
+    let harness = Harness::with_oidc().await;
+    require_db!(harness);
+
+    let (status, _) = harness
+        .oidc_login(json!({ "sub": "idp-user-3", "email": "sso@anzen.test", "email_verified": true }))
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/forgot")
+        .header(ContentType::JSON)
+        .body(json!({ "email": "sso@anzen.test" }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(harness.core.lock().await.commands.is_empty());
+
+    let resp = harness
+        .client
+        .post("/api/v1/auth/login")
+        .header(ContentType::JSON)
+        .body(json!({ "email": "sso@anzen.test", "password": common::PASSWORD }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    harness.finish().await;
+}
+
+/// Logs `idp-admin` in through the provider with `groups` and reads back
+/// the role the account ended up with
+async fn oidc_role(harness: &Harness, groups: Value) -> Value
+{
+    let (status, body) = harness
+        .oidc_login(json!({
+            "sub": "idp-admin",
+            "email": "sso-admin@anzen.test",
+            "email_verified": true,
+            "groups": groups
+        }))
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let token = body["token"].as_str().unwrap();
+    let resp = harness.client.get("/api/v1/users/user").header(bearer(token)).dispatch().await;
+    let body: Value = resp.into_json().await.unwrap();
+    body["data"]["role"].clone()
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn oidc_groups_never_demote_the_last_admin()
+{
+    let harness = Harness::with_oidc().await;
+    require_db!(harness);
+
+    assert_eq!(oidc_role(&harness, json!(["anzen-admins"])).await, json!("admin"));
+
+    // Leaving the group would leave nobody to manage users
+    assert_eq!(oidc_role(&harness, json!([])).await, json!("admin"));
+
+    harness.register(ADMIN_EMAIL).await;
+    assert_eq!(oidc_role(&harness, json!([])).await, json!("viewer"));
+
+    harness.finish().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB, set ANZEN_TEST_MONGO_URI"]
+async fn plugins_report_recent_and_long_silent_activity()
+{
+    let harness = Harness::new().await;
+    require_db!(harness);
+
+    let db = harness.database().await;
+    let now = mongodb::bson::DateTime::now().timestamp_millis();
+    let at = |secs_ago: i64| mongodb::bson::DateTime::from_millis(now - secs_ago * 1000);
+
+    let busy = ObjectId::new();
+    let quiet = ObjectId::new();
+
+    db.collection::<Document>("plugins")
+        .insert_many([doc! { "_id": busy, "name": "busy" }, doc! { "_id": quiet, "name": "quiet" }], None)
+        .await
+        .unwrap();
+
+    db.collection::<Document>("events")
+        .insert_many(
+            [
+                doc! { "timestamp": at(60), "metadata": { "plugin_id": busy }, "data": {} },
+                doc! { "timestamp": at(60 * 60 * 5), "metadata": { "plugin_id": busy }, "data": {} },
+                doc! { "timestamp": at(60 * 60 * 24 * 30), "metadata": { "plugin_id": quiet }, "data": {} },
+            ],
+            None,
+        )
+        .await
+        .unwrap();
+
+    let token = harness.login(ADMIN_EMAIL).await;
+    let resp = harness.client.get("/api/v1/plugins").header(bearer(&token)).dispatch().await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let body: Value = resp.into_json().await.unwrap();
+    let plugin = |name: &str| body.as_array().unwrap().iter().find(|p| p["name"] == json!(name)).unwrap().clone();
+
+    let busy = plugin("busy");
+    assert_eq!(busy["health"], json!("healthy"));
+    assert_eq!(busy["events_last_hour"], json!(1));
+    assert_eq!(busy["events_last_day"], json!(2));
+
+    // Events older than the window are not counted, but still say when it was last heard from
+    let quiet = plugin("quiet");
+    assert_eq!(quiet["health"], json!("silent"));
+    assert_eq!(quiet["events_last_day"], json!(0));
+    assert!(quiet["last_activity"].is_string());
+
+    harness.finish().await;
+}
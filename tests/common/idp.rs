@@ -0,0 +1,158 @@
+//! A fake OpenID Connect provider: discovery, a token endpoint handing out
+//! ID tokens with whatever claims the test registered for the code, and the
+//! JWKS they are signed with.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rocket::form::Form;
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::State;
+use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+use rsa::{PublicKeyParts, RsaPrivateKey};
+use tokio::sync::Mutex;
+
+use super::free_addr;
+
+pub const CLIENT_ID: &str = "anzen-test";
+const KID: &str = "mock-idp";
+
+struct SigningKey
+{
+    pem: String,
+    n: String,
+    e: String,
+}
+
+/// Generating a key is slow in debug builds, every provider shares one
+fn signing_key() -> &'static SigningKey
+{
+    static KEY: OnceLock<SigningKey> = OnceLock::new();
+
+    KEY.get_or_init(|| {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+
+        SigningKey {
+            pem: key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string(),
+            n: base64::encode_config(key.n().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+            e: base64::encode_config(key.e().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+        }
+    })
+}
+
+struct Provider
+{
+    issuer: String,
+    /// Claims to put in the ID token for each authorization code
+    codes: Mutex<HashMap<String, Value>>,
+}
+
+#[derive(rocket::FromForm)]
+struct TokenForm
+{
+    code: String,
+}
+
+#[rocket::get("/.well-known/openid-configuration")]
+fn discovery(provider: &State<Arc<Provider>>) -> Json<Value>
+{
+    Json(json!({
+        "issuer": provider.issuer,
+        "authorization_endpoint": format!("{}/authorize", provider.issuer),
+        "token_endpoint": format!("{}/token", provider.issuer),
+        "jwks_uri": format!("{}/jwks", provider.issuer),
+    }))
+}
+
+#[rocket::post("/token", data = "<form>")]
+async fn token(form: Form<TokenForm>, provider: &State<Arc<Provider>>) -> Option<Json<Value>>
+{
+    let extra = provider.codes.lock().await.remove(&form.code)?;
+    let now = chrono::Utc::now().timestamp();
+
+    let mut claims = json!({
+        "iss": provider.issuer,
+        "aud": CLIENT_ID,
+        "iat": now,
+        "exp": now + 300,
+    });
+
+    for (name, value) in extra.as_object()? {
+        claims[name] = value.clone();
+    }
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(KID.to_string());
+
+    let key = EncodingKey::from_rsa_pem(signing_key().pem.as_bytes()).unwrap();
+    let id_token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+
+    Some(Json(json!({ "id_token": id_token, "token_type": "Bearer" })))
+}
+
+#[rocket::get("/jwks")]
+fn jwks() -> Json<Value>
+{
+    let key = signing_key();
+
+    Json(json!({
+        "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": KID,
+            "n": key.n,
+            "e": key.e,
+        }]
+    }))
+}
+
+pub struct MockIdp
+{
+    pub issuer: String,
+    provider: Arc<Provider>,
+}
+
+impl MockIdp
+{
+    /// Serves the provider and waits until it accepts connections
+    pub async fn start() -> MockIdp
+    {
+        let addr = free_addr();
+        let issuer = format!("http://{}", addr);
+
+        let provider = Arc::new(Provider {
+            issuer: issuer.clone(),
+            codes: Mutex::new(HashMap::new()),
+        });
+
+        let figment = rocket::Config::figment()
+            .merge(("address", addr.ip()))
+            .merge(("port", addr.port()))
+            .merge(("log_level", "off"))
+            .merge(("shutdown.ctrlc", false));
+
+        let rocket = rocket::custom(figment)
+            .mount("/", rocket::routes![discovery, token, jwks])
+            .manage(provider.clone());
+
+        tokio::spawn(rocket.launch());
+
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(addr).await.is_ok() {
+                return MockIdp { issuer, provider };
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        panic!("mock identity provider did not start on {}", addr);
+    }
+
+    /// The ID token for `code` will carry `claims` on top of iss, aud and exp
+    pub async fn issue(&self, code: &str, claims: Value)
+    {
+        self.provider.codes.lock().await.insert(code.to_string(), claims);
+    }
+}
@@ -0,0 +1,465 @@
+//! Test harness: an in-process fake of the anzen core gRPC service and a
+//! throwaway Mongo database, wired into the real rocket instance.
+//!
+//! Routes which touch the database need a local `mongod`. Those tests are
+//! ignored by default, run them with `cargo test -- --include-ignored` and
+//! `ANZEN_TEST_MONGO_URI` set (e.g. `mongodb://127.0.0.1:27017`).
+//! Every harness gets its own database which is dropped by `finish`.
+
+mod idp;
+
+pub use idp::MockIdp;
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anzen_lib::anzen::anzen_server::{Anzen, AnzenServer};
+use anzen_lib::anzen::{self, ArmStatus};
+use anzen_web_api::routes::{self, CoreAPI};
+use anzen_web_api::config;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{serde_json::json, Value};
+use rsa::pkcs8::{EncodePublicKey, LineEnding};
+use rsa::{PaddingScheme, RsaPrivateKey};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use tonic::transport::Server;
+use tonic::{Request, Response};
+
+pub const ADMIN_EMAIL: &str = "admin@anzen.test";
+pub const OPERATOR_EMAIL: &str = "operator@anzen.test";
+pub const PASSWORD: &str = "Correct-Horse-42";
+pub const BREAKER_THRESHOLD: u32 = 3;
+
+/// What the fake core has been told, tests assert against it
+#[derive(Default)]
+pub struct CoreState
+{
+    /// Options handed to the plugin when it registers
+    pub plugin_opts: String,
+    pub armed: bool,
+    pub values: HashMap<String, String>,
+    pub commands: Vec<anzen::Command>,
+    pub registrations: Vec<String>,
+    pub info_calls: usize,
+    /// Every call fails as if core was down
+    pub offline: bool,
+    /// Calls are refused until the plugin registers again
+    pub token_expired: bool,
+    /// Reading the status fails while commands still go through
+    pub info_unavailable: bool,
+}
+
+impl CoreState
+{
+    fn check(&self) -> Result<(), tonic::Status>
+    {
+        if self.offline {
+            return Err(tonic::Status::unavailable("core is offline"));
+        }
+
+        if self.token_expired {
+            return Err(tonic::Status::unauthenticated("token expired"));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MockCore
+{
+    state: Arc<Mutex<CoreState>>,
+}
+
+#[tonic::async_trait]
+impl Anzen for MockCore
+{
+    async fn register(
+        &self,
+        request: Request<anzen::RegisterRequest>,
+    ) -> Result<Response<anzen::RegisterResponse>, tonic::Status>
+    {
+        let request = request.into_inner();
+        let mut state = self.state.lock().await;
+
+        if state.offline {
+            return Err(tonic::Status::unavailable("core is offline"));
+        }
+
+        state.registrations.push(request.name);
+        state.token_expired = false;
+
+        Ok(Response::new(anzen::RegisterResponse {
+            token: "mock-core-token".into(),
+            plugin_opts: state.plugin_opts.clone(),
+            ..Default::default()
+        }))
+    }
+
+    async fn info(
+        &self,
+        _request: Request<anzen::InfoRequest>,
+    ) -> Result<Response<anzen::InfoResponse>, tonic::Status>
+    {
+        let mut state = self.state.lock().await;
+        state.info_calls += 1;
+        state.check()?;
+
+        if state.info_unavailable {
+            return Err(tonic::Status::unavailable("status is unavailable"));
+        }
+
+        Ok(Response::new(anzen::InfoResponse {
+            armed: state.armed,
+            values: state.values.clone(),
+            ..Default::default()
+        }))
+    }
+
+    async fn post_single_command(
+        &self,
+        request: Request<anzen::PostSingleCommandRequest>,
+    ) -> Result<Response<anzen::PostSingleCommandResponse>, tonic::Status>
+    {
+        let command = match request.into_inner().command {
+            Some(command) => command,
+            None => return Err(tonic::Status::invalid_argument("no command")),
+        };
+
+        let mut state = self.state.lock().await;
+        state.check()?;
+
+        match command.arm_status.and_then(ArmStatus::from_i32) {
+            Some(ArmStatus::Armed) => state.armed = true,
+            Some(ArmStatus::Disarmed) => state.armed = false,
+            _ => (),
+        }
+
+        state.commands.push(command);
+
+        Ok(Response::new(anzen::PostSingleCommandResponse::default()))
+    }
+}
+
+pub struct Harness
+{
+    pub client: Client,
+    pub core: Arc<Mutex<CoreState>>,
+    /// Identity provider OpenID Connect logins go through, if configured
+    pub idp: Option<MockIdp>,
+    db: Option<(String, String)>,
+}
+
+fn free_addr() -> SocketAddr
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn random_name() -> String
+{
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Key pair the mail plugin would hold, with the public half written out for
+/// `reset_mailer_key`. Small to keep generating it quick in debug builds.
+fn mailer_key() -> &'static (RsaPrivateKey, PathBuf)
+{
+    static KEY: OnceLock<(RsaPrivateKey, PathBuf)> = OnceLock::new();
+
+    KEY.get_or_init(|| {
+        let key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let path = std::env::temp_dir().join(format!("anzen-mailer-{}.pem", std::process::id()));
+
+        let public = key.to_public_key().to_public_key_pem(LineEnding::LF).unwrap();
+        std::fs::write(&path, public).unwrap();
+
+        (key, path)
+    })
+}
+
+/// Serves the fake core and waits until it accepts connections
+async fn spawn_core(state: Arc<Mutex<CoreState>>) -> SocketAddr
+{
+    let addr = free_addr();
+    let service = AnzenServer::new(MockCore { state });
+
+    tokio::spawn(Server::builder().add_service(service).serve(addr));
+
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return addr;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("mock core did not start on {}", addr);
+}
+
+impl Harness
+{
+    pub async fn new() -> Harness
+    {
+        Harness::build(None, "").await
+    }
+
+    /// Adds top level TOML settings to what core hands out
+    pub async fn with_options(options: &str) -> Harness
+    {
+        Harness::build(None, options).await
+    }
+
+    /// Also configures OpenID Connect login against a fake provider, which
+    /// makes members of `anzen-admins` admins and everyone else a viewer
+    pub async fn with_oidc() -> Harness
+    {
+        Harness::build(Some(MockIdp::start().await), "").await
+    }
+
+    /// Registers with a fresh fake core and builds rocket from the options
+    /// it returns, like `main` does against the real one
+    async fn build(idp: Option<MockIdp>, options: &str) -> Harness
+    {
+        let db = std::env::var("ANZEN_TEST_MONGO_URI")
+            .ok()
+            .map(|uri| (uri, format!("anzen_test_{}", random_name())));
+
+        // Nothing listens on port 1, database routes fail fast instead of hanging
+        let (db_uri, db_name) = match &db {
+            Some((uri, name)) => (uri.clone(), name.clone()),
+            None => (
+                "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200".to_string(),
+                "anzen_unused".to_string(),
+            ),
+        };
+
+        let mut plugin_opts = format!(
+            "db_uri = {:?}\ndb_name = {:?}\nkey = \"integration-test-secret\"\nauth_users = [{:?}, {:?}]\nadmin_users = [{:?}]\nreset_mailer_key = {:?}\n",
+            db_uri, db_name, ADMIN_EMAIL, OPERATOR_EMAIL, ADMIN_EMAIL, mailer_key().1
+        );
+        plugin_opts.push_str(options);
+
+        if let Some(idp) = &idp {
+            plugin_opts.push_str(&format!(
+                "[oidc]\nissuer = {:?}\nclient_id = {:?}\nredirect_uri = \"http://localhost/callback\"\ndefault_role = \"viewer\"\n[oidc.role_mapping]\nanzen-admins = \"admin\"\n",
+                idp.issuer,
+                idp::CLIENT_ID
+            ));
+        }
+
+        let core = Arc::new(Mutex::new(CoreState {
+            plugin_opts,
+            ..Default::default()
+        }));
+        let addr = spawn_core(core.clone()).await;
+
+        // Only what core sends, the developer's own file and ANZEN_* are left out
+        let local = Figment::new()
+            .merge(("core_endpoint", format!("grpc://{}", addr)))
+            .merge(("core_timeout", 2))
+            .merge(("core_breaker_threshold", BREAKER_THRESHOLD));
+
+        let core_api = CoreAPI::init(config::core_connection(&local).unwrap());
+        let plugin_opts = core_api.register().await.unwrap();
+
+        let config = config::load(local, &plugin_opts).unwrap();
+        let rocket = routes::build_rocket(config, core_api).await.unwrap();
+
+        Harness {
+            client: Client::tracked(rocket).await.unwrap(),
+            core,
+            idp,
+            db,
+        }
+    }
+
+    /// The connection the routes use, to call core directly
+    pub fn core_api(&self) -> &CoreAPI
+    {
+        self.client.rocket().state::<CoreAPI>().unwrap()
+    }
+
+    pub fn has_db(&self) -> bool
+    {
+        self.db.is_some()
+    }
+
+    /// The throwaway database, to read what routes never hand out
+    pub async fn database(&self) -> mongodb::Database
+    {
+        let (uri, name) = self.db.as_ref().expect("harness was built without a database");
+        let client = mongodb::Client::with_uri_str(uri).await.unwrap();
+        client.database(name)
+    }
+
+    /// Drops the throwaway database
+    pub async fn finish(self)
+    {
+        if let Some((uri, name)) = self.db {
+            let client = mongodb::Client::with_uri_str(uri).await.unwrap();
+            client.database(&name).drop(None).await.unwrap();
+        }
+    }
+
+    pub async fn register(&self, email: &str)
+    {
+        let resp = self
+            .client
+            .post("/api/v1/auth/register")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "email": email,
+                    "username": email.split('@').next().unwrap(),
+                    "password": PASSWORD
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+
+        assert_eq!(resp.status(), Status::Ok, "register {}", email);
+    }
+
+    /// Registers the account and returns an access token for it
+    pub async fn login(&self, email: &str) -> String
+    {
+        self.register(email).await;
+        self.token(email).await
+    }
+
+    /// Invites an email which is not in `auth_users`, registers it and
+    /// returns an access token for it
+    pub async fn login_invited(&self, admin_token: &str, email: &str, role: &str) -> String
+    {
+        let resp = self
+            .client
+            .post("/api/v1/invitations")
+            .header(bearer(admin_token))
+            .header(ContentType::JSON)
+            .body(json!({ "email": email, "role": role }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(resp.status(), Status::Ok, "invite {}", email);
+        let body: Value = resp.into_json().await.unwrap();
+
+        let resp = self
+            .client
+            .post("/api/v1/auth/register")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "email": email,
+                    "username": email.split('@').next().unwrap(),
+                    "password": PASSWORD,
+                    "invite": body["code"]
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+
+        assert_eq!(resp.status(), Status::Ok, "register {}", email);
+        self.token(email).await
+    }
+
+    /// Id of the account with the email, as listed to admins
+    pub async fn user_id(&self, admin_token: &str, email: &str) -> String
+    {
+        let resp = self
+            .client
+            .get(format!("/api/v1/users/users?email={}", email))
+            .header(bearer(admin_token))
+            .dispatch()
+            .await;
+
+        let body: Value = resp.into_json().await.unwrap();
+        body["users"][0]["id"].as_str().unwrap().to_string()
+    }
+
+    /// Starts an OpenID Connect login and finishes it with an ID token
+    /// carrying `claims`. The nonce from the authorization URL is added
+    /// unless the claims name one.
+    pub async fn oidc_login(&self, claims: Value) -> (Status, Value)
+    {
+        let idp = self.idp.as_ref().expect("harness was built without OpenID Connect");
+
+        let resp = self.client.get("/api/v1/auth/oidc/login").dispatch().await;
+        assert_eq!(resp.status(), Status::Ok, "oidc login");
+
+        let body: Value = resp.into_json().await.unwrap();
+        let url = reqwest::Url::parse(body["authorization_url"].as_str().unwrap()).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+        let mut claims = claims;
+        if claims.get("nonce").is_none() {
+            claims["nonce"] = json!(query["nonce"]);
+        }
+
+        let code = random_name();
+        idp.issue(&code, claims).await;
+
+        let resp = self
+            .client
+            .post("/api/v1/auth/oidc/callback")
+            .header(ContentType::JSON)
+            .body(json!({ "code": code, "state": query["state"] }).to_string())
+            .dispatch()
+            .await;
+
+        let status = resp.status();
+        (status, resp.into_json().await.unwrap_or(Value::Null))
+    }
+
+    /// Opens a reset token sealed for the mail plugin, as the plugin would
+    pub fn open_reset(&self, sealed: &str) -> String
+    {
+        let sealed = base64::decode(sealed).unwrap();
+        let token = mailer_key().0.decrypt(PaddingScheme::new_oaep::<Sha256>(), &sealed).unwrap();
+
+        String::from_utf8(token).unwrap()
+    }
+
+    /// Logs in to an existing account
+    pub async fn token(&self, email: &str) -> String
+    {
+        let resp = self
+            .client
+            .post("/api/v1/auth/login")
+            .header(ContentType::JSON)
+            .body(json!({ "email": email, "password": PASSWORD }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(resp.status(), Status::Ok, "login {}", email);
+
+        let body: Value = resp.into_json().await.unwrap();
+        body["token"].as_str().unwrap().to_string()
+    }
+}
+
+pub fn bearer(token: &str) -> Header<'static>
+{
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+/// Fails a database test run without a database instead of letting it pass
+/// having tested nothing
+#[macro_export]
+macro_rules! require_db {
+    ($harness:expr) => {
+        assert!($harness.has_db(), "ANZEN_TEST_MONGO_URI must be set to run database tests");
+    };
+}